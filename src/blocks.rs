@@ -0,0 +1,352 @@
+use crate::FResult;
+use crate::r#enum::Fishers;
+
+/*
+    * Iterator Adapter Encrypting or Decrypting a Stream of Byte Chunks
+
+    * Chunks may be of any size, they are buffered until whole blocks are available
+    * Every item holds the modified whole blocks gathered so far, so output can be written as it comes
+    * When encrypting, the remaining bytes at the end are zero padded up to a whole block, input
+      padded with pad() beforehand is whole blocks already
+    * When decrypting, the input must be whole blocks and the padding is left for the caller to clear,
+      only the caller knows the original length (e.g. from a sidecar)
+    * Blocks are modified independently (ECB) unless chained with Blocks::cbc()
+*/
+pub(crate) struct Blocks<'a, I> {
+    block_size: usize,
+    buffer: Vec<u8>,
+    /* CBC only, the previous ciphertext block (the IV before the first block) */
+    chain: Option<Vec<u8>>,
+    chunks: I,
+    crypt: bool,
+    done: bool,
+    fisher: &'a Fishers,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Iterator for Blocks<'_, I> {
+    type Item = FResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        /* Buffer chunks until there is at least one whole block or the input runs out */
+        while self.buffer.len() < self.block_size {
+            match self.chunks.next() {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        /* Take the whole blocks, keeping the rest buffered for the next chunks */
+        let whole = self.buffer.len() - self.buffer.len() % self.block_size;
+        let mut data: Vec<u8> = self.buffer.drain(..whole).collect();
+
+        /* Input ran out, the remaining bytes form the last block */
+        if self.done && !self.buffer.is_empty() {
+            if !self.crypt {
+                return Some(Err(format!("Ciphertext is not a multiple of {} bytes", self.block_size).into()));
+            }
+            /* Zero pad the last block up to the block size */
+            data.append(&mut self.buffer);
+            data.resize(data.len().div_ceil(self.block_size) * self.block_size, 0);
+        }
+
+        if data.is_empty() {
+            return None;
+        }
+
+        for block in data.chunks_mut(self.block_size) {
+            let mut modified = block.to_vec();
+            let result = match self.crypt {
+                /* True -> Encrypt, CBC mixes the previous ciphertext block into the plaintext first */
+                true => {
+                    if let Some(chain) = &self.chain {
+                        xor(&mut modified, chain);
+                    }
+                    self.fisher.encrypt_block(&mut modified)
+                }
+                /* False -> Decrypt */
+                false => self.fisher.decrypt_block(&mut modified)
+            };
+
+            /* Failed to encrypt or decrypt the block, nothing more is yielded */
+            match result {
+                Ok(true) => {
+                    /* Chain on the ciphertext block, which is the output when encrypting and the input when decrypting */
+                    if let Some(chain) = &mut self.chain {
+                        match self.crypt {
+                            true => chain.copy_from_slice(&modified),
+                            false => {
+                                xor(&mut modified, chain);
+                                chain.copy_from_slice(block);
+                            }
+                        }
+                    }
+                    block.copy_from_slice(&modified);
+                }
+                Ok(false) => {
+                    self.done = true;
+                    return Some(Err("Failed to encrypt or decrypt block".into()));
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        Some(Ok(data))
+    }
+}
+
+impl<I> Blocks<'_, I> {
+    pub(crate) fn cbc(mut self, iv: &[u8]) -> Self {
+        /*
+            * Chain the Blocks in CBC Mode
+
+            * Each plaintext block is XORed with the previous ciphertext block before encryption, so
+              identical plaintext blocks no longer produce identical ciphertext blocks
+
+            @param self: Blocks Instance
+            @param iv: &[u8]
+                * The initialization vector, one block of random bytes per file
+            @return Blocks: The chained iterator
+        */
+        self.chain = Some(iv.to_vec());
+        self
+    }
+}
+
+fn xor(block: &mut [u8], chain: &[u8]) {
+    /*
+        * XOR the Given Chain Block into the Given Block
+
+        @param block: &mut [u8]
+            * The block to modify
+        @param chain: &[u8]
+            * The IV or previous ciphertext block, of the same size
+    */
+    for (byte, chain) in block.iter_mut().zip(chain) {
+        *byte ^= chain;
+    }
+}
+
+pub(crate) trait BlocksExt: Iterator<Item = Vec<u8>> + Sized {
+    fn blocks(self, fisher: &Fishers, block_size: usize, crypt: bool) -> Blocks<'_, Self> {
+        /*
+            * Encrypt or Decrypt the Chunks of this Iterator Block by Block
+
+            @param self: Iterator of byte chunks
+            @param fisher: &Fishers
+                * The cipher to modify the blocks with
+            @param block_size: usize
+                * The block size of the cipher in bytes
+            @param crypt: bool
+                * Whether to encrypt or decrypt
+            @return Blocks: Iterator of FResult<Vec<u8>> holding the modified whole blocks
+        */
+        Blocks {
+            block_size,
+            buffer: Vec::with_capacity(block_size),
+            chain: None,
+            chunks: self,
+            crypt,
+            done: false,
+            fisher,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> BlocksExt for I {}
+
+pub(crate) fn pad(data: &[u8], block_size: usize) -> Vec<u8> {
+    /*
+        * PKCS#7 Pad the Given Plaintext to Whole Blocks
+
+        * Always adds 1 to block_size bytes, each holding the number of bytes added, so a whole
+          block is added to plaintext that already is whole blocks (including empty plaintext)
+
+        @param data: &[u8]
+            * The plaintext
+        @param block_size: usize
+            * The block size of the cipher in bytes, at most 255
+        @return Vec<u8>
+            * The padded plaintext
+    */
+    let padding = block_size - data.len() % block_size;
+    let mut padded = Vec::with_capacity(data.len() + padding);
+    padded.extend_from_slice(data);
+    padded.resize(data.len() + padding, padding as u8);
+    padded
+}
+
+pub(crate) fn unpad(data: &mut Vec<u8>, block_size: usize) -> FResult<()> {
+    /*
+        * Remove the PKCS#7 Padding Added by pad()
+
+        @param data: &mut Vec<u8>
+            * The decrypted whole blocks, truncated in place
+        @param block_size: usize
+            * The block size of the cipher in bytes
+        @return FResult: Result<(), Box<dyn Error>>
+            * An error if the padding is malformed, usually a wrong key or a corrupted last block
+    */
+    let padding = *data.last().ok_or("Missing padding")? as usize;
+    if padding == 0 || padding > block_size || padding > data.len()
+        || data[data.len() - padding..].iter().any(|byte| *byte as usize != padding) {
+        return Err("Invalid padding".into());
+    }
+
+    data.truncate(data.len() - padding);
+    Ok(())
+}
+
+pub(crate) fn ctr(fisher: &Fishers, block_size: usize, nonce: &[u8], data: &[u8]) -> FResult<Vec<u8>> {
+    /*
+        * Encrypt or Decrypt the Given Bytes in CTR Mode
+
+        * Block n of the keystream is the nonce with n added to its last 8 bytes (big-endian), encrypted.
+          Every block only depends on its position, so encrypting and decrypting are the same and the
+          last block may be partial
+
+        @param fisher: &Fishers
+            * The cipher generating the keystream
+        @param block_size: usize
+            * The block size of the cipher in bytes
+        @param nonce: &[u8]
+            * The random nonce of the file, one block long
+        @param data: &[u8]
+            * The plaintext or ciphertext
+        @return FResult: Result<Vec<u8>, Box<dyn Error>>
+            * The ciphertext or plaintext, as long as the data
+    */
+    let tail = nonce.len() - 8;
+    let start = u64::from_be_bytes(nonce[tail..].try_into()?);
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut keystream = nonce.to_vec();
+    for (index, chunk) in data.chunks(block_size).enumerate() {
+        keystream[..tail].copy_from_slice(&nonce[..tail]);
+        keystream[tail..].copy_from_slice(&start.wrapping_add(index as u64).to_be_bytes());
+        if !fisher.encrypt_block(&mut keystream)? {
+            return Err("Failed to encrypt counter block".into());
+        }
+
+        output.extend(chunk.iter().zip(&keystream).map(|(byte, key)| byte ^ key));
+    }
+
+    Ok(output)
+}
+
+pub(crate) fn advance_counter(nonce: &mut [u8], blocks: u64) {
+    /*
+        * Move the Given CTR Nonce Forward by a Number of Blocks
+
+        * ctr() on the advanced nonce continues the keystream where the given number of blocks left off,
+          so data can be encrypted or decrypted in whole-block pieces
+
+        @param nonce: &mut [u8]
+            * The nonce of the file or of the previous piece, one block long
+        @param blocks: u64
+            * The number of blocks the previous piece held
+    */
+    let tail = nonce.len() - 8;
+    let counter = u64::from_be_bytes(nonce[tail..].try_into().expect("8 byte counter"));
+    nonce[tail..].copy_from_slice(&counter.wrapping_add(blocks).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_adds_a_whole_block_to_whole_blocks() {
+        for block_size in [8, 16, 32, 64, 128] {
+            for blocks in [0, 1, 2] {
+                let data = vec![7u8; blocks * block_size];
+                let mut padded = pad(&data, block_size);
+                assert_eq!(padded.len(), data.len() + block_size);
+                assert!(padded[data.len()..].iter().all(|byte| *byte as usize == block_size));
+
+                unpad(&mut padded, block_size).unwrap();
+                assert_eq!(padded, data);
+            }
+        }
+    }
+
+    #[test]
+    fn unpad_rejects_malformed_padding() {
+        assert!(unpad(&mut Vec::new(), 8).is_err());
+        assert!(unpad(&mut vec![1, 2, 3, 4, 5, 6, 7, 0], 8).is_err());
+        assert!(unpad(&mut vec![9; 8], 8).is_err());
+        assert!(unpad(&mut vec![1, 2, 3, 4, 5, 6, 2, 3], 8).is_err());
+    }
+
+    /* Chunk patterns the adapter must not care about: none, empty, single bytes, longer than a block */
+    fn chunkings(data: &[u8], block_size: usize) -> Vec<Vec<Vec<u8>>> {
+        let mut chunkings = vec![
+            data.chunks(1).map(<[u8]>::to_vec).collect(),
+            data.chunks(block_size * 3 + 5).map(<[u8]>::to_vec).collect(),
+            data.chunks(block_size - 1).flat_map(|chunk| [Vec::new(), chunk.to_vec()]).collect(),
+        ];
+        if data.is_empty() {
+            chunkings.push(Vec::new());
+        }
+        chunkings
+    }
+
+    fn run(chunks: Vec<Vec<u8>>, fisher: &Fishers, block_size: usize, crypt: bool, iv: Option<&[u8]>) -> Vec<u8> {
+        let blocks = chunks.into_iter().blocks(fisher, block_size, crypt);
+        let blocks = match iv {
+            Some(iv) => blocks.cbc(iv),
+            None => blocks
+        };
+        blocks.collect::<FResult<Vec<_>>>().unwrap().concat()
+    }
+
+    #[test]
+    fn uneven_chunks_match_one_shot_encryption() {
+        use crate::r#enum::{Algorithm, TWEAK_SIZE, new_cipher};
+
+        let fisher = new_cipher(Algorithm::Twofish, 16, &[5; 32], &[0; TWEAK_SIZE]).unwrap();
+        let iv = [9u8; 16];
+        for len in [0usize, 1, 15, 16, 17, 100] {
+            let data: Vec<u8> = (0..len as u8).collect();
+            for iv in [None, Some(&iv[..])] {
+                let one_shot = run(vec![data.clone()], &fisher, 16, true, iv);
+                assert_eq!(one_shot.len(), len.div_ceil(16) * 16);
+                for chunks in chunkings(&data, 16) {
+                    assert_eq!(run(chunks, &fisher, 16, true, iv), one_shot, "{} bytes, cbc {}", len, iv.is_some());
+                }
+                for chunks in chunkings(&one_shot, 16) {
+                    let decrypted = run(chunks, &fisher, 16, false, iv);
+                    assert_eq!(&decrypted[..len], data.as_slice());
+                    assert!(decrypted[len..].iter().all(|byte| *byte == 0));
+                }
+            }
+
+            /* CTR in whole-block pieces continues the keystream of the pieces before */
+            let one_shot = ctr(&fisher, 16, &iv, &data).unwrap();
+            assert_eq!(one_shot.len(), len);
+            for piece in [16, 48] {
+                let mut nonce = iv;
+                let mut chunked = Vec::new();
+                for chunk in data.chunks(piece) {
+                    chunked.extend(ctr(&fisher, 16, &nonce, chunk).unwrap());
+                    advance_counter(&mut nonce, chunk.len().div_ceil(16) as u64);
+                }
+                assert_eq!(chunked, one_shot);
+            }
+            assert_eq!(ctr(&fisher, 16, &iv, &one_shot).unwrap(), data);
+        }
+
+        /* Decrypting needs whole blocks */
+        let broken = run(vec![vec![1; 20]], &fisher, 16, true, None)[..20].to_vec();
+        assert!(vec![broken].into_iter().blocks(&fisher, 16, false).any(|blocks| blocks.is_err()));
+    }
+}
@@ -0,0 +1,1399 @@
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap::builder::PossibleValuesParser;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use sha2::{Digest, Sha256};
+use tracing_subscriber::fmt::format::FmtSpan;
+use zeroize::Zeroizing;
+
+use crate::{container, doctor, ecc, fish, r#enum, shares, FResult};
+use crate::encoding::OutputFormat;
+use crate::exclude::Exclude;
+use crate::fish::{BackupMode, ExistingOutput, Fisher, FisherConfig, SyncMode};
+use crate::header::{FLAG_PATH, Mode, SALT_SIZE};
+use crate::mac::MacAlgorithm;
+use crate::progress::ProgressMode;
+use crate::r#enum::{Algorithm, AlgorithmInfo, ALGORITHMS, Kdf, extension_info};
+
+/* Environment variable read instead of prompting for the password, see read_password() */
+const PASSWORD_ENV: &str = "FISHER_PASSWORD";
+
+/*
+    * Command Line of fisher
+
+    * Parsed with clap, which also derives the usage and help messages from the attributes below
+*/
+#[derive(Parser)]
+#[command(name = "fisher", author = "Kwunch", version = env!("CARGO_PKG_VERSION"),
+    about = "Encrypt or decrypt files and directories using Blowfish, Twofish or Threefish",
+    long_about = "\
+Encrypt or decrypt files and directories using one of three algorithms
+    - Blowfish: 64 bit blocks, 448 bit key
+    - Twofish: 128 bit blocks, 256 bit key
+    - Threefish: 256, 512 or 1024 (default) bit blocks, the key is as long as the block
+
+Encrypting notes.txt writes notes.txt.fish, decrypting it restores notes.txt (see --keep-name and
+--algorithm-extension).
+Encrypted files record their algorithm and block size, so decrypting needs neither. They are also
+authenticated (HMAC-SHA256 by default, see --mac), a wrong password or a modified file is reported
+and left untouched instead of being decrypted to garbage",
+    after_long_help = "\
+Examples:
+    fisher encrypt --algorithm blowfish -p file.txt
+    fisher encrypt --algorithm threefish --block-size 512 -p file.txt dir
+    fisher decrypt -p file.txt.fish dir
+
+Environment:
+    FISHER_ALGORITHM: Algorithm to use when --algorithm is not given, e.g. FISHER_ALGORITHM=twofish
+    FISHER_THREADS: Number of worker threads when --jobs is not given
+    FISHER_MODE: Block mode to encrypt with when --mode is not given, e.g. FISHER_MODE=ctr
+    FISHER_PASSWORD: Password to use instead of prompting for one, for cron jobs and scripts. Less
+        secure than the prompt, other processes of the same user can read it")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(visible_alias = "e", about = "Encrypt the given files and directories in place")]
+    Encrypt(RunArgs),
+    #[command(visible_alias = "d", about = "Decrypt the given files and directories in place")]
+    Decrypt(RunArgs),
+    #[command(about = "Decrypt legacy headerless files with the given algorithm and password and encrypt them again \
+                       in the current format",
+        long_about = "Decrypt legacy headerless files with the given algorithm and password and encrypt them again \
+                      in the current format, one file at a time. Files that already have a header are skipped")]
+    Migrate(RunArgs),
+    #[command(about = "List the supported algorithms, their key and block sizes")]
+    ListAlgorithms,
+    #[command(about = "Only print a verifier (SHA-256 of the key derived for the given algorithm and block size), e.g. \
+                       to check a password or build test vectors",
+        long_about = "Only print a verifier (SHA-256 of the key derived for the given algorithm and block size), e.g. \
+                      to check a password or build test vectors\n\
+                      Without --salt the key is the unsalted SHA-2 key of headerless (--no-header) files, which is \
+                      NOT the key of headered files. With --salt (and --kdf, --kdf-iterations) it is the key encrypt \
+                      --salt gives every file. Other headered files are keyed from a random salt per run and file \
+                      recorded in their header, no password alone gives their key")]
+    PasswordHash(HashArgs),
+    #[command(about = "Diagnose why an encrypted file doesn't decrypt, modifying nothing",
+        long_about = "Diagnose why an encrypted file doesn't decrypt, modifying nothing\n\
+                      Checks the header, the size recorded in it and the MAC with the given password, prints the \
+                      diagnosis and exits with it: 0 OK, 3 not a fisher file, 4 truncated, 5 wrong password, \
+                      6 corrupted body. CTR files have no padding to tell a wrong password from a corrupted body, \
+                      both are reported as a wrong password")]
+    Doctor(DoctorArgs),
+}
+
+/* Arguments of encrypt, decrypt and migrate */
+#[derive(Args)]
+struct RunArgs {
+    #[arg(short, long, env = "FISHER_ALGORITHM", value_parser = parse_algorithm,
+        help = "The algorithm: blowfish (bf), twofish (tw) or threefish (tf). Decrypting reads it from the header")]
+    algorithm: Option<Algorithm>,
+
+    #[arg(short, long, visible_alias = "blocksize", value_parser = parse_block_size,
+        help = "The Threefish block size in bits (256, 512, 1024) or bytes (32, 64, 128), default 1024")]
+    block_size: Option<usize>,
+
+    #[arg(short, long, num_args = 1.., value_parser = existing_path,
+        help = "The files and directories to encrypt or decrypt, every value up to the next flag is a path. \
+                Without paths (or with -p -) stdin is encrypted or decrypted to stdout")]
+    paths: Vec<PathBuf>,
+
+    #[arg(short, long, help = "Print every file and directory as it is modified")]
+    verbose: bool,
+
+    #[arg(short, long, conflicts_with_all = ["verbose", "ascii_progress"],
+        help = "Print no progress bar and no summary when done, only warnings and errors")]
+    quiet: bool,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "file", value_parser = parse_sync,
+        help = "fsync every written file before moving on, --sync=dir also fsyncs the directory containing it",
+        long_help = "fsync every written file before moving on, --sync=dir also fsyncs the directory containing it\n\
+                     Syncing guarantees the result survives a crash or power loss, but forces a disk flush per file \
+                     and can slow down large runs considerably")]
+    sync: Option<SyncMode>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "pretty",
+        value_parser = PossibleValuesParser::new(["pretty", "compact", "json"]),
+        help = "Write a structured trace to stderr, a span per directory and file with its path, the thread id and \
+                how long it took. --trace=compact and --trace=json (one JSON object per line) choose other formats")]
+    trace: Option<String>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "keep", value_parser = parse_backup,
+        help = "Copy each file to <name>.bak before modifying it, --backup=removable removes the copy once the \
+                file was modified. Files ending in .bak are skipped in directories while backups are enabled")]
+    backup: Option<BackupMode>,
+
+    #[arg(long, help = "Decrypt each encrypted file in memory and compare it to the original before writing, \
+                        leaving the file untouched if they differ")]
+    two_pass_verify: bool,
+
+    #[arg(long, conflicts_with_all = ["device", "zip"],
+        help = "Read each encrypted file back from disk and decrypt it as decrypt would (key derived again from the \
+                header, MAC checked), and only replace or remove the original if it hashes the same as before. \
+                Reads every file twice")]
+    verify: bool,
+
+    #[arg(long, conflicts_with_all = ["device", "zip", "dry_run", "no_header"],
+        help = "Once every file was encrypted, read each encrypted file back from disk, check its header and MAC, \
+                and list the files that fail. Catches corruption that happened after the files were written")]
+    verify_after: bool,
+
+    #[arg(long, value_name = "PASSES", num_args = 0..=1, require_equals = true, default_missing_value = "3",
+        value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["backup", "device", "zip"],
+        help = "Once a file was encrypted, overwrite the original with random bytes PASSES times (--shred alone: 3), \
+                syncing after each pass, and remove it. Also removes the originals with --output. Skipped with a \
+                warning on filesystems that don't overwrite in place (e.g. btrfs, zfs, NFS) and for files with \
+                other hard links. SSDs and flash storage may keep old copies of the data regardless")]
+    shred: Option<u32>,
+
+    #[arg(long, help = "Preserve extended attributes (e.g. macOS quarantine flags) of modified files. Best effort, \
+                        attributes that can't be copied are reported and skipped")]
+    xattrs: bool,
+
+    #[arg(long, help = "Only count the files and bytes that would be processed, modifies nothing")]
+    count_only: bool,
+
+    #[arg(long, conflicts_with_all = ["count_only", "zip", "stats_json"],
+        help = "List every file that would be encrypted or decrypted, and every file skipped and why, without \
+                modifying anything. No password is asked for")]
+    dry_run: bool,
+
+    #[arg(long, hide = true)]
+    debug: bool,
+
+    /* Debug only: print the first blocks of each file instead of modifying them */
+    #[arg(long, hide = true, requires = "debug")]
+    preview_blocks: Option<usize>,
+
+    #[arg(long, help = "Process every file one by one on the main thread, in directory order. Slower, but gives \
+                        deterministic ordering and clean backtraces for debugging")]
+    sequential: bool,
+
+    #[arg(long, value_name = "N", env = "FISHER_THREADS", value_parser = parse_jobs,
+        help = "Modify up to N files at once, default one per CPU")]
+    jobs: Option<usize>,
+
+    #[arg(long, help = "Process paths and directory entries in sorted order, so logs are stable across runs \
+                        (combine with --sequential for a fully deterministic order)")]
+    sorted: bool,
+
+    #[arg(long, help = "Write a <name>.fisher.meta JSON sidecar next to each encrypted file recording the \
+                        algorithm, block size and original size. Decrypting uses (and then removes) the sidecar to \
+                        check the algorithm and restore the exact original length")]
+    meta: bool,
+
+    #[arg(long, conflicts_with_all = ["no_header", "ecc", "compress", "armor", "output_format", "zip", "device",
+                                      "verify", "verify_after", "two_pass_verify"],
+        help = "Encrypt files in CTR mode without growing them: the encrypted file is exactly as long as the \
+                original, its header (salt, IV, MAC) goes to the <name>.fisher.meta sidecar instead. The sidecar \
+                is required to decrypt, keep it next to the file (losing it loses the file)")]
+    length_preserving: bool,
+
+    #[arg(long, help = "Stop every thread as soon as one file fails and exit with that error, instead of letting \
+                        the other directories carry on (fast failure for CI)")]
+    abort_on_first_error: bool,
+
+    #[arg(long, conflicts_with = "abort_on_first_error",
+        help = "Never stop on a failing file, also not on files given directly or with --sequential, and list \
+                every failed file with its error at the end (exits non-zero if any failed)")]
+    keep_going: bool,
+
+    #[arg(short, long, value_name = "DIR", conflicts_with_all = ["backup", "device", "zip"],
+        help = "Write the encrypted or decrypted files below DIR, mirroring the given paths (docs/a.txt is written \
+                to DIR/docs/a.txt.fish), and leave the originals untouched (unless --shred). For a single file, DIR may also be the \
+                file to write")]
+    output: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["skip_existing", "keep_name"],
+        help = "Replace outputs that already exist (e.g. <name>.fish from a previous run, or a file below --output). \
+                Without it or --skip-existing, such files fail and both are left untouched")]
+    overwrite: bool,
+
+    #[arg(long, conflicts_with = "keep_name",
+        help = "Leave files whose output already exists (e.g. <name>.fish from a previous run, or a file below \
+                --output) untouched, with a warning, and count them as skipped")]
+    skip_existing: bool,
+
+    #[arg(long, value_name = "GLOB",
+        help = "Skip files and directories in directories whose name (e.g. .git, node_modules, '*.lock') or path \
+                (e.g. '**/build/*.o') matches GLOB (repeatable). Each directory may also list gitignore-style patterns \
+                in a .fisherignore, which apply to everything below it. .DS_Store and .fisherignore files are always \
+                skipped")]
+    exclude: Vec<String>,
+
+    #[arg(long, help = "Follow symlinks found in directories, modifying the files they point to. Without it they are \
+                        skipped, as they may lead outside the given paths. Every directory and file is modified at \
+                        most once, also when links form a loop")]
+    follow_symlinks: bool,
+
+    #[arg(long, help = "Modify files in place under their own name, instead of encrypting <name> to <name>.fish and \
+                        decrypting <name>.fish back to <name>")]
+    keep_name: bool,
+
+    #[arg(long, help = "Legacy mode, read and write the headerless format of older fisher versions",
+        long_help = "Legacy mode, read and write the headerless format of older fisher versions\n\
+                     Legacy files record neither algorithm, block size nor length: decrypt them with the exact \
+                     algorithm and block size they were encrypted with, nothing detects a mismatch, trailing zero \
+                     bytes of the original are lost and blocks are not chained (ECB), so identical plaintext blocks \
+                     show as identical ciphertext blocks")]
+    no_header: bool,
+
+    #[arg(long, help = "Decrypt files without a header or sidecar anyway, and encrypt files that are already \
+                        encrypted again. Without it both are refused, as they might never have been encrypted \
+                        (decrypting plaintext destroys it) or were encrypted by mistake (two layers)")]
+    force: bool,
+
+    #[arg(long, help = "Allow block and character devices given in --paths to be encrypted or decrypted in place. \
+                        Asks to confirm each device first. Devices are written without a header, decrypt them with \
+                        the same algorithm and block size. Devices inside directories are skipped")]
+    device: bool,
+
+    #[arg(long, help = "Print the peak and average number of files modified at once and how long no file was being \
+                        modified, to tune parallelism to the disk and CPU")]
+    concurrency_report: bool,
+
+    #[arg(long, help = "Log absolute, canonicalized paths (symlinks resolved) instead of the paths as typed")]
+    canonical_paths: bool,
+
+    #[arg(long, help = "Report \"processed X/Y files\" progress as plain text, rewriting one line on a terminal and \
+                        printing a line at most once per second otherwise (logs, CI), instead of the progress bar \
+                        shown on a terminal")]
+    ascii_progress: bool,
+
+    #[arg(long, help = "When decrypting without an algorithm, infer it from the file extensions (.bf, .tw, .tf256, \
+                        .tf512, .tf1024), all files must agree")]
+    algorithm_from_ext: bool,
+
+    #[arg(long, conflicts_with = "keep_name",
+        help = "Name encrypted files after their algorithm, <name>.bf, .tw, .tf256, .tf512 or .tf1024, instead of \
+                <name>.fish. Decrypting removes either extension, and reports files whose algorithm extension \
+                doesn't match their header")]
+    algorithm_extension: bool,
+
+    #[arg(long, value_name = "MAP",
+        help = "Encrypt files matching a pattern with another algorithm than the default, e.g. \
+                '*.mp4=bf,*.txt=tf512' (names or extensions from list-algorithms, first match wins). Each file's \
+                header records its algorithm, so decrypting needs no map, every file picks its own")]
+    algorithm_map: Option<String>,
+
+    #[arg(long, value_name = "FILE",
+        help = "Encrypt: pack every file under the paths into a new ZIP container, each file a separately encrypted \
+                entry, leaving the files untouched. Decrypt: unpack the container into the single directory given \
+                with --paths. Entries can also be extracted with any ZIP tool and decrypted on their own")]
+    zip: Option<PathBuf>,
+
+    #[arg(long, value_name = "NAME", requires = "zip",
+        help = "With --zip when decrypting, only unpack this entry (repeatable)")]
+    entry: Vec<String>,
+
+    #[arg(long, value_name = "FORMAT", value_parser = parse_output_format,
+        help = "Encode encrypted files as raw (default) bytes, armor (base64 in a -----BEGIN FISHER----- envelope, \
+                for text-only channels) or base64url (one URL and file name safe line). Decrypting detects the \
+                format by itself")]
+    output_format: Option<OutputFormat>,
+
+    #[arg(long, conflicts_with = "output_format", help = "Same as --output-format armor")]
+    armor: bool,
+
+    #[arg(long, env = "FISHER_MODE", value_parser = parse_mode,
+        help = "Chain the blocks of encrypted files in cbc (default, random IV per file), ctr (counter mode, no \
+                padding, the ciphertext is as long as the file) or ecb (identical plaintext blocks give identical \
+                ciphertext blocks) mode. Decrypting reads the mode from the header. Headerless files and devices \
+                are always ecb")]
+    mode: Option<Mode>,
+
+    #[arg(long, ignore_case = true, value_parser = ["pbkdf2", "argon2", "argon2id"],
+        help = "Derive the key of each encrypted file from the password and a random per-file salt with pbkdf2 \
+                (default, PBKDF2-HMAC-SHA256) or argon2 (Argon2id with 19 MiB, 2 passes, 1 lane, memory-hard \
+                against GPU cracking). The KDF, its parameters and the salt are kept in the header for decrypting. \
+                Headerless files use the unsalted SHA-2 key")]
+    kdf: Option<String>,
+
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..),
+        help = "PBKDF2 iterations, default 100000. Not for --kdf argon2")]
+    kdf_iterations: Option<u32>,
+
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["kdf_iterations", "raw_key", "no_header"],
+        help = "Calibrate the KDF on this machine before encrypting, so deriving a key takes about MS milliseconds: \
+                PBKDF2 iterations, or Argon2id passes at its memory. The chosen parameters are recorded in the header")]
+    kdf_target_ms: Option<u64>,
+
+    #[arg(long, value_name = "HEX", value_parser = parse_salt, conflicts_with_all = ["raw_key", "no_header"],
+        help = "Derive the key of every file with the KDF over the password and this salt (32 hex digits, 16 bytes) \
+                instead of a random salt per file, e.g. to compute the same key with other tools. Every file then \
+                gets the same key, only use it deliberately. The salt is still recorded in each header")]
+    salt: Option<[u8; SALT_SIZE]>,
+
+    #[arg(long, conflicts_with = "no_header",
+        help = "WARNING: leaks which files are identical. Derive the salt, IV and tweak of each encrypted file from \
+                a hash of its plaintext keyed by the password instead of drawing them at random, so the same file \
+                encrypted with the same password always gives the same bytes, e.g. for deduplicating backups. \
+                Anyone seeing two encrypted files can tell whether their contents are equal")]
+    deterministic: bool,
+
+    #[arg(long, value_name = "MAC", value_parser = parse_mac, conflicts_with = "no_header",
+        help = "Authenticate encrypted files with hmac-sha256 (default), blake3 (keyed BLAKE3, much faster on large \
+                files) or poly1305 (with a one-time key per file, not with --salt or --deterministic). Decrypting reads \
+                the MAC from the header, files with another MAC than hmac-sha256 need this version of fisher")]
+    mac: Option<MacAlgorithm>,
+
+    #[arg(long, value_name = "STRING", conflicts_with = "no_header",
+        help = "Bind encrypted files to STRING (e.g. a host or what they are for): their MAC also covers it, so \
+                decrypting them needs the same --aad. The string itself is not stored, the header only records that \
+                there is one")]
+    aad: Option<String>,
+
+    #[arg(long, conflicts_with = "no_header",
+        help = "Don't bind encrypted files to the name of their plaintext. By default their MAC also covers the file \
+                name, so an encrypted file renamed or swapped for another one fails to decrypt, moving it to another \
+                directory is fine. Decrypting reads from the header whether a file is bound. Files encrypted from \
+                stdin are never bound")]
+    no_bind_path: bool,
+
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["no_header", "no_bind_path", "zip"],
+        help = "Bind encrypted files to their path below DIR instead of their name only, e.g. notes/a.txt for \
+                DIR/notes/a.txt, so files can't be moved within the tree but the tree as a whole can be moved. \
+                Decrypting such files needs --relative-to with the directory they are below then")]
+    relative_to: Option<PathBuf>,
+
+    #[arg(long, value_name = "RATIO", value_parser = parse_ecc, conflicts_with_all = ["no_header", "device"],
+        help = "Add Reed-Solomon parity to encrypted files, RATIO parity bytes per data byte (greater than 0 and at \
+                most 1, e.g. 0.1 for 10%). Decrypting reads the parity from the header and corrects corrupted bytes \
+                anywhere in the ciphertext, up to half the parity of each 255 byte codeword (12 at 0.1). The header \
+                itself is not protected")]
+    ecc: Option<u8>,
+
+    #[arg(long, conflicts_with_all = ["no_header", "device"],
+        help = "Compress each file with DEFLATE before encrypting it, as encrypted data doesn't compress. Files that \
+                don't shrink (e.g. media, archives) are encrypted as they are. The header records which, decrypting \
+                decompresses by itself")]
+    compress: bool,
+
+    #[arg(long, value_name = "FILE",
+        help = "Write a JSON summary of the run to FILE when it ends, also when it fails: files, bytes read and \
+                written, duration, files per algorithm and failures")]
+    stats_json: Option<PathBuf>,
+
+    #[arg(long, value_name = "N",
+        help = "Retry reads, writes and renames failing with transient errors (e.g. on network shares) up to N \
+                times with increasing delays, default 3")]
+    io_retries: Option<u32>,
+
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..),
+        help = "Encrypt with a random key split into N share files (fisher.share.<n> in the current directory), \
+                any --threshold of which can decrypt. No password is asked")]
+    shares: Option<u8>,
+
+    #[arg(long, value_name = "K", requires = "shares",
+        help = "The number of shares needed to decrypt, default all of them")]
+    threshold: Option<u8>,
+
+    #[arg(long, value_name = "FILE",
+        help = "Decrypt with the key recovered from share files, repeat once per share, e.g. fisher decrypt \
+                --share fisher.share.1 --share fisher.share.3 -p file.txt")]
+    share: Vec<PathBuf>,
+
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["shares", "share"],
+        help = "Use the contents of FILE as the password instead of prompting for one")]
+    keyfile: Option<PathBuf>,
+
+    #[arg(long, requires = "keyfile", conflicts_with_all = ["kdf", "kdf_iterations"],
+        help = "Use the contents of the --keyfile as the key itself instead of deriving one from it. It must be \
+                exactly as long as the key of the algorithm (e.g. 32 bytes for Twofish, see list-algorithms), \
+                e.g. head -c 32 /dev/urandom > key. The header records it, decrypting needs the same --keyfile")]
+    raw_key: bool,
+}
+
+/* Arguments of password-hash */
+#[derive(Args)]
+struct HashArgs {
+    #[arg(short, long, env = "FISHER_ALGORITHM", required = true, value_parser = parse_algorithm,
+        help = "The algorithm: blowfish (bf), twofish (tw) or threefish (tf)")]
+    algorithm: Algorithm,
+
+    #[arg(short, long, visible_alias = "blocksize", value_parser = parse_block_size,
+        help = "The Threefish block size in bits (256, 512, 1024) or bytes (32, 64, 128), default 1024")]
+    block_size: Option<usize>,
+
+    #[arg(long, value_name = "HEX", value_parser = parse_salt,
+        help = "Derive the key with the KDF over the password and this salt (32 hex digits), as encrypt --salt \
+                does, instead of the unsalted SHA-2 key of headerless files")]
+    salt: Option<[u8; SALT_SIZE]>,
+
+    #[arg(long, requires = "salt", ignore_case = true, value_parser = ["pbkdf2", "argon2", "argon2id"],
+        help = "The KDF used with --salt: pbkdf2 (default) or argon2, with the same parameters as encrypt")]
+    kdf: Option<String>,
+
+    #[arg(long, value_name = "N", requires = "salt", value_parser = clap::value_parser!(u32).range(1..),
+        help = "PBKDF2 iterations used with --salt, default 100000. Not for --kdf argon2")]
+    kdf_iterations: Option<u32>,
+
+    #[arg(long, help = "Print the derived key itself as hex instead of the verifier")]
+    show_key: bool,
+
+    #[arg(long, value_name = "FILE",
+        help = "Use the contents of FILE as the password instead of prompting for one")]
+    keyfile: Option<PathBuf>,
+}
+
+/* Arguments of doctor */
+#[derive(Args)]
+struct DoctorArgs {
+    #[arg(value_name = "FILE", help = "The encrypted file")]
+    file: PathBuf,
+
+    #[arg(long, value_name = "STRING", help = "The --aad the file was encrypted with")]
+    aad: Option<String>,
+
+    #[arg(long, value_name = "DIR", help = "The --relative-to directory the file is below")]
+    relative_to: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE",
+        help = "Use the contents of FILE as the password instead of prompting for one")]
+    keyfile: Option<PathBuf>,
+}
+
+pub fn run() -> FResult<()> {
+    /*
+        * Run the fisher Command Line on the Arguments of the Process
+
+        * Invalid arguments print the usage and exit with status 2
+
+        @return FResult: Result<(), Box<dyn Error>>
+    */
+    let matches = Cli::command().get_matches();
+    let (args, crypt, migrate, command) = match Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit()).command {
+        Command::Encrypt(args) => (args, true, false, "encrypt"),
+        Command::Decrypt(args) => (args, false, false, "decrypt"),
+        /* Migrating decrypts legacy headerless files, which encrypts them again */
+        Command::Migrate(args) => (args, true, true, "migrate"),
+        Command::ListAlgorithms => {
+            print_algorithms();
+            return Ok(());
+        }
+        Command::PasswordHash(args) => return print_password_hash(&args),
+        Command::Doctor(args) => return doctor(&args),
+    };
+
+    /* FISHER_MODE is a default, --no-header and --length-preserving override it where --mode conflicts with them */
+    let mode_given = matches.subcommand_matches(command).and_then(|matches| matches.value_source("mode"))
+        == Some(ValueSource::CommandLine);
+
+    let verbose = args.verbose;
+    let sync = args.sync.unwrap_or(SyncMode::Off);
+    let backup = args.backup.unwrap_or(BackupMode::Off);
+
+    /* Check if extended attributes should be preserved */
+    let mut xattrs = args.xattrs;
+    if xattrs && !xattr::SUPPORTED_PLATFORM {
+        println!("Extended attributes are not supported on this platform, --xattrs is ignored");
+        xattrs = false;
+    }
+
+    /* Check how the key of each file should be derived, decrypt reads the KDF and its parameters from the header */
+    let kdf = select_kdf(command, args.kdf.as_deref(), args.kdf_iterations);
+    /* Decrypt reads the salt of each file from its header */
+    if !crypt && args.salt.is_some() {
+        usage_error(command, ErrorKind::ArgumentConflict, "--salt is for encrypting, decrypt reads the salt from the header");
+    }
+
+    /* Identical ciphertexts give away identical plaintexts, say so every time */
+    if args.deterministic {
+        if !crypt {
+            usage_error(command, ErrorKind::ArgumentConflict, "--deterministic is for encrypting, decrypt reads the IV and salt from the header");
+        }
+        eprintln!("Warning: --deterministic encrypts identical files to identical bytes, anyone seeing them can tell they are equal");
+    }
+
+    /* A Poly1305 key must never authenticate two files, and the salt is what sets the keys of files apart */
+    let mac = args.mac.unwrap_or(FisherConfig::default().mac);
+    if !crypt && args.mac.is_some() {
+        usage_error(command, ErrorKind::ArgumentConflict, "--mac is for encrypting, decrypt reads the MAC from the header");
+    }
+    if mac == MacAlgorithm::Poly1305 && (args.salt.is_some() || args.deterministic) {
+        usage_error(command, ErrorKind::ArgumentConflict,
+                    "--mac poly1305 needs a random salt per file, it can't be combined with --salt or --deterministic");
+    }
+
+    /* Whether a file is bound to its name is recorded in its header, --aad must be given again */
+    if !crypt && args.no_bind_path {
+        usage_error(command, ErrorKind::ArgumentConflict, "--no-bind-path is for encrypting, decrypt reads from the header whether a file is bound");
+    }
+
+    /* Scale the cost of the KDF to this machine, before anything is derived with it */
+    let kdf = match args.kdf_target_ms {
+        Some(_) if !crypt => usage_error(command, ErrorKind::ArgumentConflict,
+                                         "--kdf-target-ms calibrates encryption, decrypt reads the KDF parameters from the header"),
+        Some(target) if !args.dry_run => {
+            let calibrated = kdf.calibrate(Duration::from_millis(target))?;
+            if verbose {
+                println!("Calibrated the KDF to {:?} for {} ms", calibrated, target);
+            }
+            calibrated
+        }
+        _ => kdf
+    };
+    /* A raw key is used as it is, a dry run has no key to check */
+    let kdf = match args.raw_key && !args.dry_run {
+        true => Kdf::Raw,
+        false => kdf
+    };
+
+    /* Check if the legacy headerless format should be read and written */
+    let header = !args.no_header;
+    if migrate && !header {
+        usage_error(command, ErrorKind::ArgumentConflict, "migrate always writes the current format, it can't be combined with --no-header");
+    }
+    /* Decrypt finds the header of length-preserving files in their sidecar */
+    if (!crypt || migrate) && args.length_preserving {
+        usage_error(command, ErrorKind::ArgumentConflict,
+                    "--length-preserving is for encrypting, decrypt reads the header from the sidecar");
+    }
+
+    /* Migrating starts from ciphertext, there is no plaintext to check against */
+    if (!crypt || migrate) && args.verify {
+        usage_error(command, ErrorKind::ArgumentConflict, "--verify checks newly encrypted files, it can't be combined with decrypt or migrate");
+    }
+    if !crypt && args.verify_after {
+        usage_error(command, ErrorKind::ArgumentConflict, "--verify-after checks encrypted files, it can't be combined with decrypt");
+    }
+
+    /* Only encrypting leaves plaintext behind */
+    if !crypt && args.shred.is_some() {
+        usage_error(command, ErrorKind::ArgumentConflict, "--shred overwrites the originals of encrypted files, it can't be combined with decrypt");
+    }
+
+    /* Check how the blocks of encrypted files should be chained, decrypt reads it from the header */
+    let mode = args.mode.unwrap_or(FisherConfig::default().mode);
+    if mode != Mode::Ecb && !header && mode_given {
+        usage_error(command, ErrorKind::ArgumentConflict,
+                    &format!("--mode {} keeps its IV in the header, it can't be combined with --no-header", mode.name()));
+    }
+    if args.length_preserving && mode_given {
+        usage_error(command, ErrorKind::ArgumentConflict, "--length-preserving always encrypts in ctr mode, it can't be combined with --mode");
+    }
+
+    /* Check if some files should be encrypted with other algorithms than the default */
+    let algorithm_map = match args.algorithm_map.as_deref().map(parse_algorithm_map) {
+        Some(Ok(algorithm_map)) => algorithm_map,
+        Some(Err(err)) => usage_error(command, ErrorKind::ValueValidation, &err),
+        None => Vec::new()
+    };
+
+    /* Check if a structured trace of every directory and file should be written to stderr */
+    if let Some(format) = &args.trace {
+        init_tracing(format);
+    }
+
+    let paths = args.paths;
+
+    /* Reject directories given where only a single file fits, before the password is asked for or anything is modified */
+    if let Some(err) = shape_error(&paths, args.output.as_deref(), args.zip.is_some() && !crypt) {
+        usage_error(command, ErrorKind::ArgumentConflict, &err);
+    }
+
+    /* Without paths, or with -p -, stdin is modified to stdout */
+    let pipe = paths.is_empty() || paths.iter().any(|path| path.as_os_str() == fish::PIPE_PATH);
+    if pipe {
+        if paths.len() > 1 {
+            usage_error(command, ErrorKind::ArgumentConflict, "-p - reads stdin, it can't be combined with other paths");
+        }
+        if migrate || args.output.is_some() || args.zip.is_some() || args.count_only || args.dry_run || args.shred.is_some() || args.verify
+            || args.verify_after || args.length_preserving {
+            usage_error(command, ErrorKind::ArgumentConflict,
+                        "stdin can't be migrated, counted, dry run, shredded, verified, written to --output or --zip or \
+                         given a sidecar, pass --paths");
+        }
+        if paths.is_empty() && std::io::stdin().is_terminal() {
+            usage_error(command, ErrorKind::MissingRequiredArgument, "No paths given, pass --paths or pipe data to stdin");
+        }
+    }
+
+    /* Check the patterns before the password is asked for */
+    let exclude = Exclude::new(&args.exclude)
+        .unwrap_or_else(|err| usage_error(command, ErrorKind::ValueValidation, &err.to_string()));
+
+    /* Only count the work if requested, no password is needed for that */
+    if args.count_only {
+        let (files, bytes) = fish::count(&fish::dedupe_paths(paths)?, backup, crypt, args.follow_symlinks, &exclude)?;
+        println!("{} file(s), {} byte(s) would be processed", files, bytes);
+        return Ok(());
+    }
+
+    /* Get algorithm, command line first, then the FISHER_ALGORITHM environment variable */
+    let mut block_size = args.block_size.unwrap_or(128);
+    let algorithm = match args.algorithm {
+        Some(algorithm) => algorithm,
+        /* When decrypting, the algorithm may be inferred from the file extensions if requested */
+        None if !crypt && args.algorithm_from_ext => {
+            match algorithm_from_extension(&paths) {
+                Ok(info) => {
+                    if verbose {
+                        println!("Using {} based on the file extensions", info.name);
+                    }
+                    block_size = info.block_size;
+                    info.algorithm
+                }
+                Err(err) => usage_error(command, ErrorKind::MissingRequiredArgument, &err)
+            }
+        }
+        /*
+            * Headered files record their algorithm, so decrypting needs none
+            * The default only applies to raw files decrypted with --force
+        */
+        None if !crypt && header => FisherConfig::default().algorithm,
+        None => usage_error(command, ErrorKind::MissingRequiredArgument, "No algorithm specified, pass --algorithm")
+    };
+
+    /* Blowfish and Twofish have fixed block sizes */
+    match algorithm {
+        Algorithm::Blowfish => block_size = 8,
+        Algorithm::Twofish => block_size = 16,
+        Algorithm::Threefish => {}
+    }
+
+    /* Devices are only modified with --device, after confirming each one */
+    let device = args.device;
+    for path in paths.iter().filter(|path| fish::is_device(path)) {
+        if !device {
+            println!("{:?} is a device, pass --device to {} it in place", path, if crypt { "encrypt" } else { "decrypt" });
+            return Ok(());
+        }
+        if !args.dry_run && !confirm_device(path, crypt)? {
+            println!("Aborted, nothing was modified");
+            return Ok(());
+        }
+    }
+
+    /* Get password, a dry run modifies nothing and needs none */
+    let password = if args.dry_run {
+        Zeroizing::new(Vec::new())
+    } else if let (true, Some(shares)) = (crypt, args.shares) {
+        /* Encrypt with a random key split into shares */
+        Zeroizing::new(shares::deal(shares, args.threshold.unwrap_or(shares))?.into_bytes())
+    } else if !crypt && !args.share.is_empty() {
+        /* Decrypt with the key recovered from the given shares */
+        Zeroizing::new(shares::recover(&args.share)?.into_bytes())
+    } else if let Some(keyfile) = &args.keyfile {
+        r#enum::read_keyfile(keyfile)?
+    } else {
+        match read_password(crypt)? {
+            Some(password) => password,
+            None => return Ok(())
+        }
+    };
+
+    /* Overwriting in place is only as good as the storage below the filesystem */
+    if verbose && args.shred.is_some() {
+        println!("Note: SSDs and flash storage spread writes over their blocks (wear leveling), the shredded data may \
+                  survive in blocks no longer mapped to the files");
+    }
+
+    /* Create fisher instance */
+    let fisher = Fisher::from_config(FisherConfig {
+        algorithm,
+        crypt,
+        paths: match pipe {
+            true => Vec::new(),
+            false => paths
+        },
+        passphrase: password,
+        block_size,
+        kdf,
+        mac,
+        salt: args.salt,
+        backup,
+        aad: args.aad.map(String::into_bytes),
+        bind_path: !args.no_bind_path,
+        relative_to: args.relative_to,
+        sync,
+        verify: args.two_pass_verify,
+        verify_written: args.verify,
+        verify_after: args.verify_after,
+        compress: args.compress,
+        deterministic: args.deterministic,
+        shred: args.shred.unwrap_or(0),
+        xattrs,
+        io_retries: args.io_retries.unwrap_or(FisherConfig::default().io_retries),
+        sequential: args.sequential,
+        jobs: args.jobs,
+        /* The bar is only drawn on a terminal, and not among verbose or dry run output or piped data */
+        progress: match (args.ascii_progress, args.quiet || verbose || pipe || args.dry_run || !std::io::stdout().is_terminal()) {
+            (true, _) => ProgressMode::Text,
+            (false, true) => ProgressMode::Off,
+            (false, false) => ProgressMode::Bar
+        },
+        sorted: args.sorted,
+        meta: args.meta,
+        length_preserving: args.length_preserving,
+        abort_on_error: args.abort_on_first_error,
+        keep_going: args.keep_going,
+        keep_name: args.keep_name,
+        algorithm_extension: args.algorithm_extension,
+        output: args.output,
+        existing: match (args.overwrite, args.skip_existing) {
+            (true, _) => ExistingOutput::Overwrite,
+            (_, true) => ExistingOutput::Skip,
+            _ => ExistingOutput::Error
+        },
+        follow_symlinks: args.follow_symlinks,
+        exclude: args.exclude,
+        canonical_paths: args.canonical_paths,
+        device,
+        dry_run: args.dry_run,
+        concurrency_report: args.concurrency_report,
+        output_format: match args.armor {
+            true => OutputFormat::Armor,
+            false => args.output_format.unwrap_or(FisherConfig::default().output_format)
+        },
+        algorithm_map,
+        header,
+        mode,
+        ecc: args.ecc.unwrap_or(0),
+        force: args.force,
+        migrate,
+        stats_json: args.stats_json,
+        verbose,
+    })?;
+
+    /*
+        * Stop gracefully on Ctrl-C
+        * The first signal lets in-progress files finish, a second one exits immediately
+    */
+    let stop = fisher.stop_flag();
+    ctrlc::set_handler(move || {
+        if stop.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, finishing files in progress (press Ctrl-C again to exit now)");
+    })?;
+
+    /* Debug only: print the first blocks of each file instead of modifying them */
+    if let Some(blocks) = args.preview_blocks {
+        return fisher.preview(blocks);
+    }
+
+    /* Pack the paths into a ZIP container, or unpack one into the given directory, instead of modifying files */
+    if let Some(archive) = &args.zip {
+        match crypt {
+            true => {
+                let packed = container::pack(&fisher, archive)?;
+                if !args.quiet {
+                    println!("Packed {} file(s) into {:?}", packed, archive);
+                }
+            }
+            false => {
+                /* A single directory, see shape_error() */
+                let dir = &fisher.paths()[0];
+                let unpacked = container::unpack(&fisher, archive, dir, &args.entry)?;
+                if !args.quiet {
+                    println!("Unpacked {} file(s) into {:?}", unpacked, dir);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /* Modify stdin to stdout, printing nothing else to stdout */
+    if pipe {
+        return fisher.pipe();
+    }
+
+    /* Run fisher */
+    fisher.run()?;
+
+    if fisher.stopped() {
+        println!("Interrupted! {} file(s) were modified before stopping", fisher.processed());
+        std::process::exit(130);
+    }
+
+    if args.quiet {
+        return Ok(());
+    }
+
+    if args.dry_run {
+        println!("Dry run, {} file(s) would be modified, nothing was changed", fisher.processed());
+        let (size, projected) = fisher.projected();
+        if projected > 0 {
+            println!("Encrypted, {} byte(s) would take {} byte(s), +{}", size, projected, projected - size);
+        }
+        return Ok(());
+    }
+
+    if migrate {
+        println!("Migrated {} file(s), {} already had a header", fisher.processed(), fisher.skipped());
+    } else if fisher.skipped() > 0 {
+        println!("Skipped {} file(s) that were already encrypted or whose output exists", fisher.skipped());
+    }
+
+    /* Notify user that fisher is done */
+    println!("Finished!");
+
+    Ok(())
+}
+
+fn usage_error(command: &str, kind: ErrorKind, message: &str) -> ! {
+    /*
+        * Print an Error with the Usage Message and Exit, as clap Does for Invalid Arguments
+
+        @param command: &str
+            * The subcommand the arguments were given to, whose usage is printed
+        @param kind: ErrorKind
+            * The kind of error, which sets the exit status
+        @param message: &str
+            * What is wrong with the arguments
+    */
+    let mut cli = Cli::command();
+    /* Building sets the "fisher <subcommand>" names used in the usage */
+    cli.build();
+    match cli.find_subcommand_mut(command) {
+        Some(command) => command.error(kind, message).exit(),
+        None => cli.error(kind, message).exit()
+    }
+}
+
+fn shape_error(paths: &[PathBuf], output: Option<&Path>, unpack: bool) -> Option<String> {
+    /*
+        * Check the Given Paths Fit Where the Output Goes
+
+        @param paths: &[PathBuf]
+            * The paths as given
+        @param output: Option<&Path>
+            * The --output, if given
+        @param unpack: bool
+            * Whether a --zip container is unpacked into the paths
+        @return Option<String>
+            * Why the paths don't fit, None if they do
+            * -p - and an --output naming an existing file take a single file, unpacking takes a single directory
+    */
+    let dir = paths.iter().find(|path| path.is_dir());
+
+    if paths.iter().any(|path| path.as_os_str() == fish::PIPE_PATH) {
+        return dir.map(|dir| format!("-p - modifies stdin to stdout, it can't be combined with directory {:?}", dir));
+    }
+    if unpack {
+        return match paths {
+            [dir] if dir.is_dir() => None,
+            [path] => Some(format!("Unpacking --zip writes the files to a directory, {:?} is not one", path)),
+            _ => Some("Unpacking --zip needs exactly one directory in --paths to write the files to".to_string())
+        };
+    }
+    match (output, dir) {
+        (Some(output), Some(dir)) if output.is_file() =>
+            Some(format!("--output {:?} is a file and takes a single file, {:?} is a directory (pass a directory to --output)",
+                         output, dir)),
+        (Some(output), None) if output.is_file() && paths.len() > 1 =>
+            Some(format!("--output {:?} is a file and takes a single file, {} paths were given (pass a directory to --output)",
+                         output, paths.len())),
+        _ => None
+    }
+}
+
+fn algorithm_from_extension(paths: &[PathBuf]) -> Result<&'static AlgorithmInfo, String> {
+    /*
+        * Infer the Algorithm from the Conventional Extensions of the Given Files
+
+        * Every path has to be a file with the extension of the same algorithm (e.g. .bf, .tw, .tf512),
+          anything else is ambiguous and requires the algorithm to be passed explicitly
+
+        @param paths: &[PathBuf]
+            * The paths to decrypt
+        @return Result<&AlgorithmInfo, String>
+            * The inferred algorithm or a message explaining why it can't be inferred
+    */
+
+    let mut inferred: Option<&'static AlgorithmInfo> = None;
+
+    for path in paths {
+        if path.is_dir() {
+            return Err(format!("Can't infer the algorithm of directory {:?}, pass --algorithm", path));
+        }
+
+        /* Encrypted files end in .fish, the algorithm extension comes before it, or replaces it (--algorithm-extension) */
+        let name = match path.extension().is_some_and(|extension| extension == fish::ENCRYPTED_EXTENSION) {
+            true => path.with_extension(""),
+            false => path.clone()
+        };
+        let info = match name.extension().and_then(|extension| extension.to_str()).and_then(extension_info) {
+            Some(info) => info,
+            None => return Err(format!("{:?} has no algorithm extension, pass --algorithm", path))
+        };
+
+        match inferred {
+            Some(inferred) if inferred.name != info.name => {
+                return Err(format!("Extensions are ambiguous ({} and {}), pass --algorithm", inferred.name, info.name));
+            }
+            _ => inferred = Some(info)
+        }
+    }
+
+    inferred.ok_or("No files to infer the algorithm from, pass --algorithm".to_string())
+}
+
+fn init_tracing(format: &str) {
+    /*
+        * Write Spans and Events of the Run to stderr
+
+        * Every directory and file gets a span carrying its path, every line carries the thread id,
+          closing spans report how long the directory or file took
+
+        @param format: &str
+            * pretty (human readable), compact or json (one JSON object per line)
+    */
+
+    let builder = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::DEBUG)
+        .with_thread_ids(true)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match format {
+        "compact" => builder.compact().init(),
+        "json" => builder.json().init(),
+        _ => builder.init()
+    }
+}
+
+fn confirm_device(path: &PathBuf, crypt: bool) -> FResult<bool> {
+    /*
+        * Ask the User to Confirm Modifying a Device
+
+        @param path: &PathBuf
+            * The device
+        @param crypt: bool
+            * Whether the device is about to be encrypted or decrypted
+        @return FResult: Result<bool, Box<dyn Error>>
+            * True if the user typed the device path back
+    */
+    println!("WARNING: every byte of {:?} will be {} in place. A wrong password or an interrupted run",
+             path, if crypt { "encrypted" } else { "decrypted" });
+    println!("leaves the device unusable. Type the device path to continue:");
+    print!("-> ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == path.to_string_lossy())
+}
+
+fn parse_block_size(size: &str) -> Result<usize, String> {
+    /*
+        * Parse a Threefish Block Size
+
+        @param size: &str
+            * The block size in bits (256, 512, 1024) or bytes (32, 64, 128)
+        @return Result<usize, String>
+            * The block size in bytes, or an error if it is not a Threefish block size
+    */
+
+    match size.parse::<usize>() {
+        Ok(256) => Ok(32),
+        Ok(512) => Ok(64),
+        Ok(1024) => Ok(128),
+        /* Check if bit size is a valid Threefish block size in bytes */
+        Ok(bytes) if ALGORITHMS.iter().any(|info| info.algorithm == Algorithm::Threefish && info.block_size == bytes) => Ok(bytes),
+        _ => Err("expected 256, 512 or 1024 bits (32, 64 or 128 bytes)".to_string())
+    }
+}
+
+fn read_password(confirm: bool) -> FResult<Option<Zeroizing<Vec<u8>>>> {
+    /*
+        * Get the Password from PASSWORD_ENV, or Prompt for it
+
+        * The environment of a process can be read by other processes of the same user and may end up
+          in shell history or CI logs, so the prompt is safer. PASSWORD_ENV is meant for cron jobs and
+          scripts that have no terminal to type in
+
+        @param confirm: bool
+            * Whether a prompted password is asked for a second time
+        @return FResult: Result<Option<Zeroizing<Vec<u8>>>, Box<dyn Error>>
+            * The password, None after printing why there is none (empty, or not confirmed)
+    */
+    let password = match std::env::var(PASSWORD_ENV) {
+        Ok(password) => Some(Zeroizing::new(password)),
+        Err(std::env::VarError::NotPresent) => None,
+        Err(err) => return Err(format!("Could not read {}: {}", PASSWORD_ENV, err).into())
+    };
+    let from_env = password.is_some();
+    let password = match password {
+        Some(password) => password,
+        None => Zeroizing::new(rpassword::prompt_password("Enter Password -> ")?)
+    };
+
+    /* Check if password is empty or if blank */
+    if password.trim().is_empty() {
+        match from_env {
+            true => println!("Password cannot be empty, {} is set but blank", PASSWORD_ENV),
+            false => println!("Password cannot be empty")
+        }
+        return Ok(None);
+    }
+
+    /* A typo when encrypting leaves files nobody can decrypt, a wrong password when decrypting just fails */
+    if confirm && !from_env {
+        let confirmation = Zeroizing::new(rpassword::prompt_password("Confirm Password -> ")?);
+        if *confirmation != *password {
+            println!("Passwords do not match, nothing was modified");
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(Zeroizing::new(password.as_bytes().to_vec())))
+}
+
+fn print_password_hash(args: &HashArgs) -> FResult<()> {
+    /*
+        * Print a Verifier (or with --show-key the Key) Derived from a Password, Modifying Nothing
+
+        * The verifier is SHA-256 of the derived key, so it identifies the key without revealing it
+        * The key is the salted KDF of encrypt --salt when a salt is given, otherwise the unsalted SHA-2 key of
+          headerless files, never the key of a headered file encrypted with a random salt
+
+        @param args: &HashArgs
+            * The algorithm and block size as for encryption, and the salt and KDF if any
+        @return FResult: Result<(), Box<dyn Error>>
+    */
+
+    let block_size = match args.algorithm {
+        Algorithm::Blowfish => 8,
+        Algorithm::Twofish => 16,
+        Algorithm::Threefish => args.block_size.unwrap_or(128)
+    };
+
+    let password = match &args.keyfile {
+        Some(keyfile) => r#enum::read_keyfile(keyfile)?,
+        None => match read_password(false)? {
+            Some(password) => password,
+            None => return Ok(())
+        }
+    };
+
+    /* The salted KDF as encrypt --salt runs it, or the unsalted key of headerless files */
+    let key = match &args.salt {
+        Some(salt) => select_kdf("password-hash", args.kdf.as_deref(), args.kdf_iterations)
+            .derive(args.algorithm, block_size, &password, salt)?,
+        None => r#enum::sha2_key(args.algorithm, block_size, &password)?
+    };
+
+    if args.show_key {
+        let hex: Zeroizing<String> = Zeroizing::new(key.iter().map(|byte| format!("{:02x}", byte)).collect());
+        println!("Key: {}", *hex);
+    } else {
+        let hex: String = Sha256::digest(&*key).iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("Verifier: {}", hex);
+    }
+
+    Ok(())
+}
+
+fn select_kdf(command: &str, kdf: Option<&str>, iterations: Option<u32>) -> Kdf {
+    /*
+        * Get the KDF Chosen with --kdf and --kdf-iterations
+
+        @param command: &str
+            * The subcommand, for the usage message
+        @param kdf: Option<&str>
+            * The value of --kdf, pbkdf2 (default), argon2 or argon2id in any case
+        @param iterations: Option<u32>
+            * The value of --kdf-iterations, PBKDF2 only
+        @return Kdf: PBKDF2 or Argon2id with the given or default parameters, exits on invalid combinations
+    */
+    match kdf.map(|kdf| kdf.to_lowercase()).as_deref() {
+        None | Some("pbkdf2") => match iterations {
+            Some(iterations) => Kdf::Pbkdf2 { iterations },
+            None => FisherConfig::default().kdf
+        },
+        /* Argon2id is tuned by its passes at its memory, see --kdf-target-ms */
+        _ => match iterations {
+            Some(_) => usage_error(command, ErrorKind::ArgumentConflict,
+                                   "--kdf-iterations sets PBKDF2 iterations, it can't be used with --kdf argon2"),
+            None => Kdf::ARGON2ID_DEFAULT
+        }
+    }
+}
+
+fn doctor(args: &DoctorArgs) -> FResult<()> {
+    /*
+        * Print Why the Given File Doesn't Decrypt and Exit With the Diagnosis, see doctor::Diagnosis
+
+        @param args: &DoctorArgs
+            * The file and where the password comes from
+        @return FResult: Result<(), Box<dyn Error>>
+            * An error if the file can't be read, otherwise the process exits
+    */
+    let password = match &args.keyfile {
+        Some(keyfile) => r#enum::read_keyfile(keyfile)?,
+        None => match read_password(false)? {
+            Some(password) => password,
+            None => return Ok(())
+        }
+    };
+
+    let relative_to = match &args.relative_to {
+        Some(root) => Some(root.canonicalize().map_err(|err| format!("Could not resolve {:?}: {}", root, err))?),
+        None => None
+    };
+    let (diagnosis, header) = doctor::diagnose(&args.file, &password, args.aad.as_deref().map(str::as_bytes),
+                                               relative_to.as_deref())?;
+    if let Some(header) = &header {
+        let name = ALGORITHMS.iter()
+            .find(|info| info.algorithm == header.algorithm && info.block_size == header.block_size)
+            .map_or("unknown", |info| info.name);
+        println!("Header version {}, {} in {} mode, {} byte(s) of plaintext, {} MAC", header.version, name,
+                 header.mode.name().to_uppercase(), header.original_size, header.mac_algorithm.name());
+    }
+    println!("{:?}: {}", args.file, diagnosis.message());
+    if diagnosis == doctor::Diagnosis::Corrupted && header.is_some_and(|header| header.flags & FLAG_PATH != 0) {
+        println!("The file is bound to the name it was encrypted from, it also fails authentication when renamed");
+    }
+
+    std::process::exit(diagnosis.exit_code());
+}
+
+fn parse_algorithm_map(map: &str) -> Result<Vec<(String, Algorithm, usize)>, String> {
+    /*
+        * Parse an Algorithm Map
+
+        @param map: &str
+            * Comma separated pattern=algorithm pairs, e.g. '*.mp4=bf,*.txt=tf512'. The algorithm is
+              an algorithm name or the extension of one (bf, tw, tf256, tf512, tf1024)
+        @return Result<Vec<(String, Algorithm, usize)>, String>
+            * The patterns with their algorithm and block size, in the given order
+    */
+
+    map.split(',').map(|entry| {
+        let (pattern, name) = entry.split_once('=')
+            .ok_or(format!("Invalid algorithm map entry '{}', expected pattern=algorithm", entry))?;
+
+        /* Extensions name a block size, plain algorithm names use the algorithm's default */
+        let info = extension_info(name)
+            .or_else(|| parse_algorithm(name).ok()
+                .and_then(|algorithm| ALGORITHMS.iter().rev().find(|info| info.algorithm == algorithm)))
+            .ok_or(format!("Unknown algorithm '{}' in algorithm map", name))?;
+
+        Ok((pattern.to_string(), info.algorithm, info.block_size))
+    }).collect()
+}
+
+fn parse_algorithm(name: &str) -> Result<Algorithm, String> {
+    /*
+        * Parse an Algorithm Name
+
+        @param name: &str
+            * The name given with --algorithm or in FISHER_ALGORITHM, in any case
+        @return Result<Algorithm, String>
+            * The algorithm, or an error if the name is not an algorithm
+    */
+
+    match name.to_lowercase().as_str() {
+        "blowfish" | "bf" => Ok(Algorithm::Blowfish),
+        "twofish" | "tw" => Ok(Algorithm::Twofish),
+        "threefish" | "tf" => Ok(Algorithm::Threefish),
+        _ => Err("expected blowfish (bf), twofish (tw) or threefish (tf)".to_string())
+    }
+}
+
+fn parse_jobs(jobs: &str) -> Result<usize, String> {
+    /*
+        * Parse the Value of --jobs or FISHER_THREADS
+
+        @param jobs: &str
+            * The number of worker threads
+        @return Result<usize, String>
+    */
+    match jobs.parse::<usize>() {
+        Ok(jobs) if jobs > 0 => Ok(jobs),
+        _ => Err("expected a number of worker threads, at least 1".to_string())
+    }
+}
+
+fn parse_sync(sync: &str) -> Result<SyncMode, String> {
+    /*
+        * Parse the Value of --sync
+
+        @param sync: &str
+            * file (the default of a bare --sync) or dir
+        @return Result<SyncMode, String>
+    */
+    match sync.to_lowercase().as_str() {
+        "file" => Ok(SyncMode::File),
+        "dir" => Ok(SyncMode::Dir),
+        _ => Err("expected --sync or --sync=dir".to_string())
+    }
+}
+
+fn parse_backup(backup: &str) -> Result<BackupMode, String> {
+    /*
+        * Parse the Value of --backup
+
+        @param backup: &str
+            * keep (the default of a bare --backup) or removable
+        @return Result<BackupMode, String>
+    */
+    match backup.to_lowercase().as_str() {
+        "keep" => Ok(BackupMode::Keep),
+        "removable" => Ok(BackupMode::Removable),
+        _ => Err("expected --backup or --backup=removable".to_string())
+    }
+}
+
+fn parse_output_format(format: &str) -> Result<OutputFormat, String> {
+    /*
+        * Parse the Value of --output-format
+
+        @param format: &str
+            * raw, armor or base64url
+        @return Result<OutputFormat, String>
+    */
+    OutputFormat::parse(format).ok_or("expected one of raw, armor or base64url".to_string())
+}
+
+fn parse_mac(mac: &str) -> Result<MacAlgorithm, String> {
+    /*
+        * Parse the Value of --mac
+
+        @param mac: &str
+            * hmac-sha256, blake3 or poly1305
+        @return Result<MacAlgorithm, String>
+    */
+    MacAlgorithm::parse(mac).ok_or("expected one of hmac-sha256, blake3 or poly1305".to_string())
+}
+
+fn parse_mode(mode: &str) -> Result<Mode, String> {
+    /*
+        * Parse the Value of --mode
+
+        @param mode: &str
+            * cbc, ctr or ecb
+        @return Result<Mode, String>
+    */
+    Mode::parse(mode).ok_or("expected one of cbc, ctr or ecb".to_string())
+}
+
+fn parse_ecc(ratio: &str) -> Result<u8, String> {
+    /*
+        * Parse the Value of --ecc
+
+        @param ratio: &str
+            * Parity bytes per data byte, e.g. 0.1
+        @return Result<u8, String>
+            * The parity bytes per 255 byte codeword
+    */
+    ecc::parity_for_ratio(ratio)
+        .ok_or("expected parity per data byte greater than 0 and at most 1, e.g. 0.1".to_string())
+}
+
+fn parse_salt(salt: &str) -> Result<[u8; SALT_SIZE], String> {
+    /*
+        * Parse the Value of --salt
+
+        @param salt: &str
+            * The salt in hex, e.g. 000102030405060708090a0b0c0d0e0f
+        @return Result<[u8; SALT_SIZE], String>
+    */
+    if salt.len() != SALT_SIZE * 2 || !salt.is_ascii() {
+        return Err(format!("expected {} hex digits ({} bytes)", SALT_SIZE * 2, SALT_SIZE));
+    }
+
+    let mut bytes = [0u8; SALT_SIZE];
+    for (byte, digits) in bytes.iter_mut().zip(salt.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16)
+            .map_err(|_| format!("'{}' is not hex", salt))?;
+    }
+    Ok(bytes)
+}
+
+fn existing_path(path: &str) -> Result<PathBuf, String> {
+    /*
+        * Check a Value of --paths Exists
+
+        @param path: &str
+            * The path as given, or - for stdin
+        @return Result<PathBuf, String>
+    */
+    match path == fish::PIPE_PATH || std::path::Path::new(path).exists() {
+        true => Ok(PathBuf::from(path)),
+        false => Err("path does not exist".to_string())
+    }
+}
+
+pub(crate) fn print_algorithms() {
+    /*
+        * Print Every Supported Algorithm and its Parameters
+    */
+
+    println!("{:<16}{:<10}{:<12}{:<24}{:<11}Flags", "Algorithm", "Key bits", "Block bits", "Type", "Extension");
+    for info in &ALGORITHMS {
+        println!("{:<16}{:<10}{:<12}{:<24}{:<11}{}", info.name, info.key_size * 8, info.block_size * 8, info.kind,
+                 format!(".{}", info.extension), info.flags);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn shape_error_rejects_directories_for_single_files() {
+        let dir = std::env::temp_dir().join(format!("fisher-test-{}-shape", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (file, other) = (dir.join("file"), dir.join("other"));
+        fs::write(&file, b"file").unwrap();
+        fs::write(&other, b"other").unwrap();
+        let pipe = PathBuf::from(fish::PIPE_PATH);
+
+        for (paths, output, unpack, message) in [
+            (vec![pipe.clone(), dir.clone()], None, false, Some("can't be combined with directory")),
+            (vec![pipe.clone()], None, false, None),
+            (vec![dir.clone()], Some(&file), false, Some("is a directory")),
+            (vec![file.clone(), other.clone()], Some(&file), false, Some("2 paths were given")),
+            (vec![other.clone()], Some(&file), false, None),
+            (vec![dir.clone(), file.clone()], Some(&dir), false, None),
+            (vec![file.clone()], None, true, Some("is not one")),
+            (vec![dir.clone(), dir.clone()], None, true, Some("exactly one directory")),
+            (vec![dir.clone()], None, true, None),
+        ] {
+            let err = shape_error(&paths, output.map(PathBuf::as_path), unpack);
+            match message {
+                Some(message) => assert!(err.as_ref().is_some_and(|err| err.contains(message)), "{:?}: {:?}", paths, err),
+                None => assert_eq!(err, None, "{:?}", paths)
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn algorithm_from_extension_needs_one_algorithm() {
+        let info = algorithm_from_extension(&paths(&["a.tw.fish", "b.TW", "c.tw"])).unwrap();
+        assert_eq!((info.algorithm, info.block_size), (Algorithm::Twofish, 16));
+        assert_eq!(algorithm_from_extension(&paths(&["a.tf512"])).unwrap().block_size, 64);
+
+        for (names, message) in [
+            (&["a.bf", "b.tw.fish"][..], "ambiguous (Blowfish and Twofish)"),
+            (&["a.tf256", "b.tf1024"][..], "ambiguous (Threefish-256 and Threefish-1024)"),
+            (&["a.bf", "b.fish"][..], "\"b.fish\" has no algorithm extension"),
+            (&["a.txt"][..], "\"a.txt\" has no algorithm extension"),
+            (&[][..], "No files"),
+        ] {
+            match algorithm_from_extension(&paths(names)) {
+                Ok(info) => panic!("{:?} inferred {}", names, info.name),
+                Err(err) => assert!(err.contains(message), "{:?}: {}", names, err)
+            }
+        }
+    }
+}
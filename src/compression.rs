@@ -0,0 +1,50 @@
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::FResult;
+
+/*
+    * DEFLATE Compression of the Plaintext Before Encryption
+
+    * Ciphertext doesn't compress, so data is compressed before it is encrypted and decompressed
+      after it is decrypted
+    * Data that doesn't shrink (already compressed media, archives) is encrypted as it is, FLAG_COMPRESSED
+      in the header records which one was done
+*/
+
+pub(crate) fn compress(data: &[u8]) -> FResult<Option<Vec<u8>>> {
+    /*
+        * Compress the Given Plaintext
+
+        @param data: &[u8]
+            * The plaintext
+        @return FResult: Result<Option<Vec<u8>>, Box<dyn Error>>
+            * The raw DEFLATE stream, None if it isn't smaller than the plaintext
+    */
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+
+    Ok(match compressed.len() < data.len() {
+        true => Some(compressed),
+        false => None
+    })
+}
+
+pub(crate) fn decompress(data: &[u8]) -> FResult<Vec<u8>> {
+    /*
+        * Decompress a Plaintext Compressed with compress()
+
+        @param data: &[u8]
+            * The raw DEFLATE stream
+        @return FResult: Result<Vec<u8>, Box<dyn Error>>
+            * The plaintext, or an error for a corrupted stream
+    */
+    let mut output = Vec::with_capacity(data.len() * 2);
+    DeflateDecoder::new(data).read_to_end(&mut output)
+        .map_err(|err| format!("Could not decompress: {}", err))?;
+    Ok(output)
+}
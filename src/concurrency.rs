@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/*
+    * Worker Utilization Recorder
+
+    * A worker is any thread modifying a file, counted with Concurrency::worker()
+    * The active count changes under the idle lock, so idle periods start and end exactly when the
+      count reaches and leaves zero. One short lock per file is negligible next to the file IO
+*/
+pub(crate) struct Concurrency {
+    active: AtomicUsize,
+    busy_nanos: AtomicU64,
+    idle: Mutex<(Duration, Option<Instant>)>,
+    peak: AtomicUsize,
+    started: Instant,
+}
+
+/* Counts its thread as an active worker until dropped, including when the file fails or panics */
+pub(crate) struct Worker<'a> {
+    concurrency: &'a Concurrency,
+    started: Instant,
+}
+
+impl Concurrency {
+    pub(crate) fn new() -> Concurrency {
+        /*
+            * Create a new Utilization Recorder, the run starts idle now
+
+            @return Concurrency
+        */
+        let now = Instant::now();
+        Concurrency {
+            active: AtomicUsize::new(0),
+            busy_nanos: AtomicU64::new(0),
+            idle: Mutex::new((Duration::ZERO, Some(now))),
+            peak: AtomicUsize::new(0),
+            started: now,
+        }
+    }
+
+    pub(crate) fn worker(&self) -> Worker<'_> {
+        /*
+            * Count the Calling Thread as an Active Worker
+
+            @param self: Concurrency Instance
+            @return Worker: Guard counting the thread until dropped
+        */
+        let mut idle = self.idle.lock().unwrap();
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(active, Ordering::SeqCst);
+
+        /* First active worker, the idle period ends */
+        if let Some(since) = idle.1.take() {
+            idle.0 += since.elapsed();
+        }
+
+        Worker { concurrency: self, started: Instant::now() }
+    }
+
+    pub(crate) fn report(&self) {
+        /*
+            * Print the Peak and Average Number of Active Workers and the Idle Time
+
+            @param self: Concurrency Instance
+        */
+        let elapsed = self.started.elapsed();
+        let idle = {
+            let idle = self.idle.lock().unwrap();
+            idle.0 + idle.1.map(|since| since.elapsed()).unwrap_or_default()
+        };
+        let busy = Duration::from_nanos(self.busy_nanos.load(Ordering::SeqCst));
+
+        /* Average over the whole run, so idle time pulls it down */
+        let average = match elapsed.is_zero() {
+            true => 0.0,
+            false => busy.as_secs_f64() / elapsed.as_secs_f64()
+        };
+
+        println!("Concurrency: peak {} worker(s), average {:.2}, idle {:.2}s of {:.2}s",
+                 self.peak.load(Ordering::SeqCst), average, idle.as_secs_f64(), elapsed.as_secs_f64());
+    }
+}
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        let concurrency = self.concurrency;
+        concurrency.busy_nanos.fetch_add(self.started.elapsed().as_nanos() as u64, Ordering::SeqCst);
+
+        /* Last active worker, an idle period starts */
+        let mut idle = concurrency.idle.lock().unwrap();
+        if concurrency.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            idle.1 = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::write::SimpleFileOptions;
+
+use crate::FResult;
+use crate::exclude::Exclude;
+use crate::fish::{BackupMode, Fisher, is_symlink, skip_file};
+
+/*
+    * ZIP Container of Individually Encrypted Files
+
+    * Every entry is a complete encrypted file (header and ciphertext) named after the file's path,
+      so the container can be listed with any ZIP tool and a single entry extracted and decrypted
+      on its own with `fisher decrypt`, without touching the rest
+    * Entries are stored uncompressed, ciphertext doesn't compress
+*/
+
+pub(crate) fn pack(fisher: &Fisher, archive: &Path) -> FResult<usize> {
+    /*
+        * Encrypt the Paths of the Given Fisher into a New ZIP Container
+
+        * The files themselves are left untouched
+
+        @param fisher: &Fisher
+            * The Fisher to encrypt with, in encrypt mode
+        @param archive: &Path
+            * The container to create, must not exist yet
+        @return FResult: Result<usize, Box<dyn Error>>
+            * The number of files packed
+    */
+    let file = OpenOptions::new().write(true).create_new(true).open(archive)
+        .map_err(|err| format!("Could not create {:?}: {}", archive, err))?;
+
+    /* Don't leave a partial container behind */
+    match write_entries(fisher, archive, file) {
+        Ok(packed) => Ok(packed),
+        Err(err) => {
+            let _ = fs::remove_file(archive);
+            Err(err)
+        }
+    }
+}
+
+fn write_entries(fisher: &Fisher, archive: &Path, file: File) -> FResult<usize> {
+    /*
+        * Write an Encrypted Entry for Every File Under the Paths of the Given Fisher
+
+        @param fisher: &Fisher
+            * The Fisher to encrypt with
+        @param archive: &Path
+            * The container being written
+        @param file: File
+            * The opened container
+        @return FResult: Result<usize, Box<dyn Error>>
+            * The number of files packed
+    */
+    /* Never pack the container into itself when it is created inside one of the paths */
+    let archive = archive.canonicalize()?;
+
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut packed = 0;
+
+    for path in fisher.paths() {
+        /* Entries are named relative to the directory containing the given path, like tar does */
+        let base = path.parent().unwrap_or(Path::new(""));
+
+        for file in walk(fisher.exclude(), path)? {
+            if file.canonicalize()? == archive {
+                continue;
+            }
+
+            let name = entry_name(file.strip_prefix(base).unwrap_or(&file));
+            let data = fs::read(&file)?;
+            zip.start_file(name.as_str(), options)?;
+            zip.write_all(&fisher.encrypt_bytes(&file, &data)?)?;
+
+            if fisher.verbose() {
+                println!("Packed {:?} as {}", file, name);
+            }
+            packed += 1;
+        }
+    }
+
+    zip.finish()?.flush()?;
+    Ok(packed)
+}
+
+pub(crate) fn unpack(fisher: &Fisher, archive: &Path, dir: &Path, entries: &[String]) -> FResult<usize> {
+    /*
+        * Decrypt the Entries of a ZIP Container into the Given Directory
+
+        @param fisher: &Fisher
+            * The Fisher to decrypt with, in decrypt mode
+        @param archive: &Path
+            * The container written by pack()
+        @param dir: &Path
+            * The directory to write the decrypted files to, existing files are never overwritten
+        @param entries: &[String]
+            * The entry names to decrypt, empty for all of them
+        @return FResult: Result<usize, Box<dyn Error>>
+            * The number of files unpacked
+    */
+    let mut zip = ZipArchive::new(File::open(archive)?)?;
+    let mut unpacked = 0;
+
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        if entry.is_dir() {
+            continue;
+        }
+
+        /* Refuse names escaping the directory (absolute paths, ..) */
+        let name = entry.enclosed_name()
+            .ok_or(format!("Refusing to unpack entry {} of {:?} outside the directory", index, archive))?;
+        if !entries.is_empty() && !entries.iter().any(|wanted| Path::new(wanted) == name) {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        let output = fisher.decrypt_bytes(&name, &data, None)?;
+
+        let path = dir.join(&name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().write(true).create_new(true).open(&path)
+            .map_err(|err| format!("Could not create {:?}: {}", path, err))?
+            .write_all(&output)?;
+
+        if fisher.verbose() {
+            println!("Unpacked {:?} to {:?}", name, path);
+        }
+        unpacked += 1;
+    }
+
+    /* Every requested entry must have been found */
+    if !entries.is_empty() && unpacked < entries.len() {
+        return Err(format!("Only {} of the {} requested entries were found in {:?}", unpacked, entries.len(), archive).into());
+    }
+
+    Ok(unpacked)
+}
+
+fn walk(exclude: &Exclude, path: &Path) -> FResult<Vec<PathBuf>> {
+    /*
+        * Collect the Files Under the Given Path, Sorted so Containers are Reproducible
+
+        @param exclude: &Exclude
+            * The patterns of the directory above
+        @param path: &Path
+            * A file or directory
+        @return FResult: Result<Vec<PathBuf>, Box<dyn Error>>
+            * The path itself if it is a file, otherwise every file below it a run would modify
+    */
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let exclude = exclude.enter(path)?;
+    let mut modules = fs::read_dir(path)?
+        .map(|module| module.map(|module| module.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    modules.sort();
+
+    let mut files = Vec::new();
+    for module in modules {
+        /* Links are never packed, they may lead outside the path or back up the tree */
+        if is_symlink(&module) || exclude.matching(&module).is_some() {
+            continue;
+        }
+        match module.is_dir() {
+            true => files.extend(walk(&exclude, &module)?),
+            false => {
+                /* Encrypted files in the directory are packed as they are, the archive is written outside of it */
+                if !skip_file(&module, BackupMode::Off, false) {
+                    files.push(module);
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn entry_name(path: &Path) -> String {
+    /*
+        * Get the ZIP Entry Name of the Given Relative Path
+
+        @param path: &Path
+            * The path relative to the packed directory's parent
+        @return String
+            * The normal components of the path joined with '/', as ZIP requires
+    */
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::Path;
+
+use crate::FResult;
+use crate::blocks;
+use crate::ecc;
+use crate::encoding;
+use crate::fish::bound_name;
+use crate::header::{FLAG_MAC, FLAG_PKCS7, FLAG_RELATIVE, Header, Mode};
+use crate::r#enum::{mac_key, new_cipher};
+
+/*
+    * Diagnosing Why an Encrypted File Doesn't Decrypt, see fisher doctor
+
+    * Nothing is written, the file is only read and its MAC checked
+*/
+
+/* What is wrong with a file, each with its own exit status */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Diagnosis {
+    /* Authenticates, decrypting it should work */
+    Ok,
+    /* Starts with neither the header magic nor another output format of it */
+    NotFisher,
+    /* Ends before its header or ciphertext does */
+    Truncated,
+    /* Fails authentication, and the padding of its last block doesn't decrypt either */
+    WrongPassword,
+    /* Fails authentication although the key decrypts its last block, or its header or parity is damaged */
+    Corrupted,
+}
+
+impl Diagnosis {
+    pub(crate) fn exit_code(&self) -> i32 {
+        /*
+            * Get the Exit Status fisher doctor Exits With
+
+            * 1 and 2 are taken by errors and invalid arguments
+
+            @param self: Diagnosis Instance
+            @return i32: 0 for OK, 3 to 6 otherwise
+        */
+        match self {
+            Diagnosis::Ok => 0,
+            Diagnosis::NotFisher => 3,
+            Diagnosis::Truncated => 4,
+            Diagnosis::WrongPassword => 5,
+            Diagnosis::Corrupted => 6
+        }
+    }
+
+    pub(crate) fn message(&self) -> &'static str {
+        /*
+            * Describe the Diagnosis
+
+            @param self: Diagnosis Instance
+            @return &str
+        */
+        match self {
+            Diagnosis::Ok => "OK",
+            Diagnosis::NotFisher => "not a fisher file",
+            Diagnosis::Truncated => "truncated",
+            Diagnosis::WrongPassword => "wrong password",
+            Diagnosis::Corrupted => "corrupted body"
+        }
+    }
+}
+
+pub(crate) fn diagnose(path: &Path, passphrase: &[u8], aad: Option<&[u8]>, relative_to: Option<&Path>)
+                       -> FResult<(Diagnosis, Option<Header>)> {
+    /*
+        * Find Out Whether the Given File Decrypts With the Given Passphrase, and Why Not
+
+        @param path: &Path
+            * The encrypted file
+        @param passphrase: &[u8]
+            * The passphrase (or key file contents) it should decrypt with
+        @param aad: Option<&[u8]>
+            * The --aad it was encrypted with, if any
+        @param relative_to: Option<&Path>
+            * The canonical --relative-to root it is below, if any
+        @return FResult: Result<(Diagnosis, Option<Header>), Box<dyn Error>>
+            * The diagnosis and the header, if it could be read
+            * An error if the file can't be read, was written by a newer fisher or needs another --aad or --relative-to
+    */
+    let data = fs::read(path)?;
+    if !encoding::is_encrypted(&data) {
+        return Ok((Diagnosis::NotFisher, None));
+    }
+    let data = match encoding::decode(&data) {
+        Ok(data) => data,
+        Err(err) if err.to_string().contains("missing its END line") => return Ok((Diagnosis::Truncated, None)),
+        Err(_) => return Ok((Diagnosis::Corrupted, None))
+    };
+
+    let mut header = match Header::parse(&data) {
+        Ok(Some(header)) => header,
+        Ok(None) => return Ok((Diagnosis::NotFisher, None)),
+        Err(err) if err.to_string() == "Truncated header" => return Ok((Diagnosis::Truncated, None)),
+        Err(err) if err.to_string().contains("newer fisher") => return Err(err),
+        /* Unknown ids in a header this build should understand mean it was damaged */
+        Err(_) => return Ok((Diagnosis::Corrupted, None))
+    };
+
+    /* A file bound to its name is checked under the name it has now (below its root), a renamed file fails authentication */
+    let root = match header.flags & FLAG_RELATIVE != 0 {
+        true => Some(relative_to.ok_or(format!(
+            "Could not check {:?}, it is bound to its path below a directory, pass that directory with --relative-to", path))?),
+        false => None
+    };
+    header.associate(aad, bound_name(path, root)?.as_deref()).map_err(|err| format!("Could not check {:?}, {}", path, err))?;
+
+    let ciphertext = &data[header.size()..];
+    let ciphertext = match header.ecc {
+        0 => ciphertext.to_vec(),
+        parity => match ecc::decode(ciphertext, parity) {
+            Ok((ciphertext, _)) => ciphertext,
+            Err(_) => return Ok((Diagnosis::Corrupted, Some(header)))
+        }
+    };
+
+    /* Compare the ciphertext length with the length the recorded plaintext length implies */
+    let pkcs7 = header.flags & FLAG_PKCS7 != 0;
+    let block_size = header.block_size as u64;
+    let expected = match (header.mode, pkcs7) {
+        (Mode::Ctr, _) => header.original_size,
+        (_, true) => (header.original_size / block_size + 1) * block_size,
+        (_, false) => header.original_size.div_ceil(block_size) * block_size
+    };
+    let size = ciphertext.len() as u64;
+    if size < expected {
+        return Ok((Diagnosis::Truncated, Some(header)));
+    }
+    if size != expected {
+        return Ok((Diagnosis::Corrupted, Some(header)));
+    }
+
+    /* Same key as decrypting the file, from the master key of its run if it has one */
+    let key = header.key(passphrase)?;
+
+    let authenticates = header.flags & FLAG_MAC == 0 || header.verify_mac(mac_key(&key).as_slice(), &ciphertext)?;
+    /*
+        * A wrong key decrypts the last block to noise, which is hardly ever valid padding, while damage
+          elsewhere leaves it intact
+        * CTR files have no padding, a failing MAC can't tell a wrong key from a damaged body
+    */
+    let key_matches = match pkcs7 {
+        true => last_block_padded(&header, &key, &ciphertext)?,
+        false => authenticates
+    };
+
+    let diagnosis = match (authenticates, key_matches) {
+        (true, true) => Diagnosis::Ok,
+        (_, false) => Diagnosis::WrongPassword,
+        (false, true) => Diagnosis::Corrupted
+    };
+    Ok((diagnosis, Some(header)))
+}
+
+fn last_block_padded(header: &Header, key: &[u8], ciphertext: &[u8]) -> FResult<bool> {
+    /*
+        * Check Whether the Last Block of a PKCS#7 Padded File Decrypts to the Padding its Header Implies
+
+        @param header: &Header
+            * The header of the file
+        @param key: &[u8]
+            * The raw cipher key the file would be decrypted with
+        @param ciphertext: &[u8]
+            * The ciphertext, whole blocks without parity
+        @return FResult: Result<bool, Box<dyn Error>>
+    */
+    let fisher = new_cipher(header.algorithm, header.block_size, key, &header.tweak)?;
+    let last = ciphertext.len() - header.block_size;
+
+    let mut block = ciphertext[last..].to_vec();
+    if !fisher.decrypt_block(&mut block)? {
+        return Ok(false);
+    }
+    /* CBC chains on the block before, or on the IV for a single block */
+    if header.mode == Mode::Cbc {
+        let previous = match last {
+            0 => header.iv.as_slice(),
+            _ => &ciphertext[last - header.block_size..last]
+        };
+        for (byte, previous) in block.iter_mut().zip(previous) {
+            *byte ^= previous;
+        }
+    }
+
+    Ok(blocks::unpad(&mut block, header.block_size).is_ok() && (last + block.len()) as u64 == header.original_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zeroize::Zeroizing;
+
+    use crate::fish::{Fisher, FisherConfig};
+    use crate::r#enum::{Algorithm, Kdf};
+
+    #[test]
+    fn diagnoses_each_failure() {
+        let dir = std::env::temp_dir().join(format!("fisher-test-{}-doctor", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let fisher = Fisher::from_config(FisherConfig {
+            algorithm: Algorithm::Twofish,
+            block_size: 16,
+            passphrase: Zeroizing::new(b"password".to_vec()),
+            kdf: Kdf::Pbkdf2 { iterations: 1 },
+            ..FisherConfig::default()
+        }).unwrap();
+        let encrypted = fisher.encrypt_bytes(Path::new("file"), &[3; 1000]).unwrap();
+
+        let mut truncated = encrypted.clone();
+        truncated.truncate(500);
+        let mut corrupted = encrypted.clone();
+        corrupted[300] ^= 1;
+
+        for (contents, passphrase, expected) in [
+            (encrypted.clone(), &b"password"[..], Diagnosis::Ok),
+            (encrypted, b"wrong", Diagnosis::WrongPassword),
+            (b"plaintext".to_vec(), b"password", Diagnosis::NotFisher),
+            (truncated, b"password", Diagnosis::Truncated),
+            (b"FSHR\x06".to_vec(), b"password", Diagnosis::Truncated),
+            (corrupted, b"password", Diagnosis::Corrupted),
+        ] {
+            let path = dir.join("file.fish");
+            fs::write(&path, contents).unwrap();
+            assert_eq!(diagnose(&path, passphrase, None, None).unwrap().0, expected);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+use reed_solomon::{Decoder, Encoder};
+
+use crate::FResult;
+
+/* Bytes per Reed-Solomon codeword (data and parity) over GF(256) */
+const CODEWORD_SIZE: usize = 255;
+
+/*
+    * Reed-Solomon Error Correction Around the Ciphertext
+
+    * The ciphertext is cut into codewords of CODEWORD_SIZE - parity data bytes, each followed by
+      its parity bytes. The last codeword is shortened to the remaining data
+    * Up to parity / 2 corrupted bytes per codeword are corrected on decrypt, wherever they are
+    * The header is not covered, the parity count is read from it
+*/
+
+pub(crate) fn parity_for_ratio(ratio: &str) -> Option<u8> {
+    /*
+        * Get the Parity Bytes per Codeword for the Parity Ratio Given on the Command Line
+
+        @param ratio: &str
+            * Parity bytes per data byte, e.g. 0.1 adds 10%, greater than 0 and at most 1
+        @return Option<u8>
+            * The parity bytes per codeword, at least 2 so a byte can be corrected, None for invalid ratios
+    */
+    let ratio: f64 = ratio.parse().ok()?;
+    if !(ratio > 0.0 && ratio <= 1.0) {
+        return None;
+    }
+
+    /* parity / (CODEWORD_SIZE - parity) = ratio */
+    let parity = (CODEWORD_SIZE as f64 * ratio / (1.0 + ratio)).ceil() as u8;
+    Some(parity.max(2))
+}
+
+pub(crate) fn encode(data: &[u8], parity: u8) -> Vec<u8> {
+    /*
+        * Add Parity to the Given Ciphertext
+
+        @param data: &[u8]
+            * The ciphertext
+        @param parity: u8
+            * Parity bytes per codeword, see parity_for_ratio()
+        @return Vec<u8>
+            * The codewords
+    */
+    let encoder = Encoder::new(parity as usize);
+    let chunk_size = CODEWORD_SIZE - parity as usize;
+
+    let mut output = Vec::with_capacity(data.len() + data.len().div_ceil(chunk_size) * parity as usize);
+    for chunk in data.chunks(chunk_size) {
+        output.extend_from_slice(&encoder.encode(chunk));
+    }
+    output
+}
+
+pub(crate) fn encoded_size(size: u64, parity: u8) -> u64 {
+    /*
+        * Get the Size of the Codewords encode() Makes of the Given Number of Bytes
+
+        @param size: u64
+            * The ciphertext length
+        @param parity: u8
+            * Parity bytes per codeword, 0 for none
+        @return u64: The ciphertext length with the parity of every codeword
+    */
+    let chunk_size = (CODEWORD_SIZE - parity as usize) as u64;
+    size + size.div_ceil(chunk_size) * parity as u64
+}
+
+pub(crate) fn decode(data: &[u8], parity: u8) -> FResult<(Vec<u8>, usize)> {
+    /*
+        * Check and Correct the Given Codewords and Strip their Parity
+
+        @param data: &[u8]
+            * The codewords written by encode()
+        @param parity: u8
+            * Parity bytes per codeword, from the header
+        @return FResult: Result<(Vec<u8>, usize), Box<dyn Error>>
+            * The ciphertext and the number of bytes corrected
+            * An error if a codeword has more corrupted bytes than its parity can correct
+    */
+    let decoder = Decoder::new(parity as usize);
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut corrected = 0;
+    for (index, codeword) in data.chunks(CODEWORD_SIZE).enumerate() {
+        /* Every codeword carries at least one data byte after its parity */
+        if codeword.len() <= parity as usize {
+            return Err(format!("Truncated error correction codeword {}", index).into());
+        }
+
+        let (buffer, fixed) = decoder.correct_err_count(codeword, None)
+            .map_err(|_| format!("Codeword {} has too many corrupted bytes to correct", index))?;
+        output.extend_from_slice(buffer.data());
+        corrected += fixed;
+    }
+
+    Ok((output, corrected))
+}
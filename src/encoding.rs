@@ -0,0 +1,148 @@
+use std::borrow::Cow;
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+
+use crate::FResult;
+use crate::header::MAGIC;
+
+/* Envelope around armored output */
+const ARMOR_BEGIN: &str = "-----BEGIN FISHER-----";
+const ARMOR_END: &str = "-----END FISHER-----";
+
+/* Length of the base64 lines inside the armor envelope */
+const ARMOR_WIDTH: usize = 64;
+
+/*
+    * How Encrypted Output is Written
+
+    * The format is never stored separately, decode() recognizes it from the output itself:
+        * Armor starts with the ARMOR_BEGIN line
+        * Base64Url decodes to bytes starting with the header magic
+        * Anything else is raw
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OutputFormat {
+    /* The header and ciphertext bytes as they are */
+    Raw,
+    /* Standard base64 wrapped at ARMOR_WIDTH inside a BEGIN/END envelope, for text-only channels */
+    Armor,
+    /* Unpadded, URL and file name safe base64 on a single line, no envelope */
+    Base64Url,
+}
+
+impl OutputFormat {
+    pub(crate) fn parse(name: &str) -> Option<OutputFormat> {
+        /*
+            * Get the Output Format Named on the Command Line
+
+            @param name: &str
+                * raw, armor or base64url
+            @return Option<OutputFormat>
+                * The format, None for unknown names
+        */
+        match name.to_lowercase().as_str() {
+            "raw" => Some(OutputFormat::Raw),
+            "armor" => Some(OutputFormat::Armor),
+            "base64url" => Some(OutputFormat::Base64Url),
+            _ => None
+        }
+    }
+
+    pub(crate) fn encode(&self, data: Vec<u8>) -> Vec<u8> {
+        /*
+            * Encode Encrypted Output in this Format
+
+            @param self: OutputFormat Instance
+            @param data: Vec<u8>
+                * The header and ciphertext
+            @return Vec<u8>
+                * The bytes to write
+        */
+        match self {
+            OutputFormat::Raw => data,
+            OutputFormat::Armor => {
+                let encoded = STANDARD.encode(&data);
+                let mut armored = String::with_capacity(encoded.len() + encoded.len() / ARMOR_WIDTH + 64);
+                armored.push_str(ARMOR_BEGIN);
+                armored.push('\n');
+                for line in encoded.as_bytes().chunks(ARMOR_WIDTH) {
+                    /* Base64 is ASCII, so every chunk is valid UTF-8 */
+                    armored.push_str(std::str::from_utf8(line).unwrap());
+                    armored.push('\n');
+                }
+                armored.push_str(ARMOR_END);
+                armored.push('\n');
+                armored.into_bytes()
+            }
+            OutputFormat::Base64Url => URL_SAFE_NO_PAD.encode(&data).into_bytes()
+        }
+    }
+
+    pub(crate) fn encoded_size(&self, size: u64) -> u64 {
+        /*
+            * Get the Size of the Output encode() Writes for the Given Number of Bytes
+
+            @param self: OutputFormat Instance
+            @param size: u64
+                * The header and ciphertext length
+            @return u64
+        */
+        match self {
+            OutputFormat::Raw => size,
+            OutputFormat::Armor => {
+                let encoded = size.div_ceil(3) * 4;
+                ARMOR_BEGIN.len() as u64 + 1 + encoded + encoded.div_ceil(ARMOR_WIDTH as u64) + ARMOR_END.len() as u64 + 1
+            }
+            OutputFormat::Base64Url => (size * 4).div_ceil(3)
+        }
+    }
+}
+
+pub(crate) fn is_encrypted(start: &[u8]) -> bool {
+    /*
+        * Check Whether a File is Fisher-Encrypted, in any OutputFormat
+
+        @param start: &[u8]
+            * The first bytes of the file, at least ARMOR_BEGIN long to recognize every format
+        @return bool: True if it starts with the header magic, an armor envelope or the magic in base64url
+    */
+    /* 8 base64url characters decode to 6 bytes, enough for the magic */
+    let base64url = start.get(..8).and_then(|prefix| URL_SAFE_NO_PAD.decode(prefix).ok());
+    start.starts_with(&MAGIC)
+        || start.starts_with(ARMOR_BEGIN.as_bytes())
+        || base64url.is_some_and(|decoded| decoded.starts_with(&MAGIC))
+}
+
+pub(crate) fn decode(data: &[u8]) -> FResult<Cow<'_, [u8]>> {
+    /*
+        * Undo the Output Format of an Encrypted File
+
+        @param data: &[u8]
+            * The contents of an encrypted file in any OutputFormat
+        @return FResult: Result<Cow<[u8]>, Box<dyn Error>>
+            * The header and ciphertext, borrowed when the file is raw
+    */
+    /* Armor, everything between the envelope lines is base64 */
+    if data.starts_with(ARMOR_BEGIN.as_bytes()) {
+        let text = std::str::from_utf8(data).map_err(|_| "Armored file is not valid text")?;
+        let body = text[ARMOR_BEGIN.len()..].split(ARMOR_END).next().unwrap_or_default();
+        if !text.contains(ARMOR_END) {
+            return Err("Armored file is missing its END line".into());
+        }
+        let encoded: String = body.chars().filter(|char| !char.is_ascii_whitespace()).collect();
+        return Ok(Cow::Owned(STANDARD.decode(encoded).map_err(|err| format!("Invalid armor: {}", err))?));
+    }
+
+    /* Base64url, only if it decodes to a header, raw ciphertext may happen to be base64 characters */
+    if !data.starts_with(&MAGIC) {
+        let trimmed = data.trim_ascii_end();
+        if let Ok(decoded) = URL_SAFE_NO_PAD.decode(trimmed) {
+            if decoded.starts_with(&MAGIC) {
+                return Ok(Cow::Owned(decoded));
+            }
+        }
+    }
+
+    Ok(Cow::Borrowed(data))
+}
@@ -1,18 +1,79 @@
+use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use blowfish::Blowfish;
 use blowfish::cipher::Key;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256, Sha512};
 use sha2::digest::core_api::Block;
 use threefish::{cipher::KeyInit, Threefish1024, Threefish256, Threefish512};
 use threefish::cipher::{BlockDecrypt, BlockEncrypt};
 use twofish::Twofish;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::FResult;
 
-pub(crate) enum Fishers {
+/* Size in bytes of the Threefish tweak, see new_cipher() */
+pub(crate) const TWEAK_SIZE: usize = 16;
+
+/* The cipher families supported by fisher, Threefish additionally takes a block size */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Blowfish,
+    Twofish,
+    Threefish,
+}
+
+/* Description of one algorithm (and block size) supported by Fishers */
+pub(crate) struct AlgorithmInfo {
+    /* Identifier stored in file headers, never reuse or renumber */
+    pub(crate) id: u8,
+    /* Human readable name */
+    pub(crate) name: &'static str,
+    /* Command line names selecting the algorithm */
+    pub(crate) flags: &'static str,
+    /* Algorithm passed to generate_key() */
+    pub(crate) algorithm: Algorithm,
+    /* Key size in bytes */
+    pub(crate) key_size: usize,
+    /* Block size in bytes */
+    pub(crate) block_size: usize,
+    /* Kind of cipher */
+    pub(crate) kind: &'static str,
+    /* Conventional file extension of files encrypted with it */
+    pub(crate) extension: &'static str,
+}
+
+/*
+    * Every Fishers Variant, in Declaration Order
+
+    * Single source of truth for the supported algorithms, keep in sync with Fishers and generate_key()
+*/
+pub(crate) const ALGORITHMS: [AlgorithmInfo; 5] = [
+    AlgorithmInfo { id: 1, name: "Blowfish", flags: "-a blowfish | -a bf", algorithm: Algorithm::Blowfish, key_size: 56, block_size: 8, kind: "block cipher", extension: "bf" },
+    AlgorithmInfo { id: 2, name: "Twofish", flags: "-a twofish | -a tw", algorithm: Algorithm::Twofish, key_size: 32, block_size: 16, kind: "block cipher", extension: "tw" },
+    AlgorithmInfo { id: 3, name: "Threefish-256", flags: "-a threefish | -a tf -b 256", algorithm: Algorithm::Threefish, key_size: 32, block_size: 32, kind: "tweakable block cipher", extension: "tf256" },
+    AlgorithmInfo { id: 4, name: "Threefish-512", flags: "-a threefish | -a tf -b 512", algorithm: Algorithm::Threefish, key_size: 64, block_size: 64, kind: "tweakable block cipher", extension: "tf512" },
+    AlgorithmInfo { id: 5, name: "Threefish-1024", flags: "-a threefish | -a tf -b 1024", algorithm: Algorithm::Threefish, key_size: 128, block_size: 128, kind: "tweakable block cipher", extension: "tf1024" },
+];
+
+/*
+    * Byte Order
+
+    * Keys and blocks only ever cross into the ciphers as byte slices. The RustCrypto implementations
+      load Threefish words with u64::from_le_bytes (and Blowfish/Twofish with their own fixed byte
+      order), so the same bytes produce the same ciphertext on little- and big-endian targets.
+    * Nothing in this module reinterprets bytes as integers, keep it that way when touching
+      encrypt_block(), decrypt_block() or generate_key()
+*/
+/* A keyed cipher, public so library users can take out the RustCrypto cipher, see the TryFrom impls */
+#[allow(clippy::large_enum_variant)]
+pub enum Fishers {
     Blowfish(Blowfish),
     Twofish(Twofish),
     Threefish256(Threefish256),
@@ -21,7 +82,7 @@ pub(crate) enum Fishers {
 }
 
 impl Fishers {
-    pub(crate) fn encrypt_block(&'static self, block: &mut Vec<u8>) -> FResult<bool> {
+    pub(crate) fn encrypt_block(&self, block: &mut Vec<u8>) -> FResult<bool> {
         /*
             * Encrypt the Given Block
 
@@ -32,31 +93,31 @@ impl Fishers {
 
         match self {
             Fishers::Blowfish(blowfish) => {
-                let mut bf_block = Block::<Blowfish>::clone_from_slice(&block);
+                let mut bf_block = Block::<Blowfish>::clone_from_slice(block);
                 blowfish.encrypt_block(&mut bf_block);
 
                 *block = bf_block.to_vec();
             }
             Fishers::Twofish(twofish) => {
-                let mut tf_block = Block::<Twofish>::clone_from_slice(&block);
+                let mut tf_block = Block::<Twofish>::clone_from_slice(block);
                 twofish.encrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
             }
             Fishers::Threefish256(threefish) => {
-                let mut tf_block = Block::<Threefish256>::clone_from_slice(&block);
+                let mut tf_block = Block::<Threefish256>::clone_from_slice(block);
                 threefish.encrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
             }
             Fishers::Threefish512(threefish) => {
-                let mut tf_block = Block::<Threefish512>::clone_from_slice(&block);
+                let mut tf_block = Block::<Threefish512>::clone_from_slice(block);
                 threefish.encrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
             }
             Fishers::Threefish1024(threefish) => {
-                let mut tf_block = Block::<Threefish1024>::clone_from_slice(&block);
+                let mut tf_block = Block::<Threefish1024>::clone_from_slice(block);
                 threefish.encrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
@@ -66,7 +127,7 @@ impl Fishers {
         Ok(true)
     }
 
-    pub(crate) fn decrypt_block(&'static self, block: &mut Vec<u8>) -> FResult<bool> {
+    pub(crate) fn decrypt_block(&self, block: &mut Vec<u8>) -> FResult<bool> {
         /*
             * Decrypt the Given Block
 
@@ -76,31 +137,31 @@ impl Fishers {
         */
         match self {
             Fishers::Blowfish(blowfish) => {
-                let mut bf_block = Block::<Blowfish>::clone_from_slice(&block);
+                let mut bf_block = Block::<Blowfish>::clone_from_slice(block);
                 blowfish.decrypt_block(&mut bf_block);
 
                 *block = bf_block.to_vec();
             }
             Fishers::Twofish(twofish) => {
-                let mut tf_block = Block::<Twofish>::clone_from_slice(&block);
+                let mut tf_block = Block::<Twofish>::clone_from_slice(block);
                 twofish.decrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
             }
             Fishers::Threefish256(threefish) => {
-                let mut tf_block = Block::<Threefish256>::clone_from_slice(&block);
+                let mut tf_block = Block::<Threefish256>::clone_from_slice(block);
                 threefish.decrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
             }
             Fishers::Threefish512(threefish) => {
-                let mut tf_block = Block::<Threefish512>::clone_from_slice(&block);
+                let mut tf_block = Block::<Threefish512>::clone_from_slice(block);
                 threefish.decrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
             }
             Fishers::Threefish1024(threefish) => {
-                let mut tf_block = Block::<Threefish1024>::clone_from_slice(&block);
+                let mut tf_block = Block::<Threefish1024>::clone_from_slice(block);
                 threefish.decrypt_block(&mut tf_block);
 
                 *block = tf_block.to_vec();
@@ -111,85 +172,458 @@ impl Fishers {
     }
 }
 
-pub(crate) fn generate_key(alg: u8, block_size: usize, passphrase: String) -> FResult<Fishers> {
+/*
+    * Typed Access to the Underlying Ciphers
+
+    * Each variant wraps a RustCrypto cipher that implements the standard BlockCipher,
+      BlockEncrypt and BlockDecrypt traits, so e.g. `<&Twofish>::try_from(&fishers)` can be
+      plugged into any block mode built on those traits
+*/
+impl<'a> TryFrom<&'a Fishers> for &'a Blowfish {
+    type Error = Box<dyn Error>;
+
+    fn try_from(fishers: &'a Fishers) -> FResult<Self> {
+        match fishers {
+            Fishers::Blowfish(blowfish) => Ok(blowfish),
+            _ => Err("Cipher is not Blowfish".into())
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Fishers> for &'a Twofish {
+    type Error = Box<dyn Error>;
+
+    fn try_from(fishers: &'a Fishers) -> FResult<Self> {
+        match fishers {
+            Fishers::Twofish(twofish) => Ok(twofish),
+            _ => Err("Cipher is not Twofish".into())
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Fishers> for &'a Threefish256 {
+    type Error = Box<dyn Error>;
+
+    fn try_from(fishers: &'a Fishers) -> FResult<Self> {
+        match fishers {
+            Fishers::Threefish256(threefish) => Ok(threefish),
+            _ => Err("Cipher is not Threefish256".into())
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Fishers> for &'a Threefish512 {
+    type Error = Box<dyn Error>;
+
+    fn try_from(fishers: &'a Fishers) -> FResult<Self> {
+        match fishers {
+            Fishers::Threefish512(threefish) => Ok(threefish),
+            _ => Err("Cipher is not Threefish512".into())
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Fishers> for &'a Threefish1024 {
+    type Error = Box<dyn Error>;
+
+    fn try_from(fishers: &'a Fishers) -> FResult<Self> {
+        match fishers {
+            Fishers::Threefish1024(threefish) => Ok(threefish),
+            _ => Err("Cipher is not Threefish1024".into())
+        }
+    }
+}
+
+pub(crate) fn generate_key(alg: Algorithm, block_size: usize, kdf: Kdf, passphrase: &[u8]) -> FResult<Fishers> {
     /*
         * Generate a Key from the Given Passphrase
 
-        @param self: Fisher Instance
-        @param passphrase: String
+        @param alg: Algorithm
+            * The algorithm to generate the key for
+        @param block_size: usize
+            * The Threefish block size in bytes, ignored for other algorithms
+        @param kdf: Kdf
+            * An unsalted KDF, see Kdf::unsalted()
+        @param passphrase: &[u8]
             * The passphrase to generate the key from
         @return FResult: Result<Key, Box<dyn Error>>
             * The generated key or some Error
     */
 
-    /* Check if passphrase is actually a file, if so read the file and use that as the passphrase */
-    let passphrase = match PathBuf::from(&passphrase).is_file() {
-        true => {
-            let mut file = File::open(passphrase)?;
-            let mut passphrase = String::new();
-            file.read_to_string(&mut passphrase)?;
-            passphrase
-        }
-        false => passphrase
-    };
+    let key = kdf.derive(alg, block_size, passphrase, &[])?;
 
-    match alg {
-        0 => {
-            let mut hasher = Sha512::default();
-            hasher.update(passphrase.as_bytes());
-            let hash = hasher.finalize();
+    new_cipher(alg, block_size, &key, &[0; TWEAK_SIZE])
+}
 
-            /* Truncate the hash to 448 bits */
-            let hash = &hash[..56];
+pub(crate) fn new_cipher(alg: Algorithm, block_size: usize, key: &[u8], tweak: &[u8; TWEAK_SIZE]) -> FResult<Fishers> {
+    /*
+        * Create the Cipher for the Given Raw Key
 
-            Ok(Fishers::Blowfish(Blowfish::new(Key::<Blowfish>::from_slice(hash))))
+        @param alg: Algorithm
+            * The algorithm of the cipher
+        @param block_size: usize
+            * The Threefish block size in bytes, ignored for other algorithms
+        @param key: &[u8]
+            * The key, as long as AlgorithmInfo::key_size
+        @param tweak: &[u8; TWEAK_SIZE]
+            * The Threefish tweak, random per headered file and zero otherwise, ignored for other algorithms
+        @return FResult: Result<Fishers, Box<dyn Error>>
+            * The keyed cipher or some Error
+    */
+    match alg {
+        Algorithm::Blowfish => Ok(Fishers::Blowfish(Blowfish::new(Key::<Blowfish>::from_slice(key)))),
+        Algorithm::Twofish => Ok(Fishers::Twofish(Twofish::new(Key::<Twofish>::from_slice(key)))),
+        Algorithm::Threefish => {
+            match block_size {
+                32 => Ok(Fishers::Threefish256(Threefish256::new_with_tweak(key.try_into()?, tweak))),
+                64 => Ok(Fishers::Threefish512(Threefish512::new_with_tweak(key.try_into()?, tweak))),
+                128 => Ok(Fishers::Threefish1024(Threefish1024::new_with_tweak(key.try_into()?, tweak))),
+                _ => {
+                    Err("Invalid block size".into())
+                }
+            }
         }
-        1 => {
-            let mut hasher = Sha256::default();
-            hasher.update(passphrase.as_bytes());
-            let hash = hasher.finalize();
+    }
+}
+
+pub(crate) fn read_keyfile(path: &Path) -> FResult<Zeroizing<Vec<u8>>> {
+    /*
+        * Read the Passphrase from the File Given with --keyfile
+
+        * Read as raw bytes, so binary key files are hashed (or with Kdf::Raw used) as they are
 
-            Ok(Fishers::Twofish(Twofish::new(Key::<Twofish>::from_slice(hash.as_slice()))))
+        @param path: &Path
+            * The key file
+        @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+            * The contents of the file, wiped from memory when dropped, or an error if it can't be read or
+              is empty
+    */
+    let mut contents = Zeroizing::new(Vec::new());
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|err| format!("Could not read key file {:?}: {}", path, err))?;
+
+    match contents.is_empty() {
+        true => Err(format!("Key file {:?} is empty", path).into()),
+        false => Ok(contents)
+    }
+}
+
+fn digest<D: Digest>(input: &[u8]) -> Zeroizing<Vec<u8>> {
+    /*
+        * Hash the Given Bytes into a Buffer Wiped from Memory when Dropped
+
+        * The hash output is copied out and then zeroed, so no copy of the key material is left behind
+
+        @param input: &[u8]
+            * The bytes to hash
+        @return Zeroizing<Vec<u8>>
+            * The hash
+    */
+    let mut hash = D::digest(input);
+    let bytes = Zeroizing::new(hash.to_vec());
+    hash.as_mut_slice().zeroize();
+    bytes
+}
+
+pub(crate) fn sha2_key(alg: Algorithm, block_size: usize, passphrase: &[u8]) -> FResult<Zeroizing<Vec<u8>>> {
+    /*
+        * Derive the Unsalted Cipher Key of Headerless Files (Kdf::Sha2) from a Passphrase
+
+        * Only headerless files and files encrypted before keys were salted use it, headered files are
+          keyed with a salted KDF, see Kdf::derive() and subkey()
+        * Separate from generate_key() so other tools can reproduce these keys exactly:
+            * Blowfish:       SHA-512(passphrase)[..56]                          (448 bit key)
+            * Twofish:        SHA-256(passphrase)                                (256 bit key)
+            * Threefish-256:  SHA-256(passphrase)                                (256 bit key)
+            * Threefish-512:  SHA-512(passphrase)                                (512 bit key)
+            * Threefish-1024: SHA-512(passphrase) || SHA-512(SHA-512(passphrase)) (1024 bit key)
+          where passphrase is hashed as its UTF-8 bytes, or the bytes of the key file
+
+        @param alg: Algorithm
+            * The algorithm to derive the key for
+        @param block_size: usize
+            * The Threefish block size in bytes (32, 64 or 128), ignored for other algorithms
+        @param passphrase: &[u8]
+            * The passphrase to derive the key from
+        @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+            * The key bytes, wiped from memory when dropped
+    */
+
+    match alg {
+        Algorithm::Blowfish => {
+            let mut hash = digest::<Sha512>(passphrase);
+
+            /* Truncate the hash to 448 bits */
+            hash.truncate(56);
+            Ok(hash)
         }
-        2 => {
+        Algorithm::Twofish => Ok(digest::<Sha256>(passphrase)),
+        Algorithm::Threefish => {
             match block_size {
-                32 => {
-                    /* Create 256 bit hash of the passphrase */
-                    let mut hasher = Sha256::default();
-                    hasher.update(passphrase.as_bytes());
-                    let hash = hasher.finalize();
-                    Ok(Fishers::Threefish256(Threefish256::new(Key::<Threefish256>::from_slice(hash.as_slice()))))
-                }
-                64 => {
-                    /* Create 512 bit hash of the passphrase */
-                    let mut hasher = Sha512::default();
-                    hasher.update(passphrase.as_bytes());
-                    let hash = hasher.finalize();
-                    Ok(Fishers::Threefish512(Threefish512::new(Key::<Threefish512>::from_slice(hash.as_slice()))))
-                }
+                /* Create 256 bit hash of the passphrase */
+                32 => Ok(digest::<Sha256>(passphrase)),
+                /* Create 512 bit hash of the passphrase */
+                64 => Ok(digest::<Sha512>(passphrase)),
                 128 => {
                     /* Create 1024 bit hash of the passphrase */
                     /* Combines 512 hash of original passphrase with 512 hash of the 512 hash */
-                    let mut hasher = Sha512::default();
-                    hasher.update(passphrase.as_bytes());
-                    let hash = hasher.finalize();
-                    let mut cct_hasher = Sha512::default();
-                    cct_hasher.update(hash.as_slice());
-                    let cct_hash = cct_hasher.finalize();
-                    /* Combine the two hashes */
-                    let mut combined_hash: [u8; 128] = [0; 128];
-                    combined_hash[..64].clone_from_slice(hash.as_slice());
-                    combined_hash[64..].clone_from_slice(cct_hash.as_slice());
-                    Ok(Fishers::Threefish1024(Threefish1024::new(Key::<Threefish1024>
-                    ::from_slice(combined_hash.as_slice()))))
+                    let hash = digest::<Sha512>(passphrase);
+                    let cct_hash = digest::<Sha512>(&hash);
+                    /* Combine the two hashes (a plain byte concatenation, independent of byte order) */
+                    let mut combined_hash = Zeroizing::new(vec![0u8; 128]);
+                    combined_hash[..64].clone_from_slice(&hash);
+                    combined_hash[64..].clone_from_slice(&cct_hash);
+                    Ok(combined_hash)
                 }
                 _ => {
                     Err("Invalid block size".into())
                 }
             }
         }
-        _ => {
-            Err("Invalid algorithm".into())
+    }
+}
+
+/* How the key of a file is derived from the passphrase, recorded in its header */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Kdf {
+    /* sha2_key(), unsalted, for headerless files and files encrypted before keys were salted */
+    Sha2,
+    /* PBKDF2-HMAC-SHA256 with the given iteration count */
+    Pbkdf2 { iterations: u32 },
+    /* Argon2id with the given memory in KiB, passes and lanes, memory-hard against GPU cracking */
+    Argon2id { memory: u32, time: u32, parallelism: u32 },
+    /* None, the key file (--raw-key) is the key itself and must be AlgorithmInfo::key_size bytes */
+    Raw,
+}
+
+impl Kdf {
+    /* Argon2id parameters used unless given, the argon2 crate's (and OWASP's) recommendation */
+    pub(crate) const ARGON2ID_DEFAULT: Kdf = Kdf::Argon2id {
+        memory: argon2::Params::DEFAULT_M_COST,
+        time: argon2::Params::DEFAULT_T_COST,
+        parallelism: argon2::Params::DEFAULT_P_COST,
+    };
+
+    pub(crate) fn unsalted(&self) -> Kdf {
+        /*
+            * Get the KDF Used for Headerless Files, which Have No Salt
+
+            @param self: Kdf Instance
+            @return Kdf: Raw for Raw, Sha2 otherwise
+        */
+        match self {
+            Kdf::Raw => Kdf::Raw,
+            _ => Kdf::Sha2
+        }
+    }
+
+    pub(crate) fn stretching(&self) -> bool {
+        /*
+            * Check Whether the KDF is Deliberately Slow, see stretch()
+
+            @param self: Kdf Instance
+            @return bool: True for Pbkdf2 and Argon2id
+        */
+        matches!(self, Kdf::Pbkdf2 { .. } | Kdf::Argon2id { .. })
+    }
+
+    pub(crate) fn derive(&self, alg: Algorithm, block_size: usize, passphrase: &[u8], salt: &[u8]) -> FResult<Zeroizing<Vec<u8>>> {
+        /*
+            * Derive the Raw Cipher Key for the Given Algorithm
+
+            * Salted and iterated (except Sha2), so every file gets its own key and guessing passwords
+              costs a full derivation per guess and file
+            * The key is AlgorithmInfo::key_size bytes of KDF output over the passphrase as UTF-8 bytes (or
+              the bytes of the key file)
+
+            @param self: Kdf Instance
+            @param alg: Algorithm
+                * The algorithm to derive the key for
+            @param block_size: usize
+                * The block size in bytes, selects the Threefish variant
+            @param passphrase: &[u8]
+                * The passphrase to derive the key from
+            @param salt: &[u8]
+                * The random salt of the file, ignored by Sha2 and Raw
+            @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+                * The key bytes, wiped from memory when dropped
+        */
+        let info = algorithm_info(alg, block_size)?;
+
+        match *self {
+            Kdf::Sha2 => sha2_key(alg, block_size, passphrase),
+            Kdf::Raw => match passphrase.len() == info.key_size {
+                true => Ok(Zeroizing::new(passphrase.to_vec())),
+                false => Err(format!("Raw key is {} bytes, {} needs a {} byte ({} bit) key",
+                                     passphrase.len(), info.name, info.key_size, info.key_size * 8).into())
+            },
+            _ => self.stretch(passphrase, salt, info.key_size)
+        }
+    }
+
+    pub(crate) fn calibrate(&self, target: Duration) -> FResult<Kdf> {
+        /*
+            * Scale the Cost of the KDF so Stretching Takes About the Given Time on this Machine
+
+            * A cheap derivation is timed and its cost scaled linearly to the target: PBKDF2 iterations,
+              or Argon2id passes with its memory and lanes kept, as the memory is what slows down cracking
+
+            @param self: Kdf Instance
+            @param target: Duration
+                * How long stretching should take
+            @return FResult: Result<Kdf, Box<dyn Error>>
+                * The KDF with the calibrated parameters, an error for KDFs that don't stretch
+        */
+        let time = |kdf: Kdf| -> FResult<Duration> {
+            let started = Instant::now();
+            kdf.stretch(b"calibration", &[0; 16], MASTER_KEY_SIZE)?;
+            Ok(started.elapsed())
+        };
+        let scale = |cost: u32, elapsed: Duration| {
+            (cost as f64 * target.as_secs_f64() / elapsed.as_secs_f64().max(1e-9)).round().clamp(1.0, u32::MAX as f64) as u32
+        };
+
+        match *self {
+            Kdf::Pbkdf2 { .. } => {
+                /* Double the iterations until a derivation is long enough to time reliably */
+                let mut iterations = 1024;
+                let mut elapsed = time(Kdf::Pbkdf2 { iterations })?;
+                while elapsed < target / 10 && iterations < u32::MAX / 2 {
+                    iterations *= 2;
+                    elapsed = time(Kdf::Pbkdf2 { iterations })?;
+                }
+                Ok(Kdf::Pbkdf2 { iterations: scale(iterations, elapsed) })
+            }
+            Kdf::Argon2id { memory, parallelism, .. } => {
+                let elapsed = time(Kdf::Argon2id { memory, time: 1, parallelism })?;
+                Ok(Kdf::Argon2id { memory, time: scale(1, elapsed), parallelism })
+            }
+            Kdf::Sha2 | Kdf::Raw => Err(format!("{:?} has no cost to calibrate", self).into())
+        }
+    }
+
+    pub(crate) fn stretch(&self, passphrase: &[u8], salt: &[u8], len: usize) -> FResult<Zeroizing<Vec<u8>>> {
+        /*
+            * Run the Slow KDF Over the Given Passphrase and Salt
+
+            * Used for the key of a single file (see derive()), or once per run for the master key
+              the keys of its files are derived from (see subkey())
+
+            @param self: Kdf Instance
+            @param passphrase: &[u8]
+                * The passphrase to stretch
+            @param salt: &[u8]
+                * The random salt of the file or run
+            @param len: usize
+                * The number of bytes to derive
+            @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+                * The derived bytes, an error for KDFs that don't stretch
+        */
+        let mut key = Zeroizing::new(vec![0u8; len]);
+        match *self {
+            Kdf::Pbkdf2 { iterations } => {
+                pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, &mut key);
+            }
+            Kdf::Argon2id { memory, time, parallelism } => {
+                let params = argon2::Params::new(memory, time, parallelism, Some(len))
+                    .map_err(|err| format!("Invalid Argon2id parameters: {}", err))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, &mut key)
+                    .map_err(|err| format!("Argon2id failed: {}", err))?;
+            }
+            Kdf::Sha2 | Kdf::Raw => return Err(format!("{:?} does not stretch passphrases", self).into())
         }
+        Ok(key)
     }
-}
\ No newline at end of file
+}
+
+/* Size in bytes of the master key stretched once per run, see subkey() */
+pub(crate) const MASTER_KEY_SIZE: usize = 32;
+
+pub(crate) fn subkey(alg: Algorithm, block_size: usize, master: &[u8], salt: &[u8]) -> FResult<Zeroizing<Vec<u8>>> {
+    /*
+        * Derive the Key of a File From the Master Key of its Run
+
+        * HKDF-SHA256 with the salt of the file, so every file still gets its own key while the slow
+          KDF only runs once per run (see Kdf::stretch())
+
+        @param alg: Algorithm
+            * The algorithm to derive the key for
+        @param block_size: usize
+            * The block size in bytes, selects the Threefish variant
+        @param master: &[u8]
+            * The master key, stretched from the passphrase and the salt of the run
+        @param salt: &[u8]
+            * The random salt of the file
+        @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+            * AlgorithmInfo::key_size bytes, wiped from memory when dropped
+    */
+    let info = algorithm_info(alg, block_size)?;
+
+    let mut key = Zeroizing::new(vec![0u8; info.key_size]);
+    hkdf::Hkdf::<Sha256>::new(Some(salt), master)
+        .expand(b"fisher file key", &mut key)
+        .map_err(|_| "Key too long for HKDF")?;
+    Ok(key)
+}
+
+pub(crate) fn algorithm_info(alg: Algorithm, block_size: usize) -> FResult<&'static AlgorithmInfo> {
+    /*
+        * Get the Description of the Given Algorithm and Block Size
+
+        @param alg: Algorithm
+            * The algorithm
+        @param block_size: usize
+            * The block size in bytes
+        @return FResult: Result<&AlgorithmInfo, Box<dyn Error>>
+            * An error for block sizes the algorithm doesn't have
+    */
+    ALGORITHMS.iter()
+        .find(|info| info.algorithm == alg && info.block_size == block_size)
+        .ok_or_else(|| "Invalid block size".into())
+}
+
+pub(crate) fn extension_info(extension: &str) -> Option<&'static AlgorithmInfo> {
+    /*
+        * Get the Algorithm and Block Size a File Extension Stands For, see AlgorithmInfo::extension
+
+        @param extension: &str
+            * The extension without the dot, in any case, e.g. tw or TF512
+        @return Option<&AlgorithmInfo>: None for extensions of no algorithm
+    */
+    ALGORITHMS.iter().find(|info| info.extension.eq_ignore_ascii_case(extension))
+}
+
+pub(crate) fn mac_key(key: &[u8]) -> Zeroizing<[u8; 32]> {
+    /*
+        * Derive the HMAC Key of a File from its Cipher Key
+
+        * HMAC-SHA256(key, "fisher mac"), so the cipher key itself is never used for the MAC
+
+        @param key: &[u8]
+            * The raw cipher key of the file, see Kdf::derive()
+        @return Zeroizing<[u8; 32]>
+            * The HMAC-SHA256 key, wiped from memory when dropped
+    */
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(b"fisher mac");
+    Zeroizing::new(mac.finalize().into_bytes().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibrate_keeps_the_kdf_and_scales_its_cost() {
+        assert!(matches!(Kdf::Pbkdf2 { iterations: 1 }.calibrate(Duration::from_millis(20)),
+                         Ok(Kdf::Pbkdf2 { iterations }) if iterations > 1));
+
+        let argon2id = Kdf::Argon2id { memory: 64, time: 1, parallelism: 1 }.calibrate(Duration::from_millis(20)).unwrap();
+        assert!(matches!(argon2id, Kdf::Argon2id { memory: 64, time, parallelism: 1 } if time >= 1));
+
+        assert!(Kdf::Raw.calibrate(Duration::from_millis(20)).is_err());
+        assert!(Kdf::Sha2.calibrate(Duration::from_millis(20)).is_err());
+    }
+}
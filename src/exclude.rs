@@ -0,0 +1,200 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+
+use crate::FResult;
+
+/* Name of the per-directory file of gitignore-style patterns */
+pub(crate) const IGNORE_FILE: &str = ".fisherignore";
+
+/* Files and directories always skipped in directories, as if given with --exclude */
+const DEFAULT_EXCLUDES: &[&str] = &[
+    /* Finder metadata on macOS */
+    ".DS_Store",
+    /* Kept readable, so the same files are skipped again when decrypting */
+    IGNORE_FILE,
+];
+
+/*
+    * Patterns of Files and Directories Skipped While Walking Directories
+
+    * The --exclude patterns match either the name of an entry (e.g. .git, node_modules, *.lock) or
+      its whole path, so patterns with a directory in them work too
+    * Each directory walked may hold a .fisherignore, its patterns apply to everything below that
+      directory on top of the patterns of the directories above, see enter()
+    * Only entries found in directories are skipped, paths given on the command line never are
+*/
+#[derive(Clone)]
+pub(crate) struct Exclude {
+    globs: Arc<(GlobSet, Vec<String>)>,
+    /* The ignore files of the directories walked so far, outermost first */
+    ignores: Vec<Arc<IgnoreFile>>,
+}
+
+/* The rules of a .fisherignore, in the order they are written */
+struct IgnoreFile {
+    path: PathBuf,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    /* The line the rule was read from, for logs */
+    line: String,
+    matcher: GlobMatcher,
+    /* Written with a leading '!', matching entries are kept */
+    negated: bool,
+    /* Written with a trailing '/', only matches directories */
+    dir_only: bool,
+    /* Written with a '/' before the end, matches paths relative to the directory of the ignore file */
+    anchored: bool,
+}
+
+impl Exclude {
+    pub(crate) fn new(patterns: &[String]) -> FResult<Exclude> {
+        /*
+            * Compile the Given Patterns Together with DEFAULT_EXCLUDES
+
+            @param patterns: &[String]
+                * Glob patterns given with --exclude
+            @return FResult: Result<Exclude, Box<dyn Error>>
+                * The patterns, or an error naming an invalid one
+        */
+        let patterns: Vec<String> = DEFAULT_EXCLUDES.iter().map(|pattern| pattern.to_string())
+            .chain(patterns.iter().cloned())
+            .collect();
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            builder.add(Glob::new(pattern).map_err(|err| format!("Invalid exclude pattern '{}': {}", pattern, err))?);
+        }
+
+        Ok(Exclude { globs: Arc::new((builder.build()?, patterns)), ignores: Vec::new() })
+    }
+
+    pub(crate) fn enter(&self, dir: &Path) -> FResult<Exclude> {
+        /*
+            * Get the Patterns for the Entries of the Given Directory
+
+            @param self: Exclude Instance
+                * The patterns of the directory above
+            @param dir: &Path
+                * The directory about to be walked
+            @return FResult: Result<Exclude, Box<dyn Error>>
+                * The patterns with those of the directory's .fisherignore added, if it has one
+                * An error if the .fisherignore can't be read or holds an invalid pattern
+        */
+        let path = dir.join(IGNORE_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(self.clone()),
+            Err(err) => return Err(format!("Could not read {:?}: {}", path, err).into())
+        };
+
+        let mut rules = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            if let Some(rule) = Rule::parse(line).map_err(|err| format!("{:?} line {}: {}", path, number + 1, err))? {
+                rules.push(rule);
+            }
+        }
+
+        let mut exclude = self.clone();
+        exclude.ignores.push(Arc::new(IgnoreFile { path, rules }));
+        Ok(exclude)
+    }
+
+    pub(crate) fn matching(&self, path: &Path) -> Option<String> {
+        /*
+            * Get Why the Given File or Directory is Excluded
+
+            @param self: Exclude Instance
+                * The patterns of the directory holding it, see enter()
+            @param path: &Path
+                * The file or directory found in a directory
+            @return Option<String>
+                * The pattern excluding it, None if it isn't excluded
+        */
+        let (set, patterns) = &*self.globs;
+        let name_matches = path.file_name().map(|name| set.matches(name)).unwrap_or_default();
+        if let Some(index) = name_matches.into_iter().chain(set.matches(path)).min() {
+            return Some(format!("'{}'", patterns[index]));
+        }
+
+        /* As with .gitignore the deepest file decides, and within a file the last matching rule */
+        let is_dir = path.is_dir();
+        for ignore in self.ignores.iter().rev() {
+            let Some(rule) = ignore.rules.iter().rev().find(|rule| rule.matches(ignore, path, is_dir)) else {
+                continue;
+            };
+            return match rule.negated {
+                true => None,
+                false => Some(format!("'{}' in {:?}", rule.line, ignore.path))
+            };
+        }
+        None
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> FResult<Option<Rule>> {
+        /*
+            * Parse a Line of a .fisherignore
+
+            @param line: &str
+                * The line, in .gitignore syntax
+            @return FResult: Result<Option<Rule>, Box<dyn Error>>
+                * The rule, None for blank lines and # comments, an error for an invalid pattern
+        */
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, pattern) = match trimmed.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, trimmed)
+        };
+        /* A leading backslash escapes a literal '!' or '#' */
+        let pattern = pattern.strip_prefix('\\').filter(|rest| rest.starts_with(['!', '#'])).unwrap_or(pattern);
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern)
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| format!("invalid pattern '{}': {}", trimmed, err))?
+            .compile_matcher();
+
+        Ok(Some(Rule { line: trimmed.to_string(), matcher, negated, dir_only, anchored }))
+    }
+
+    fn matches(&self, ignore: &IgnoreFile, path: &Path, is_dir: bool) -> bool {
+        /*
+            * Check Whether the Rule Matches the Given File or Directory
+
+            @param self: Rule Instance
+            @param ignore: &IgnoreFile
+                * The ignore file holding the rule
+            @param path: &Path
+                * The file or directory, below the directory of the ignore file
+            @param is_dir: bool
+                * Whether it is a directory
+            @return bool
+        */
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        match self.anchored {
+            true => ignore.path.parent()
+                .and_then(|dir| path.strip_prefix(dir).ok())
+                .is_some_and(|relative| self.matcher.is_match(relative)),
+            false => path.file_name().is_some_and(|name| self.matcher.is_match(name))
+        }
+    }
+}
@@ -1,49 +1,586 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::sync::Mutex;
-use std::thread::JoinHandle;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::r#enum::{Fishers, generate_key};
+use crate::blocks;
+use crate::blocks::BlocksExt;
+use crate::compression;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use globset::{Glob, GlobMatcher};
+use indicatif::ProgressBar;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::concurrency::Concurrency;
+use crate::ecc;
+use crate::encoding;
+use crate::encoding::OutputFormat;
+use crate::exclude::Exclude;
+use crate::header;
+use crate::header::{FLAG_COMPRESSED, FLAG_PKCS7, FLAG_RELATIVE, Header, MAC_SIZE, Mode, SALT_SIZE};
+use crate::mac::{FileMac, MacAlgorithm};
+use crate::meta::{Meta, META_SUFFIX, meta_path};
+use crate::pool;
+use crate::pool::Pool;
+use crate::progress::{Progress, ProgressMode};
+use crate::report::{Failure, RunReport};
+use crate::shred;
+use crate::r#enum::{Algorithm, ALGORITHMS, Fishers, Kdf, MASTER_KEY_SIZE, TWEAK_SIZE, algorithm_info, extension_info, generate_key,
+                    mac_key, new_cipher, subkey};
 
 pub(crate) type FResult<T> = Result<T, Box<dyn Error>>;
 
-pub(crate) struct Fisher {
+/* How aggressively written files are flushed to disk */
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum SyncMode {
+    /* Leave flushing to the OS */
+    Off,
+    /* fsync each written file */
+    File,
+    /* fsync each written file and its containing directory */
+    Dir,
+}
+
+/* Whether to keep a copy of each file before it is modified in place */
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum BackupMode {
+    /* No backup is made */
+    Off,
+    /* Copy the original to <name>.bak and leave it there */
+    Keep,
+    /* Copy the original to <name>.bak and remove it once the file was modified successfully */
+    Removable,
+}
+
+/* What to do when the renamed output of a file (e.g. <name>.fish) already exists */
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ExistingOutput {
+    /* Fail the file and leave both untouched */
+    Error,
+    /* Replace the existing output */
+    Overwrite,
+    /* Leave the file and the existing output untouched, counted as skipped */
+    Skip,
+}
+
+/* Where modified files are written */
+enum Destination {
+    /* Over the original, or next to it when the name changes */
+    InPlace,
+    /* Below a directory, mirroring the paths below each given path, with the directory canonicalized */
+    Dir(PathBuf, PathBuf),
+    /* To a single file, for a single given file */
+    File(PathBuf),
+}
+
+/* Master salt of --deterministic runs, fixed so the same file encrypts the same in every run */
+const DETERMINISTIC_SALT: [u8; SALT_SIZE] = *b"fisher determin.";
+
+/* Stretched master keys by the KDF and salt they were stretched with */
+type Masters = HashMap<(Kdf, [u8; SALT_SIZE]), Zeroizing<Vec<u8>>>;
+
+/* A keyed cipher together with the algorithm and block size it was created for */
+struct Cipher {
+    algorithm: Algorithm,
     block_size: usize,
-    crypt: bool,
     fisher: Fishers,
+    /* HMAC key of the file, None for the configured ciphers, which only modify unauthenticated (headerless) files */
+    mac_key: Option<Zeroizing<[u8; MAC_SIZE]>>,
+}
+
+pub(crate) struct Fisher {
+    /* Associated data the MAC of every headered file covers, see FLAG_AAD */
+    aad: Option<Vec<u8>>,
+    abort_on_error: bool,
+    /* Name encrypted files after their algorithm (e.g. .tw) instead of .fish */
+    algorithm_extension: bool,
+    algorithm_map: Vec<(GlobMatcher, Cipher)>,
+    backup: BackupMode,
+    /* Bind encrypted files to the name of their plaintext, see FLAG_PATH */
+    bind_path: bool,
+    canonical_paths: bool,
+    compress: bool,
+    cipher: Cipher,
+    concurrency: Option<Concurrency>,
+    crypt: bool,
+    destination: Destination,
+    /* Derive the salt, IV and tweak of each file from its plaintext instead of drawing them, see file_params() */
+    deterministic: bool,
+    device: bool,
+    dry_run: bool,
+    ecc: u8,
+    error: Mutex<Option<String>>,
+    exclude: Exclude,
+    existing: ExistingOutput,
+    follow_symlinks: bool,
+    force: bool,
+    header: bool,
+    io_retries: u32,
+    jobs: usize,
+    keep_going: bool,
+    keep_name: bool,
+    kdf: Kdf,
+    /* Keep the header in the sidecar, so encrypted files are exactly as long as their plaintext */
+    length_preserving: bool,
+    /* MAC headered files are authenticated with */
+    mac: MacAlgorithm,
+    /* Salt the master key of this run is stretched with, see Fisher::master() */
+    master_salt: [u8; SALT_SIZE],
+    /* Master keys stretched so far, by KDF and salt, so every run a file came from is stretched once */
+    masters: Mutex<Masters>,
+    meta: bool,
+    migrate: bool,
+    mode: Mode,
+    output_format: OutputFormat,
+    /* Kept to derive the salted key of every headered file */
+    passphrase: Zeroizing<Vec<u8>>,
     paths: Vec<PathBuf>,
+    processed: AtomicUsize,
+    progress: Option<Progress>,
+    /* Dry runs only, the bytes of the files that would be encrypted and their projected encrypted size */
+    projected: Mutex<(u64, u64)>,
+    /* Canonical root the paths of bound files are taken below, see FLAG_RELATIVE */
+    relative_to: Option<PathBuf>,
+    report: Mutex<RunReport>,
+    /* Salt of every file with --salt, which keys them directly instead of from the master key */
+    salt: Option<[u8; SALT_SIZE]>,
+    sequential: bool,
+    /* Overwrite passes of originals left behind by encryption, 0 to leave them as they are */
+    shred: u32,
+    skipped: AtomicUsize,
+    sorted: bool,
+    started: Instant,
+    stats_json: Option<PathBuf>,
+    stop: Arc<AtomicBool>,
+    sync: SyncMode,
     verbose: bool,
-    threads: Mutex<Vec<JoinHandle<()>>>,
+    verify: bool,
+    /* Check the header and MAC of every encrypted file again once the run is done, see verify_outputs() */
+    verify_after: bool,
+    verify_written: bool,
+    /* Canonical directories and files already reached, to follow symlinks without loops or repeats */
+    visited: Mutex<HashSet<PathBuf>>,
+    xattrs: bool,
+}
+
+/* Everything needed to create a Fisher, see FisherConfig::default() for the defaults */
+pub(crate) struct FisherConfig {
+    /* The algorithm to encrypt or decrypt with */
+    pub(crate) algorithm: Algorithm,
+    /* Whether to encrypt (true) or decrypt (false) */
+    pub(crate) crypt: bool,
+    /* The files and directories to encrypt or decrypt */
+    pub(crate) paths: Vec<PathBuf>,
+    /* The passphrase to encrypt or decrypt with, wiped from memory when dropped */
+    pub(crate) passphrase: Zeroizing<Vec<u8>>,
+    /* The block size in bytes, must match the algorithm */
+    pub(crate) block_size: usize,
+    /* How the salted key of each headered file is derived */
+    pub(crate) kdf: Kdf,
+    /* The MAC encrypted files are authenticated with */
+    pub(crate) mac: MacAlgorithm,
+    /* The salt of every encrypted file, keying it directly with the KDF, None for a random salt per file */
+    pub(crate) salt: Option<[u8; SALT_SIZE]>,
+    /* Whether to copy each file to <name>.bak before modifying it */
+    pub(crate) backup: BackupMode,
+    /* A string the MAC of every encrypted file also covers, which decrypting must be given again */
+    pub(crate) aad: Option<Vec<u8>>,
+    /* Whether the MAC of every encrypted file also covers the name of its plaintext, so it can't be passed off as another file */
+    pub(crate) bind_path: bool,
+    /* A directory to bind the path of files below instead of their name, and to find that path below when decrypting */
+    pub(crate) relative_to: Option<PathBuf>,
+    /* Whether to fsync written files (and their directories) */
+    pub(crate) sync: SyncMode,
+    /* Whether to decrypt encrypted output in memory and compare it before writing */
+    pub(crate) verify: bool,
+    /* Whether to read each encrypted file back and check it decrypts to the original before replacing it */
+    pub(crate) verify_written: bool,
+    /* Whether to check the header and MAC of every encrypted file once the run is done */
+    pub(crate) verify_after: bool,
+    /* Whether to compress the plaintext of each encrypted file first, where it shrinks */
+    pub(crate) compress: bool,
+    /* Whether the salt, IV and tweak of each encrypted file are derived from its plaintext, so identical files encrypt identically */
+    pub(crate) deterministic: bool,
+    /* How many times to overwrite each original once it was encrypted before removing it, 0 for never */
+    pub(crate) shred: u32,
+    /* Whether to carry extended attributes over to the modified files */
+    pub(crate) xattrs: bool,
+    /* How many times to retry reads, writes and renames failing with transient errors */
+    pub(crate) io_retries: u32,
+    /* Whether to process everything on the calling thread, in directory order */
+    pub(crate) sequential: bool,
+    /* The number of worker threads, None for one per CPU */
+    pub(crate) jobs: Option<usize>,
+    /* How to report the files processed */
+    pub(crate) progress: ProgressMode,
+    /* Whether to process paths and directory entries in sorted order */
+    pub(crate) sorted: bool,
+    /* Whether to write a <name>.fisher.meta sidecar next to each encrypted file */
+    pub(crate) meta: bool,
+    /* Whether encrypted files are CTR with their header in the sidecar, exactly as long as their plaintext */
+    pub(crate) length_preserving: bool,
+    /* Whether the first failing file stops every thread and fails the run */
+    pub(crate) abort_on_error: bool,
+    /* Whether no failing file stops the run, the failures are listed at the end */
+    pub(crate) keep_going: bool,
+    /* Whether files keep their name, instead of encrypting to <name>.fish and decrypting back to <name> */
+    pub(crate) keep_name: bool,
+    /* Whether encrypted files are named <name>.<extension of their algorithm> (e.g. .tw) instead of <name>.fish */
+    pub(crate) algorithm_extension: bool,
+    /* A directory to write the modified files to, leaving the originals untouched (unless shredded), None to modify them in place */
+    pub(crate) output: Option<PathBuf>,
+    /* What to do when the output of a file already exists, next to it or below --output */
+    pub(crate) existing: ExistingOutput,
+    /* Whether symlinks found in directories are followed, instead of skipped */
+    pub(crate) follow_symlinks: bool,
+    /* Glob patterns of files and directories to skip in directories, on top of DEFAULT_EXCLUDES */
+    pub(crate) exclude: Vec<String>,
+    /* Whether to log absolute, canonicalized paths instead of the paths as given */
+    pub(crate) canonical_paths: bool,
+    /* Whether devices given as paths may be modified in place */
+    pub(crate) device: bool,
+    /* Whether to only list the files that would be modified and those skipped, modifying nothing */
+    pub(crate) dry_run: bool,
+    /* Whether to record and print how many files were modified concurrently */
+    pub(crate) concurrency_report: bool,
+    /* How to encode encrypted files */
+    pub(crate) output_format: OutputFormat,
+    /* Glob patterns selecting another algorithm and block size than the default for matching files */
+    pub(crate) algorithm_map: Vec<(String, Algorithm, usize)>,
+    /* Whether encrypted files carry a header, false reads and writes the legacy headerless format */
+    pub(crate) header: bool,
+    /* How the blocks of headered files are chained, headerless files are always ECB */
+    pub(crate) mode: Mode,
+    /* Reed-Solomon parity bytes per codeword added to encrypted files, 0 for none (see ecc.rs) */
+    pub(crate) ecc: u8,
+    /* Whether to decrypt files without a header or sidecar, which might never have been encrypted */
+    pub(crate) force: bool,
+    /* Whether legacy headerless files are decrypted and encrypted again with a header (crypt must be true) */
+    pub(crate) migrate: bool,
+    /* The file to write a JSON summary of the run to once it ends, successful or not */
+    pub(crate) stats_json: Option<PathBuf>,
+    /* Whether to print what is being processed */
+    pub(crate) verbose: bool,
+}
+
+impl Default for FisherConfig {
+    fn default() -> Self {
+        /*
+            * Threefish-1024 encryption of nothing, with every optional behavior turned off
+        */
+        FisherConfig {
+            algorithm: Algorithm::Threefish,
+            crypt: true,
+            paths: Vec::new(),
+            passphrase: Zeroizing::new(Vec::new()),
+            block_size: 128,
+            kdf: Kdf::Pbkdf2 { iterations: 100_000 },
+            mac: MacAlgorithm::HmacSha256,
+            salt: None,
+            backup: BackupMode::Off,
+            aad: None,
+            bind_path: true,
+            relative_to: None,
+            sync: SyncMode::Off,
+            verify: false,
+            verify_written: false,
+            verify_after: false,
+            compress: false,
+            deterministic: false,
+            shred: 0,
+            xattrs: false,
+            io_retries: 3,
+            sequential: false,
+            jobs: None,
+            progress: ProgressMode::Off,
+            sorted: false,
+            meta: false,
+            length_preserving: false,
+            abort_on_error: false,
+            keep_going: false,
+            keep_name: false,
+            algorithm_extension: false,
+            output: None,
+            existing: ExistingOutput::Error,
+            follow_symlinks: false,
+            exclude: Vec::new(),
+            canonical_paths: false,
+            device: false,
+            dry_run: false,
+            concurrency_report: false,
+            output_format: OutputFormat::Raw,
+            algorithm_map: Vec::new(),
+            header: true,
+            mode: Mode::Cbc,
+            ecc: 0,
+            force: false,
+            migrate: false,
+            stats_json: None,
+            verbose: false,
+        }
+    }
 }
 
 impl Fisher {
-    pub(crate) fn new(algorithm: u8, crypt: bool, paths: Vec<PathBuf>, passphrase: String, block_size: usize, verbose: bool) -> FResult<Fisher> {
+    pub(crate) fn from_config(mut config: FisherConfig) -> FResult<Fisher> {
         /*
             * Create a new Fisher Instance
 
-            @param crypt: bool
-                * Whether to encrypt or decrypt
-            @param paths: PathBuf
-                * The path to the file or directory to encrypt or decrypt
-            @param passphrase: String
-                * The passphrase to encrypt or decrypt with
+            @param config: FisherConfig
+                * The algorithm, paths, passphrase and options to run with
             @return FResult: Result<Fisher, Box<dyn Error>>
                 * The Fisher instance or some Error
         */
+        /* Never process a file twice because it was also passed inside another path */
+        config.paths = dedupe_paths(config.paths)?;
+        if config.sorted {
+            config.paths.sort();
+        }
+
+        /*
+            * Write to the output directory, created if needed, or to the output file for a single file
+              that isn't given an existing directory
+        */
+        let destination = match config.output.take() {
+            Some(output) => match (config.paths.as_slice(), output.is_dir()) {
+                ([path], false) if path.is_file() => Destination::File(output),
+                /* A dry run creates nothing, the output only names where files would be written */
+                _ if config.dry_run => {
+                    let canonical = output.canonicalize().unwrap_or_else(|_| output.clone());
+                    Destination::Dir(output, canonical)
+                }
+                _ => {
+                    fs::create_dir_all(&output).map_err(|err| format!("Could not create {:?}: {}", output, err))?;
+                    let canonical = output.canonicalize()?;
+                    Destination::Dir(output, canonical)
+                }
+            },
+            None => Destination::InPlace
+        };
+
+        let exclude = Exclude::new(&config.exclude)?;
+
+        /* Count the files up front so progress can be reported against the total */
+        let progress = match config.progress {
+            ProgressMode::Off => None,
+            mode => Progress::new(mode, count(&config.paths, config.backup, config.crypt, config.follow_symlinks, &exclude)?.0)
+        };
+
+        /* Every mapped algorithm gets its own key from the same passphrase */
+        let mut algorithm_map = Vec::with_capacity(config.algorithm_map.len());
+        for (pattern, algorithm, block_size) in &config.algorithm_map {
+            let glob = Glob::new(pattern).map_err(|err| format!("Invalid pattern '{}' in algorithm map: {}", pattern, err))?;
+            algorithm_map.push((glob.compile_matcher(), Cipher {
+                algorithm: *algorithm,
+                block_size: *block_size,
+                fisher: generate_key(*algorithm, *block_size, config.kdf.unsalted(), &config.passphrase)?,
+                mac_key: None,
+            }));
+        }
+
         Ok(Fisher {
-            block_size,
-            crypt,
-            fisher: generate_key(algorithm, block_size, passphrase)?,
-            paths,
-            threads: Mutex::new(Vec::new()),
-            verbose,
+            aad: config.aad,
+            abort_on_error: config.abort_on_error,
+            algorithm_extension: config.algorithm_extension,
+            algorithm_map,
+            backup: config.backup,
+            bind_path: config.bind_path,
+            canonical_paths: config.canonical_paths,
+            cipher: Cipher {
+                algorithm: config.algorithm,
+                block_size: config.block_size,
+                fisher: generate_key(config.algorithm, config.block_size, config.kdf.unsalted(), &config.passphrase)?,
+                mac_key: None,
+            },
+            concurrency: match config.concurrency_report {
+                true => Some(Concurrency::new()),
+                false => None
+            },
+            crypt: config.crypt,
+            destination,
+            deterministic: config.deterministic,
+            device: config.device,
+            dry_run: config.dry_run,
+            ecc: config.ecc,
+            error: Mutex::new(None),
+            exclude,
+            existing: config.existing,
+            follow_symlinks: config.follow_symlinks,
+            force: config.force,
+            header: config.header,
+            io_retries: config.io_retries,
+            jobs: match config.jobs {
+                Some(jobs) => jobs,
+                None => pool::default_jobs()
+            },
+            keep_going: config.keep_going,
+            keep_name: config.keep_name,
+            kdf: config.kdf,
+            length_preserving: config.length_preserving,
+            mac: config.mac,
+            /* --deterministic files must not depend on the run they were encrypted in */
+            master_salt: match config.deterministic {
+                true => DETERMINISTIC_SALT,
+                false => {
+                    let mut master_salt = [0u8; SALT_SIZE];
+                    rand::thread_rng().fill_bytes(&mut master_salt);
+                    master_salt
+                }
+            },
+            masters: Mutex::new(HashMap::new()),
+            /* Length-preserving files are CTR, with their header in the sidecar */
+            meta: config.meta || config.length_preserving,
+            migrate: config.migrate,
+            mode: match config.length_preserving {
+                true => Mode::Ctr,
+                false => config.mode
+            },
+            output_format: config.output_format,
+            /* Headered files get their own key from the passphrase and their salt, see salted() */
+            passphrase: config.passphrase.clone(),
+            paths: config.paths,
+            processed: AtomicUsize::new(0),
+            progress,
+            projected: Mutex::new((0, 0)),
+            relative_to: match config.relative_to {
+                Some(root) => Some(root.canonicalize().map_err(|err| format!("Could not resolve {:?}: {}", root, err))?),
+                None => None
+            },
+            report: Mutex::new(RunReport {
+                operation: operation(config.migrate, config.crypt).to_string(),
+                ..RunReport::default()
+            }),
+            salt: config.salt,
+            sequential: config.sequential,
+            shred: config.shred,
+            skipped: AtomicUsize::new(0),
+            sorted: config.sorted,
+            started: Instant::now(),
+            stats_json: config.stats_json,
+            stop: Arc::new(AtomicBool::new(false)),
+            sync: config.sync,
+            verbose: config.verbose,
+            verify: config.verify,
+            verify_after: config.verify_after,
+            verify_written: config.verify_written,
+            compress: config.compress,
+            visited: Mutex::new(HashSet::new()),
+            xattrs: config.xattrs,
         })
     }
 
-    pub(crate) fn run(&'static self) -> crate::FResult<()> {
+    pub(crate) fn stop(&self) {
+        /*
+            * Ask the Fisher to Stop
+
+            * Files already being modified are finished, no new files are started
+
+            @param self: Fisher Instance
+        */
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn stopped(&self) -> bool {
+        /*
+            * Check Whether the Fisher was Asked to Stop
+
+            @param self: Fisher Instance
+            @return bool: True if stop() was called
+        */
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn stop_flag(&self) -> Arc<AtomicBool> {
+        /*
+            * Get the Flag Set by stop(), for Signal Handlers that Outlive the Fisher
+
+            @param self: Fisher Instance
+            @return Arc<AtomicBool>: Set to stop the Fisher
+        */
+        Arc::clone(&self.stop)
+    }
+
+    fn abort(&self, err: Box<dyn Error>) {
+        /*
+            * Record the First Error and Stop Every Thread
+
+            * Only the first error is kept, later ones are usually caused by stopping
+
+            @param self: Fisher Instance
+            @param err: Box<dyn Error>
+                * The error that failed the run
+        */
+        tracing::error!(error = %err, "aborting run");
+
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(err.to_string());
+        }
+        self.stop();
+    }
+
+    pub(crate) fn processed(&self) -> usize {
+        /*
+            * Get the Number of Files Modified so Far
+
+            @param self: Fisher Instance
+            @return usize: The number of files successfully modified
+        */
+        self.processed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn paths(&self) -> &[PathBuf] {
+        /*
+            * Get the Paths the Fisher Runs On
+
+            @param self: Fisher Instance
+            @return &[PathBuf]: The paths, without duplicates and in processing order
+        */
+        &self.paths
+    }
+
+    pub(crate) fn projected(&self) -> (u64, u64) {
+        /*
+            * Get the Size of the Files a Dry Run Would Encrypt, Before and After
+
+            @param self: Fisher Instance
+            @return (u64, u64): The total size of the files and their total projected size, see projected_size()
+        */
+        *self.projected.lock().unwrap()
+    }
+
+    pub(crate) fn verbose(&self) -> bool {
+        /*
+            * Check Whether the Fisher Prints What it is Processing
+
+            @param self: Fisher Instance
+            @return bool: True with --verbose
+        */
+        self.verbose
+    }
+
+    pub(crate) fn skipped(&self) -> usize {
+        /*
+            * Get the Number of Files Left Alone Because They Were Already Migrated or Encrypted, or Their Output Exists
+
+            @param self: Fisher Instance
+            @return usize: The number of files migrate found with a header, encrypting found encrypted, or
+                           --skip-existing left alone
+        */
+        self.skipped.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn run(&self) -> crate::FResult<()> {
         /*
             * Run the Fisher on the Given Path
 
@@ -51,98 +588,292 @@ impl Fisher {
             @return FResult: Result<(), Box<dyn Error>>
         */
 
+        /* Everything runs on this thread in directory order when sequential, otherwise on a pool of workers */
+        let errors = match self.sequential {
+            true => self.run_sequential(),
+            false => Pool::run(self.jobs, self.paths.iter().cloned().map(Work::Path), |work, pool| self.work(work, pool))
+        };
+
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+        if let Some(concurrency) = &self.concurrency {
+            concurrency.report();
+        }
+
+        /* Check the written files once nothing writes anymore, failures are recorded in the report */
+        let unverified = match self.verify_after && !self.stopped() {
+            true => self.verify_outputs(),
+            false => Vec::new()
+        };
+
+        /* The report covers failed runs too, so it is written before failing */
+        let written = match &self.stats_json {
+            Some(path) => self.write_report(path),
+            None => Ok(())
+        };
+
+        /* Fail the run with the error that stopped it, once every worker has finished */
+        if let Some(err) = self.error.lock().unwrap().take() {
+            return Err(err.into());
+        }
+        /* Otherwise with every failure, the other paths were modified */
+        if !errors.is_empty() {
+            /* --keep-going lists them one per line */
+            if self.keep_going {
+                println!("Modified {} file(s), {} failed:", self.processed(), errors.len());
+                for error in &errors {
+                    println!("    {}", error);
+                }
+                return Err(format!("{} path(s) could not be modified", errors.len()).into());
+            }
+            return Err(format!("{} path(s) could not be modified: {}", errors.len(), errors.join("; ")).into());
+        }
+        if !unverified.is_empty() {
+            println!("Encrypted {} file(s), {} failed verification:", self.processed(), unverified.len());
+            for error in &unverified {
+                println!("    {}", error);
+            }
+            return Err(format!("{} encrypted file(s) failed verification", unverified.len()).into());
+        }
+
+        written
+    }
+
+    fn run_sequential(&self) -> Vec<String> {
+        /*
+            * Run the Fisher on the Given Paths One File at a Time, on this Thread
+
+            @param self: Fisher Instance
+            @return Vec<String>
+                * With --keep-going, the failures naming their paths, otherwise the first one stops the run
+        */
+        let mut errors = Vec::new();
         for path in &self.paths {
-            let path = path.clone();
+            /* Don't start on new paths once asked to stop */
+            if self.stopped() {
+                break;
+            }
+
             match path.is_dir() {
                 /* Iterate over the directory */
                 true => {
                     if self.verbose {
-                        println!("Got directory: {:?}", path);
+                        println!("Got directory: {:?}", self.log_path(path));
                     }
-                    /* Create new thread to run the directory */
-                    {
-                        let mut threads = self.threads.lock().unwrap();
-                        threads.push(std::thread::spawn(move || {
-                            self.iter_dir(path)
-                                .expect("Failed to run directory");
-                        }));
+                    if let Err(err) = self.iter_dir(path, &self.exclude, &mut errors) {
+                        self.abort(err);
                     }
                 }
                 /* Modify the file */
                 false => {
                     if self.verbose {
-                        println!("Got file: {:?}", path);
+                        println!("Got file: {:?}", self.log_path(path));
+                    }
+                    if let Err(err) = self.modify_file(path) {
+                        if let Err(err) = self.keep_going(path, self.record_failure(path, err), &mut errors) {
+                            self.abort(err);
+                        }
                     }
-                    self.modify_file(&path)?;
                 }
             }
         }
+        errors
+    }
 
-        /* Wait for all threads to finish */
-        loop {
-            /* Lock the threads */
-            let mut threads = self.threads.lock().unwrap();
-            /* If there are are threads, pop the first, drop the lock, and join the thread */
-            if threads.len() > 0 {
-                /* Pop the first thread */
-                let thread = threads.remove(0);
-                /*
-                    * Drop the lock before joining the thread
-                    * Prevents deadlock if threads are still being spawned in run_dir()
-                */
-                drop(threads);
-                /* Join the thread */
-                thread.join().unwrap();
-            } else {
-                /* No threads left, break the loop (Lock drops on loop exit) */
-                break;
+    fn work(&self, work: Work, pool: &Pool<Work>) -> crate::FResult<()> {
+        /*
+            * Work on an Item of the Worker Pool
+
+            @param self: Fisher Instance
+            @param work: Work
+                * The path to modify or directory to list
+            @param pool: &Pool<Work>
+                * The pool, the entries of listed directories are pushed to it
+            @return FResult: Result<(), Box<dyn Error>>
+                * The error of a file or directory that failed, naming it
+        */
+        /* Don't start on new items once asked to stop */
+        if self.stopped() {
+            return Ok(());
+        }
+
+        match work {
+            Work::Path(path) => match path.is_dir() {
+                true => {
+                    if self.verbose {
+                        println!("Got directory: {:?}", self.log_path(&path));
+                    }
+                    pool.push(Work::Dir(path, self.exclude.clone()));
+                }
+                /* A file given on the command line failing stops the run */
+                false => {
+                    if self.verbose {
+                        println!("Got file: {:?}", self.log_path(&path));
+                    }
+                    self.modify_file(&path).map_err(|err| self.fail(&path, self.record_failure(&path, err), true))?;
+                }
+            },
+            Work::Dir(path, exclude) => {
+                let _span = tracing::info_span!("dir", path = ?path).entered();
+
+                let (modules, exclude) = self.list_dir(&path, &exclude).map_err(|err| self.fail(&path, err, false))?;
+                for module in modules {
+                    match module.is_dir() {
+                        true => {
+                            if self.verbose {
+                                println!("Got subdirectory: {:?}", self.log_path(&module));
+                            }
+                            pool.push(Work::Dir(module, exclude.clone()));
+                        }
+                        false => {
+                            if !self.skip(&module) {
+                                pool.push(Work::File(module));
+                            }
+                        }
+                    }
+                }
+            }
+            Work::File(path) => {
+                if self.verbose {
+                    println!("Got file: {:?}", self.log_path(&path));
+                }
+                self.modify_file(&path).map_err(|err| self.fail(&path, self.record_failure(&path, err), false))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fail(&self, path: &Path, err: Box<dyn Error>, stop: bool) -> Box<dyn Error> {
+        /*
+            * Handle a File or Directory that Failed
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file or directory
+            @param err: Box<dyn Error>
+                * The error
+            @param stop: bool
+                * Whether the failure stops the run even without --abort-on-first-error, unless --keep-going
+            @return Box<dyn Error>: The error, naming the path
+        */
+        let named = format!("{:?}: {}", self.log_path(path), err);
+        if (stop && !self.keep_going) || self.abort_on_error {
+            self.abort(err);
+        }
+        named.into()
+    }
+
+    fn keep_going(&self, path: &Path, err: Box<dyn Error>, errors: &mut Vec<String>) -> crate::FResult<()> {
+        /*
+            * Carry On After a File or Directory Failed with --keep-going, Sequential Runs
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file or directory
+            @param err: Box<dyn Error>
+                * The error
+            @param errors: &mut Vec<String>
+                * The failures so far, the error is added naming the path with --keep-going
+            @return FResult: Result<(), Box<dyn Error>>
+                * The error itself without --keep-going, to stop the run
+        */
+        match self.keep_going {
+            true => {
+                errors.push(format!("{:?}: {}", self.log_path(path), err));
+                Ok(())
+            }
+            false => Err(err)
+        }
+    }
+
+    pub(crate) fn preview(&self, blocks: usize) -> crate::FResult<()> {
+        /*
+            * Print the First Modified Blocks of Each File Without Modifying Anything
+
+            * Debugging aid, e.g. to see identical plaintext blocks produce identical ciphertext blocks
+
+            @param self: Fisher Instance
+            @param blocks: usize
+                * The number of blocks to print per file
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+
+        for path in &self.paths {
+            if path.is_dir() {
+                println!("Skipping directory: {:?}", path);
+                continue;
+            }
+
+            /* Only read as many bytes as needed for the requested blocks */
+            let mut data: Vec<u8> = Vec::new();
+            let cipher = self.cipher_for(path);
+            File::open(path)?.take((blocks * cipher.block_size) as u64).read_to_end(&mut data)?;
+
+            /* Modify whole blocks only, so the preview shows the blocks as they are stored */
+            data.resize(data.len().div_ceil(cipher.block_size) * cipher.block_size, 0);
+            let output = self.transform(cipher, &data, true, Mode::Ecb, &[], None)?;
+
+            println!("{:?}:", path);
+            for (index, block) in output.chunks(cipher.block_size).enumerate() {
+                let hex: String = block.iter().map(|byte| format!("{:02x}", byte)).collect();
+                println!("    block {}: {}", index, hex);
             }
         }
+
         Ok(())
     }
 
-    fn iter_dir(&'static self, path: PathBuf) -> crate::FResult<()> {
+    fn iter_dir(&self, path: &Path, exclude: &Exclude, errors: &mut Vec<String>) -> crate::FResult<()> {
         /*
-            * Run the Fisher on the Given Directory
+            * Run the Fisher on the Given Directory, on this Thread
 
             @param self: Fisher Instance
-            @param path: PathBuf
+            @param path: &Path
                 * The path to the directory to encrypt or decrypt
+            @param exclude: &Exclude
+                * The patterns of the directory above
+            @param errors: &mut Vec<String>
+                * With --keep-going, failures are added here instead of returned
             @return FResult: Result<(), Box<dyn Error>>
         */
 
+        let _span = tracing::info_span!("dir", path = ?path).entered();
+
+        let (modules, exclude) = match self.list_dir(path, exclude) {
+            Ok(listed) => listed,
+            Err(err) => return self.keep_going(path, err, errors)
+        };
+
         /* Iterate over the directory */
-        for module in fs::read_dir(path)? {
-            /* Get the module */
-            let module = module?;
+        for module in modules {
+            /* Don't start on new entries once asked to stop */
+            if self.stopped() {
+                break;
+            }
 
-            match module.path().is_dir() {
+            match module.is_dir() {
                 true => {
                     if self.verbose {
-                        println!("Got subdirectory: {:?}", module.path());
-                    }
-                    /* Create new thread to run the subdirectory */
-                    {
-                        let mut threads = self.threads.lock().unwrap();
-                        threads.push(std::thread::spawn(move || {
-                            self.iter_dir(module.path())
-                                .expect("Failed to run subdirectory");
-                        }));
+                        println!("Got subdirectory: {:?}", self.log_path(&module));
                     }
+                    self.iter_dir(&module, &exclude, errors)?;
                 }
                 false => {
                     /* Modify the file */
-                    /* On MAC, ignore .DS_Store */
-                    if module.path().file_name().unwrap().eq(".DS_Store") {
+                    if self.skip(&module) {
                         continue;
                     }
 
                     if self.verbose {
-                        println!("Got file: {:?}", module.path());
+                        println!("Got file: {:?}", self.log_path(&module));
                     }
 
                     /* Run modify_file() on the file */
-                    self.modify_file(&module.path())?;
+                    if let Err(err) = self.modify_file(&module) {
+                        self.keep_going(&module, self.record_failure(&module, err), errors)?;
+                    }
                 }
             }
         }
@@ -150,78 +881,2989 @@ impl Fisher {
         Ok(())
     }
 
-    fn modify_file(&'static self, path: &PathBuf) -> crate::FResult<()> {
+    pub(crate) fn exclude(&self) -> &Exclude {
+        /*
+            * Get the Patterns of Files and Directories Skipped in the Given Directories
+
+            @param self: Fisher Instance
+            @return &Exclude
+                * The --exclude patterns, enter() adds those of each directory's .fisherignore
+        */
+        &self.exclude
+    }
+
+    fn skip(&self, path: &Path) -> bool {
+        /*
+            * Check Whether a File Found in a Directory Should be Skipped, Saying Why if Verbose or Dry Running
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file
+            @return bool: True if the file should not be modified
+        */
+        match skip_reason(path, self.backup, self.crypt) {
+            Some(reason) => {
+                if self.verbose || self.dry_run {
+                    println!("Skipping {:?}, {}", self.log_path(path), reason);
+                }
+                true
+            }
+            None => false
+        }
+    }
+
+    fn visit(&self, path: &Path) -> bool {
+        /*
+            * Mark a Directory or File as Reached, Following Symlinks
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The directory or file
+            @return bool: True the first time its canonical path is reached
+        */
+        match path.canonicalize() {
+            Ok(canonical) => self.visited.lock().unwrap().insert(canonical),
+            Err(_) => true
+        }
+    }
+
+    fn list_dir(&self, path: &Path, exclude: &Exclude) -> crate::FResult<(Vec<PathBuf>, Exclude)> {
+        /*
+            * Collect the Entries of the Given Directory
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The directory
+            @param exclude: &Exclude
+                * The patterns of the directory above
+            @return FResult: Result<(Vec<PathBuf>, Exclude), Box<dyn Error>>
+                * The entry paths, sorted if requested, a failure is recorded in the run report
+                * Entries matching the patterns of the directory are left out, the patterns are returned for
+                  its subdirectories
+                * Symlinks are left out, or with --follow-symlinks replaced by the file they point to, and a
+                  directory or file already reached through another link is left out
+        */
+        if self.follow_symlinks && !self.visit(path) {
+            if self.verbose || self.dry_run {
+                println!("Skipping {:?}, already visited through another link", self.log_path(path));
+            }
+            return Ok((Vec::new(), exclude.clone()));
+        }
+
+        let exclude = exclude.enter(path).map_err(|err| self.record_failure(path, err))?;
+        let entries = fs::read_dir(path)
+            .and_then(|modules| modules.map(|module| module.map(|module| module.path())).collect::<Result<Vec<PathBuf>, _>>())
+            .map_err(|err| self.record_failure(path, err.into()))?;
+
+        /* Symlinks may point outside the given paths or back up the tree, they are only followed if requested */
+        let mut modules = Vec::with_capacity(entries.len());
+        for module in entries {
+            if let Some(pattern) = exclude.matching(&module) {
+                if self.verbose || self.dry_run {
+                    println!("Skipping {:?}, excluded by {}", self.log_path(&module), pattern);
+                }
+                continue;
+            }
+
+            match (is_symlink(&module), self.follow_symlinks) {
+                (true, false) => {
+                    if self.verbose || self.dry_run {
+                        println!("Skipping symlink {:?}", self.log_path(&module));
+                    }
+                }
+                /* Linked files are modified where they are, linked directories are checked once listed */
+                (true, true) => match module.canonicalize() {
+                    Ok(target) if target.is_dir() => modules.push(module),
+                    Ok(target) => {
+                        if self.visit(&target) {
+                            modules.push(target);
+                        }
+                    }
+                    Err(err) => eprintln!("Warning: skipping symlink {:?}, {}", self.log_path(&module), err)
+                },
+                (false, true) if !module.is_dir() => {
+                    if module.canonicalize().map_or(true, |target| self.visit(&target)) {
+                        modules.push(module);
+                    }
+                }
+                (false, _) => modules.push(module)
+            }
+        }
+        /* Never modify the output again when it is below a given directory */
+        if let Destination::Dir(_, output) = &self.destination {
+            modules.retain(|module| !module.is_dir() || module.canonicalize().is_ok_and(|module| &module != output));
+        }
+        if self.sorted {
+            modules.sort();
+        }
+        Ok((modules, exclude))
+    }
+
+    fn modify_file(&self, path: &Path) -> crate::FResult<()> {
         /*
             * Modify [Encrypt or Decrypt] the Given File
 
             @param self: Fisher Instance
-            @param path: &PathBuf
+            @param path: &Path
                 * The path to the file to encrypt or decrypt
 
             @return FResult: Result<(), Box<dyn Error>>
         */
-        let mut file = File::open(path)?;
-        let mut buffer: Vec<u8>;
+        let _span = tracing::info_span!("file", path = ?path, crypt = self.crypt).entered();
 
-        /* Read the file into blocks */
-        let mut modified_blocks: Vec<Vec<u8>> = Vec::new();
+        /* Count this thread as busy until the file is done */
+        let _worker = self.concurrency.as_ref().map(Concurrency::worker);
 
-        loop {
-            /* Create a new buffer */
-            buffer = vec![0; self.block_size];
-            /* Read the buffer size from the file */
-            let bytes_read = file.read(&mut buffer)?;
+        /* Devices can't be replaced or resized, they are modified in place */
+        if is_device(path) {
+            return self.modify_device(path);
+        }
 
-            if bytes_read == 0 {
-                /* End of file, break the loop */
-                break;
-            }
+        /* Encrypting an encrypted file again buries its header under another layer, only do so on request */
+        if self.crypt && !self.migrate && !self.force && is_encrypted(path)? {
+            eprintln!("Warning: skipping {:?}, it is already fisher-encrypted (pass --force to encrypt it again)",
+                      self.log_path(path));
+            self.skipped.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
 
-            /* Convert the buffer to a vector */
-            let mut block: Vec<u8> = buffer.to_vec();
-
-            if match self.crypt {
-                /* True -> Encrypt */
-                true => self.fisher.encrypt_block(&mut block)?,
-                /* False -> Decrypt */
-                false => self.fisher.decrypt_block(&mut block)?
-            } {
-                /* Push the modified block to the vector */
-                modified_blocks.push(block.to_vec());
-            } else {
-                /* Failed to encrypt or decrypt the block */
-                return Err("Failed to encrypt or decrypt block".into());
-            }
-        }
-
-        /* Write the modified blocks to the file */
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-
-        /* Iterate over the modified blocks writing each block */
-        for block in &modified_blocks {
-            if *block == modified_blocks.last().unwrap().to_vec() {
-                /* Last block, clear padding */
-                let mut padding = 0;
-                for byte in block.iter().rev() {
-                    if *byte == 0 {
-                        padding += 1;
-                    } else {
-                        break;
-                    }
-                }
-                /* Truncate the block */
-                let block = &block[..block.len() - padding];
-                file.write(&block)?;
-                break;
+        /* Only list what would be done, without reading or writing anything else */
+        if self.dry_run {
+            let output_path = self.output_path(path);
+            /* Encrypting grows every file by its header, padding and parity */
+            let growth = match self.crypt && !self.migrate {
+                true => {
+                    let size = fs::metadata(path)?.len();
+                    let projected = self.projected_size(path, size);
+                    let mut total = self.projected.lock().unwrap();
+                    total.0 += size;
+                    total.1 += projected;
+                    format!(" ({} -> {} bytes, +{})", size, projected, projected - size)
+                }
+                false => String::new()
+            };
+            match output_path == path {
+                true => println!("Would {} {:?}{}", self.operation(), self.log_path(path), growth),
+                false => println!("Would {} {:?} to {:?}{}", self.operation(), self.log_path(path), self.log_path(&output_path), growth)
             }
+            self.processed.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
 
-            file.write(&block)?;
+        /* A sidecar written on encryption records how the file was encrypted, for files encrypted with --meta */
+        let meta = match self.crypt {
+            true => None,
+            false => Meta::read(path)?
+        };
+
+        /* Encrypted files are named <name>.fish, only replace another file with the output on request */
+        let output_path = self.output_path(path);
+        if output_path != path && output_path.exists() {
+            match self.existing {
+                ExistingOutput::Error => return Err(match self.in_place() {
+                    true => format!("{:?} already exists, not overwriting it (pass --overwrite, --skip-existing or \
+                                     --keep-name to modify {:?} in place)", self.log_path(&output_path), self.log_path(path)),
+                    false => format!("{:?} already exists, not overwriting it (pass --overwrite or --skip-existing)",
+                                     self.log_path(&output_path))
+                }.into()),
+                ExistingOutput::Overwrite => {
+                    if self.verbose {
+                        println!("Overwriting {:?}", self.log_path(&output_path));
+                    }
+                }
+                ExistingOutput::Skip => {
+                    eprintln!("Warning: skipping {:?}, {:?} already exists", self.log_path(path), self.log_path(&output_path));
+                    self.skipped.fetch_add(1, Ordering::SeqCst);
+                    return Ok(());
+                }
+            }
+        }
+        if !self.in_place() {
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
         }
 
-        Ok(())
-    }
-}
+        /*
+            * Write the modified file to a temporary file next to the original and rename it over the original,
+              or to its new name and remove the original
+            * If anything fails before the rename, the original is left untouched
+            * Raw headered files are streamed a chunk at a time, anything needing the whole file at once
+              (output formats, parity, --two-pass-verify, --compress, legacy files) is modified in memory
+        */
+        let temp_path = temp_path(&output_path);
+
+        /* Hash the plaintext before encrypting it, to check the encrypted file against once written */
+        let expected = match self.crypt && self.verify_written {
+            true => Some(self.retry("read", path, || {
+                let mut hasher = Sha256::new();
+                io::copy(&mut File::open(path)?, &mut hasher)?;
+                Ok(hasher.finalize())
+            })?),
+            false => None
+        };
 
+        let modified = match self.crypt {
+            true => match self.header && !self.migrate && !self.verify && !self.compress && self.ecc == 0 && self.output_format == OutputFormat::Raw {
+                true => self.encrypt_stream(path, &temp_path).map(Some),
+                false => self.modify_buffered(path, &temp_path, meta.as_ref())
+            },
+            /* Length-preserving files keep their header in the sidecar, the ciphertext starts right away */
+            false => match meta.as_ref().map_or(Ok(None), Meta::header) {
+                Ok(Some(header)) => self.decrypt_stream(path, &temp_path, &header, 0).map(Some),
+                Ok(None) => match self.stream_header(path) {
+                    Ok(Some(header)) => self.decrypt_stream(path, &temp_path, &header, header.size() as u64).map(Some),
+                    Ok(None) => self.modify_buffered(path, &temp_path, meta.as_ref()),
+                    Err(err) => Err(err)
+                },
+                Err(err) => Err(err)
+            }
+        };
+        let modified = match modified {
+            Ok(Some(modified)) => modified,
+            /* Skipped, e.g. migrating a file that already has a header */
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                /* Clean up the partial output, ignoring errors since the temp file may not exist */
+                let _ = fs::remove_file(&temp_path);
+                return Err(err);
+            }
+        };
+        tracing::debug!(bytes = modified.read, "read");
+        tracing::debug!(bytes = modified.written, "written");
+
+        /* Only replace the original once the encrypted file was read back and decrypted to it */
+        if let Some(expected) = expected {
+            if self.verbose {
+                println!("Verifying {:?}", self.log_path(path));
+            }
+            if let Err(err) = self.verify_written(path, &temp_path, &expected) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(err);
+            }
+        }
+
+        /* Restore the permissions recorded on encryption, in case the encrypted file lost them on the way */
+        if modified.permissions != 0 {
+            if let Err(err) = set_mode_bits(&temp_path, modified.permissions) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(err.into());
+            }
+        }
+
+        /* Keep a copy of the original before it gets replaced */
+        let backup_path = backup_path(path);
+        if self.backup != BackupMode::Off {
+            if self.verbose {
+                println!("Backing up {:?} to {:?}", self.log_path(path), self.log_path(&backup_path));
+            }
+            if let Err(err) = fs::copy(path, &backup_path) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(err.into());
+            }
+        }
+
+        /* Hold on to the original, so it can be overwritten once the encrypted file replaced it */
+        let original = match self.crypt && self.shred > 0 {
+            true => match self.open_shred(path) {
+                Ok(original) => original,
+                Err(err) => {
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(err);
+                }
+            },
+            false => None
+        };
+
+        /* A renamed output reached again through another link must not be modified twice */
+        if self.follow_symlinks && output_path != path {
+            let parent = output_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if let (Ok(parent), Some(name)) = (parent.canonicalize(), output_path.file_name()) {
+                self.visited.lock().unwrap().insert(parent.join(name));
+            }
+        }
+
+        if let Err(err) = self.retry("rename", &temp_path, || Ok(fs::rename(&temp_path, &output_path)?)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        if output_path != path && self.in_place() {
+            if self.verbose {
+                println!("Renamed {:?} to {:?}", self.log_path(path), self.log_path(&output_path));
+            }
+            self.retry("remove", path, || Ok(fs::remove_file(path)?))?;
+        }
+        if let Some(mut original) = original {
+            shred::overwrite(&mut original, self.shred).map_err(|err| format!("Could not shred {:?}: {}", path, err))?;
+            if self.verbose {
+                println!("Shredded {:?} with {} pass(es)", self.log_path(path), self.shred);
+            }
+        }
+        /* Shredding removes the original also when the encrypted file was written elsewhere */
+        if self.crypt && self.shred > 0 && !self.in_place() {
+            self.retry("remove", path, || Ok(fs::remove_file(path)?))?;
+        }
+
+        /* Record how the file was encrypted in its sidecar, or consume the sidecar once decrypted */
+        if self.crypt && self.meta {
+            Meta {
+                algorithm: modified.algorithm,
+                block_size: modified.block_size,
+                mode: match self.header {
+                    true => self.mode,
+                    false => Mode::Ecb
+                }.name().to_string(),
+                original_size: modified.original_size,
+                header: modified.header.as_ref().map(|header| STANDARD.encode(header)),
+            }.write(&output_path)?;
+        } else if meta.is_some() && self.in_place() {
+            fs::remove_file(meta_path(path))?;
+        }
+
+        /* Make the rename itself durable if requested */
+        self.sync_dir(&output_path)?;
+
+        /* The file was modified successfully, the backup is no longer needed */
+        if self.backup == BackupMode::Removable {
+            fs::remove_file(&backup_path)?;
+        }
+
+        self.record_file(modified.algorithm, modified.block_size, modified.read, modified.written);
+        if self.verify_after {
+            self.report.lock().unwrap().outputs.push((output_path, path.to_path_buf()));
+        }
+        self.processed.fetch_add(1, Ordering::SeqCst);
+        if let Some(progress) = &self.progress {
+            progress.file_done();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn pipe(&self) -> crate::FResult<()> {
+        /*
+            * Encrypt or Decrypt stdin to stdout, e.g. cat file | fisher encrypt -a tw > file.fish
+
+            * The header records the original size and the MAC of the whole ciphertext, and no plaintext
+              may be written before the MAC was checked, so the data is held in memory as for --ecc
+            * Nothing but the data is written to stdout, verbose output goes to stderr
+
+            @param self: Fisher Instance
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+        let path = Path::new(PIPE_PATH);
+
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data)?;
+        if self.verbose {
+            eprintln!("Read {} byte(s) from stdin", data.len());
+        }
+
+        let (output, (algorithm, block_size)) = match self.crypt {
+            true => {
+                let (output, used) = self.encrypt(path, &data)?;
+                (self.output_format.encode(output), used)
+            }
+            false => {
+                let (output, used, _) = self.decrypt(path, &encoding::decode(&data)?, None)?;
+                (output, used)
+            }
+        };
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&output)?;
+        stdout.flush()?;
+        if self.verbose {
+            eprintln!("Wrote {} byte(s) to stdout", output.len());
+        }
+
+        self.record_file(algorithm, block_size, data.len(), output.len());
+        self.processed.fetch_add(1, Ordering::SeqCst);
+        match &self.stats_json {
+            Some(stats_json) => self.write_report(stats_json),
+            None => Ok(())
+        }
+    }
+
+    fn modify_buffered(&self, path: &Path, temp_path: &Path, meta: Option<&Meta>) -> crate::FResult<Option<Modified>> {
+        /*
+            * Modify the Given File in Memory into the Given Temporary File
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file to encrypt or decrypt
+            @param temp_path: &Path
+                * The temporary file to write the output to
+            @param meta: Option<&Meta>
+                * The sidecar of the file when decrypting, if it has one
+            @return FResult: Result<Option<Modified>, Box<dyn Error>>
+                * What was read and written, None if the file was skipped
+        */
+        /* Read the whole file */
+        let data = self.retry("read", path, || Ok(fs::read(path)?))?;
+        let read = data.len();
+
+        /* Armored or base64url files are decoded back to the header and ciphertext */
+        let data = match self.crypt && !self.migrate {
+            true => Cow::Borrowed(data.as_slice()),
+            false => encoding::decode(&data)?
+        };
+
+        /* Migrating decrypts a legacy file here, the plaintext is then encrypted as usual */
+        let data = match self.migrate {
+            true => {
+                if Header::parse(&data)?.is_some() {
+                    if self.verbose {
+                        println!("Skipping {:?}, it already has a header", self.log_path(path));
+                    }
+                    self.skipped.fetch_add(1, Ordering::SeqCst);
+                    return Ok(None);
+                }
+
+                let cipher = self.cipher_for(path);
+                if !data.len().is_multiple_of(cipher.block_size) {
+                    return Err(format!("Block size mismatch: {:?} is not a multiple of {} bytes", path, cipher.block_size).into());
+                }
+                Cow::Owned(self.transform(cipher, &data, false, Mode::Ecb, &[], None)?)
+            }
+            false => data
+        };
+
+        /* Encrypt or decrypt the file contents */
+        let (output, (algorithm, block_size), permissions) = match self.crypt {
+            true => {
+                let (output, used) = self.encrypt(path, &data)?;
+                (self.output_format.encode(output), used, 0)
+            }
+            false => self.decrypt(path, &data, meta)?
+        };
+
+        self.write_temp(path, temp_path, |file| Ok(file.write_all(&output)?))?;
+
+        Ok(Some(Modified {
+            algorithm,
+            block_size,
+            original_size: match self.crypt {
+                true => data.len(),
+                false => output.len()
+            } as u64,
+            permissions,
+            read,
+            written: output.len(),
+            header: None,
+        }))
+    }
+
+    fn encrypt_stream(&self, path: &Path, temp_path: &Path) -> crate::FResult<Modified> {
+        /*
+            * Encrypt the Given File into the Given Temporary File a Chunk at a Time
+
+            * Writes the same header and ciphertext as encrypt() without any output format or parity,
+              the MAC is filled into the header once the whole ciphertext was written
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file to encrypt
+            @param temp_path: &Path
+                * The temporary file to write the header and ciphertext to
+            @return FResult: Result<Modified, Box<dyn Error>>
+        */
+        let cipher = self.cipher_for(path);
+
+        /* Same per-file salt, tweak and IV as encrypt(), --deterministic reads the file an extra time for them */
+        let (salt, iv, tweak) = self.file_params(cipher, self.mode, File::open(path)?)?;
+        let master_salt = self.run_master_salt();
+        let keyed = self.keyed(cipher.algorithm, cipher.block_size, self.kdf, &salt, master_salt.as_ref(), &tweak)?;
+        let mac_key = keyed.mac_key.as_ref().ok_or("Missing MAC key")?;
+
+        let metadata = fs::metadata(path)?;
+        let size = metadata.len();
+        let header = Header::new(cipher.algorithm, cipher.block_size, size)
+            .with_mode(self.mode, iv.clone())
+            .with_kdf(self.kdf, salt, master_salt)
+            .with_permissions(mode_bits(&metadata))
+            .with_tweak(tweak)
+            .with_mac_algorithm(self.mac);
+        let header = self.bind(header, path)?;
+
+        let bar = self.file_bar(path, size);
+        let written = self.write_temp(path, temp_path, |file| {
+            let mut header = header.clone();
+            let mut mac = header.begin_mac(mac_key.as_slice())?;
+            let mut iv = iv.clone();
+
+            /* The MAC is still zero here, the header is written again below, or to the sidecar with --length-preserving */
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut writer = BufWriter::new(&mut *file);
+            let mut written = 0;
+            if !self.length_preserving {
+                writer.write_all(&header.to_bytes()?)?;
+                written = header.size();
+            }
+
+            let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+            let mut remaining = size;
+            loop {
+                let len = STREAM_CHUNK_SIZE.min(remaining as usize);
+                reader.read_exact(&mut chunk[..len])?;
+                remaining -= len as u64;
+                if let Some(bar) = &bar {
+                    bar.set_position(size - remaining);
+                }
+
+                /* The last chunk (possibly empty) gets the PKCS#7 padding, CTR needs none */
+                let last = remaining == 0;
+                let padded = match last && self.mode != Mode::Ctr {
+                    true => Cow::Owned(blocks::pad(&chunk[..len], cipher.block_size)),
+                    false => Cow::Borrowed(&chunk[..len])
+                };
+                let ciphertext = self.transform(&keyed, &padded, true, self.mode, &iv, None)?;
+                mac.update(&ciphertext);
+                writer.write_all(&ciphertext)?;
+                written += ciphertext.len();
+
+                if last {
+                    break;
+                }
+                next_iv(&mut iv, self.mode, &ciphertext);
+            }
+            writer.flush()?;
+            drop(writer);
+
+            header.finish_mac(mac);
+            if self.length_preserving {
+                return Ok((written, Some(header.to_bytes()?)));
+            }
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&header.to_bytes()?)?;
+            Ok((written, None))
+        });
+        if let Some(bar) = bar {
+            bar.finish_and_clear();
+        }
+        let (written, header) = written?;
+
+        Ok(Modified {
+            algorithm: cipher.algorithm,
+            block_size: cipher.block_size,
+            original_size: size,
+            permissions: 0,
+            read: size as usize,
+            written,
+            header,
+        })
+    }
+
+    fn stream_header(&self, path: &Path) -> crate::FResult<Option<Header>> {
+        /*
+            * Read the Header of a File that can be Decrypted a Chunk at a Time
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file to decrypt
+            @return FResult: Result<Option<Header>, Box<dyn Error>>
+                * The header of a raw (no output format) headered file without parity or compression, otherwise None
+        */
+        if !self.header || self.migrate {
+            return Ok(None);
+        }
+
+        let mut start = Vec::with_capacity(header::MAX_SIZE);
+        File::open(path)?.take(header::MAX_SIZE as u64).read_to_end(&mut start)?;
+        Ok(Header::parse(&start)?.filter(|header| header.ecc == 0 && header.flags & FLAG_COMPRESSED == 0))
+    }
+
+    fn decrypt_stream(&self, path: &Path, temp_path: &Path, header: &Header, offset: u64) -> crate::FResult<Modified> {
+        /*
+            * Decrypt the Given File into the Given Temporary File a Chunk at a Time
+
+            * The plaintext only reaches the temporary file before the MAC is checked, a file failing the
+              check is never renamed over the original
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file to decrypt
+            @param temp_path: &Path
+                * The temporary file to write the plaintext to
+            @param header: &Header
+                * The header of the file, see stream_header(), or from its sidecar (see Meta::header())
+            @param offset: u64
+                * Where the ciphertext starts, the size of the header, 0 when it is kept in the sidecar
+            @return FResult: Result<Modified, Box<dyn Error>>
+        */
+        self.check_extension(path, header)?;
+        let mut header = header.clone();
+        self.associate(&mut header, path)?;
+        let read = fs::metadata(path)?.len();
+        let size = read - offset;
+        let pkcs7 = header.flags & FLAG_PKCS7 != 0;
+
+        let cipher = self.keyed(header.algorithm, header.block_size, header.kdf, &header.salt, header.master_salt.as_ref(),
+                                &header.tweak)?;
+        let mac_key = cipher.mac_key.as_ref().ok_or("Missing MAC key")?;
+        check_size(path, cipher.block_size, header.mode, pkcs7, size, Some((header.original_size, "header")))?;
+
+        let bar = self.file_bar(path, size);
+        let written = self.write_temp(path, temp_path, |file| {
+            let mut mac = header.mac_state(mac_key.as_slice())?;
+            let mut iv = header.iv.clone();
+
+            let mut input = File::open(path)?;
+            input.seek(SeekFrom::Start(offset))?;
+            let mut reader = BufReader::new(input);
+            /* Zero runs of the plaintext (e.g. of a disk image) become holes again */
+            let mut writer = SparseWriter::new(BufWriter::new(&mut *file));
+
+            let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+            let (mut remaining, mut written) = (size, 0u64);
+            let mut padding = true;
+            while remaining > 0 {
+                let len = STREAM_CHUNK_SIZE.min(remaining as usize);
+                reader.read_exact(&mut chunk[..len])?;
+                remaining -= len as u64;
+                if let Some(bar) = &bar {
+                    bar.set_position(size - remaining);
+                }
+
+                mac.update(&chunk[..len]);
+                let mut plaintext = self.transform(&cipher, &chunk[..len], false, header.mode, &iv, Some(len))?;
+                next_iv(&mut iv, header.mode, &chunk[..len]);
+
+                /* The padding must be intact and end the plaintext exactly at the recorded size */
+                if remaining == 0 && pkcs7 {
+                    padding = blocks::unpad(&mut plaintext, cipher.block_size).is_ok()
+                        && written + plaintext.len() as u64 == header.original_size;
+                }
+                plaintext.truncate(header.original_size.saturating_sub(written) as usize);
+                writer.write_all(&plaintext)?;
+                written += plaintext.len() as u64;
+            }
+            writer.finish()?.flush()?;
+
+            if !header.mac_matches(mac) {
+                return Err(format!("Authentication failed for {:?}, wrong password or corrupted file", path).into());
+            }
+            if !padding {
+                return Err(format!("Invalid padding in {:?}, wrong password or corrupted file", path).into());
+            }
+            Ok(written)
+        });
+        if let Some(bar) = bar {
+            bar.finish_and_clear();
+        }
+        let written = written?;
+
+        Ok(Modified {
+            algorithm: cipher.algorithm,
+            block_size: cipher.block_size,
+            original_size: written,
+            permissions: header.permissions,
+            read: read as usize,
+            written: written as usize,
+            header: None,
+        })
+    }
+
+    pub(crate) fn encrypt_bytes(&self, path: &Path, data: &[u8]) -> crate::FResult<Vec<u8>> {
+        /*
+            * Encrypt the Contents of the Given File in Memory
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file the data belongs to, selects the algorithm (see cipher_for())
+            @param data: &[u8]
+                * The plaintext
+            @return FResult: Result<Vec<u8>, Box<dyn Error>>
+                * The header (unless --no-header) followed by the ciphertext, before any output format
+        */
+        self.encrypt(path, data).map(|(output, _)| output)
+    }
+
+    fn encrypt(&self, path: &Path, data: &[u8]) -> crate::FResult<(Vec<u8>, (Algorithm, usize))> {
+        /*
+            * Encrypt the Contents of the Given File in Memory, see encrypt_bytes()
+
+            @return FResult: Result<(Vec<u8>, (Algorithm, usize)), Box<dyn Error>>
+                * The header and ciphertext, and the algorithm and block size that encrypted them
+        */
+        let cipher = self.cipher_for(path);
+
+        /* Ciphertext doesn't compress, so compress first if requested. Only the header can record it */
+        let compressed = match self.compress && self.header {
+            true => compression::compress(data)?,
+            false => None
+        };
+        let plaintext = compressed.as_deref().unwrap_or(data);
+
+        /* Headerless files have nowhere to keep an IV, they are always ECB */
+        let mode = match self.header {
+            true => self.mode,
+            false => Mode::Ecb
+        };
+
+        /* Headered files are encrypted with a key of their own, derived with a random salt, a random tweak and IV */
+        let (salt, iv, tweak) = match self.header {
+            true => self.file_params(cipher, mode, plaintext)?,
+            false => ([0; SALT_SIZE], Vec::new(), [0; TWEAK_SIZE])
+        };
+        let master_salt = self.run_master_salt();
+        let salted;
+        let keyed = match self.header {
+            true => {
+                salted = self.keyed(cipher.algorithm, cipher.block_size, self.kdf, &salt, master_salt.as_ref(), &tweak)?;
+                &salted
+            }
+            false => cipher
+        };
+
+        /* The header's original size makes PKCS#7 padding checkable, headerless files stay zero padded, CTR needs none */
+        let padded = match self.header && mode != Mode::Ctr {
+            true => Cow::Owned(blocks::pad(plaintext, cipher.block_size)),
+            false => Cow::Borrowed(plaintext)
+        };
+        let ciphertext = self.transform(keyed, &padded, true, mode, &iv, None)?;
+
+        /* Put the header, authenticating itself and the ciphertext, in front of the ciphertext */
+        let mut output = match &keyed.mac_key {
+            Some(mac_key) => self.bind(Header::new(cipher.algorithm, cipher.block_size, plaintext.len() as u64)
+                .with_mode(mode, iv.clone())
+                .with_kdf(self.kdf, salt, master_salt)
+                .with_ecc(self.ecc)
+                .with_permissions(fs::metadata(path).map_or(0, |metadata| mode_bits(&metadata)))
+                .with_compression(compressed.is_some())
+                .with_tweak(tweak)
+                .with_mac_algorithm(self.mac), path)?
+                .with_mac(mac_key.as_slice(), &ciphertext)?
+                .to_bytes()?,
+            None => Vec::new()
+        };
+        match self.ecc {
+            0 => output.extend_from_slice(&ciphertext),
+            parity => output.extend_from_slice(&ecc::encode(&ciphertext, parity))
+        }
+
+        /* Make sure the encrypted output decrypts back to the original before touching the file */
+        if self.verify {
+            if self.verbose {
+                println!("Verifying {:?}", self.log_path(path));
+            }
+            let (header_size, parity) = match self.header {
+                true => {
+                    let header = Header::parse(&output)?.ok_or("Missing header")?;
+                    (header.size(), header.ecc)
+                }
+                false => (0, 0)
+            };
+            let ciphertext = match parity {
+                0 => Cow::Borrowed(&output[header_size..]),
+                parity => Cow::Owned(ecc::decode(&output[header_size..], parity)?.0)
+            };
+            if self.transform(keyed, &ciphertext, false, mode, &iv, Some(plaintext.len()))? != plaintext {
+                return Err(format!("Verification failed for {:?}, file left untouched", path).into());
+            }
+        }
+
+        Ok((output, (cipher.algorithm, cipher.block_size)))
+    }
+
+    pub(crate) fn decrypt_bytes(&self, path: &Path, data: &[u8], meta: Option<&Meta>) -> crate::FResult<Vec<u8>> {
+        /*
+            * Decrypt the Contents of the Given File in Memory
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file the data belongs to, selects the algorithm of headerless data (see cipher_for())
+            @param data: &[u8]
+                * The header (if any) followed by the ciphertext, output formats already decoded
+            @param meta: Option<&Meta>
+                * The sidecar of the file, if it has one
+            @return FResult: Result<Vec<u8>, Box<dyn Error>>
+                * The plaintext
+        */
+        self.decrypt(path, data, meta).map(|(output, _, _)| output)
+    }
+
+    fn decrypt(&self, path: &Path, data: &[u8], meta: Option<&Meta>) -> crate::FResult<(Vec<u8>, (Algorithm, usize), u32)> {
+        /*
+            * Decrypt the Contents of the Given File in Memory, see decrypt_bytes()
+
+            @return FResult: Result<(Vec<u8>, (Algorithm, usize), u32), Box<dyn Error>>
+                * The plaintext, the algorithm and block size that decrypted it, and the mode bits recorded
+                  in its header (0 for none)
+        */
+
+        /*
+            * Encrypted files start with a header recording how they were encrypted, legacy files have none
+            * Length-preserving files keep theirs in the sidecar
+        */
+        let sidecar = match self.header {
+            true => meta.map_or(Ok(None), Meta::header)?,
+            false => None
+        };
+        let (mut header, ciphertext) = match sidecar {
+            Some(header) => (Some(header), data),
+            None if self.header => match Header::parse(data)? {
+                Some(header) => {
+                    let size = header.size();
+                    (Some(header), &data[size..])
+                }
+                None => (None, data)
+            },
+            None => (None, data)
+        };
+        if let Some(header) = &mut header {
+            self.associate(header, path)?;
+        }
+
+        /* Correct corrupted bytes with the Reed-Solomon parity, then drop it */
+        let ciphertext = match header.as_ref().map(|header| header.ecc).unwrap_or(0) {
+            0 => Cow::Borrowed(ciphertext),
+            parity => {
+                let (ciphertext, corrected) = ecc::decode(ciphertext, parity)
+                    .map_err(|err| format!("Could not correct {:?}: {}", path, err))?;
+                if corrected > 0 {
+                    println!("Corrected {} corrupted byte(s) in {:?}", corrected, self.log_path(path));
+                }
+                Cow::Owned(ciphertext)
+            }
+        };
+
+        /* CBC and CTR files are decrypted with the IV from their header, headerless files are ECB */
+        let (mode, iv) = match &header {
+            Some(header) => (header.mode, header.iv.as_slice()),
+            None => (Mode::Ecb, &[][..])
+        };
+
+        /* Files written since the header gained FLAG_PKCS7 always carry at least one byte of padding */
+        let pkcs7 = header.as_ref().is_some_and(|header| header.flags & FLAG_PKCS7 != 0);
+
+        /*
+            * Files recording how they were encrypted (header or sidecar) are decrypted with a cipher made
+              for them, whatever algorithm was configured. Only raw files take the configured one
+        */
+        let keyed;
+        let (cipher, recorded) = match (&header, meta) {
+            (Some(header), _) => {
+                self.check_extension(path, header)?;
+                keyed = self.keyed(header.algorithm, header.block_size, header.kdf, &header.salt, header.master_salt.as_ref(),
+                                   &header.tweak)?;
+                (&keyed, Some((header.original_size, "header")))
+            }
+            (None, Some(meta)) => {
+                keyed = self.keyed(meta.algorithm, meta.block_size, self.kdf.unsalted(), &[], None, &[0; TWEAK_SIZE])?;
+                (&keyed, Some((meta.original_size, "sidecar")))
+            }
+            (None, None) => (self.cipher_for(path), None)
+        };
+        /* Decrypting something that was never encrypted destroys it, only do so on request */
+        if recorded.is_none() && self.header && !self.force {
+            return Err(format!("{:?} does not appear to be fisher-encrypted (pass --force for legacy or raw files)", path).into());
+        }
+
+        check_size(path, cipher.block_size, mode, pkcs7, ciphertext.len() as u64, recorded)?;
+
+        /* Authenticated files are only decrypted if neither the header nor the ciphertext changed */
+        if let (Some(header), Some(mac_key)) = (&header, &cipher.mac_key) {
+            if !header.verify_mac(mac_key.as_slice(), &ciphertext)? {
+                return Err(format!("Authentication failed for {:?}, wrong password or corrupted file", path).into());
+            }
+        }
+
+        let original_size = recorded.map(|(original_size, _)| original_size as usize);
+        let mut output = match pkcs7 {
+            true => self.transform(cipher, &ciphertext, false, mode, iv, Some(ciphertext.len()))?,
+            false => self.transform(cipher, &ciphertext, false, mode, iv, original_size)?
+        };
+
+        /* The padding must be intact and end the plaintext exactly at the recorded size */
+        if pkcs7 && (blocks::unpad(&mut output, cipher.block_size).is_err() || Some(output.len()) != original_size) {
+            return Err(format!("Invalid padding in {:?}, wrong password or corrupted file", path).into());
+        }
+
+        /* Compressed files are decompressed once authenticated and unpadded */
+        if header.as_ref().is_some_and(|header| header.flags & FLAG_COMPRESSED != 0) {
+            output = compression::decompress(&output).map_err(|err| format!("{:?}: {}", path, err))?;
+        }
+        Ok((output, (cipher.algorithm, cipher.block_size), header.map_or(0, |header| header.permissions)))
+    }
+
+    fn record_file(&self, algorithm: Algorithm, block_size: usize, read: usize, written: usize) {
+        /*
+            * Count a Successfully Modified File in the Run Report
+
+            @param self: Fisher Instance
+            @param algorithm: Algorithm
+                * The algorithm the file was modified with
+            @param block_size: usize
+                * The block size in bytes the file was modified with
+            @param read: usize
+                * The number of bytes read from the file
+            @param written: usize
+                * The number of bytes written to the file
+        */
+        let name = ALGORITHMS.iter()
+            .find(|info| info.algorithm == algorithm && info.block_size == block_size)
+            .map(|info| info.name.to_string())
+            .unwrap_or_else(|| format!("{:?}", algorithm));
+
+        let mut report = self.report.lock().unwrap();
+        report.files += 1;
+        report.bytes_read += read as u64;
+        report.bytes_written += written as u64;
+        *report.algorithms.entry(name).or_insert(0) += 1;
+    }
+
+    fn record_failure(&self, path: &Path, err: Box<dyn Error>) -> Box<dyn Error> {
+        /*
+            * Add a File that Could not be Modified to the Run Report
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file (or directory that could not be read)
+            @param err: Box<dyn Error>
+                * The reason
+            @return Box<dyn Error>: The same error, to be passed on
+        */
+        self.report.lock().unwrap().failures.push(Failure {
+            path: self.log_path(path),
+            error: err.to_string(),
+        });
+        err
+    }
+
+    fn write_report(&self, path: &Path) -> crate::FResult<()> {
+        /*
+            * Finish the Run Report and Write it to the Given File
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The --stats-json file
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+        let mut report = self.report.lock().unwrap();
+        report.duration_secs = self.started.elapsed().as_secs_f64();
+        report.interrupted = self.stopped();
+        report.write(path).map_err(|err| format!("Could not write stats to {:?}: {}", path, err).into())
+    }
+
+    fn keyed(&self, algorithm: Algorithm, block_size: usize, kdf: Kdf, salt: &[u8], master_salt: Option<&[u8; SALT_SIZE]>,
+             tweak: &[u8; TWEAK_SIZE]) -> crate::FResult<Cipher> {
+        /*
+            * Create a Cipher for a Single File
+
+            @param self: Fisher Instance
+            @param algorithm: Algorithm
+                * The algorithm of the file
+            @param block_size: usize
+                * The block size in bytes of the file
+            @param kdf: Kdf
+                * The key derivation function and parameters of the file
+            @param salt: &[u8]
+                * The salt of the file, empty for Kdf::Sha2
+            @param master_salt: Option<&[u8; SALT_SIZE]>
+                * The salt of the run the file was encrypted in, if its key was derived from the master key
+                  of the run, see master()
+            @param tweak: &[u8; TWEAK_SIZE]
+                * The Threefish tweak of the file, zero for none
+            @return FResult: Result<Cipher, Box<dyn Error>>
+                * The cipher keyed with the KDF of the passphrase and salt, with the HMAC key derived from it
+        */
+        let key = match master_salt {
+            Some(master_salt) => subkey(algorithm, block_size, &self.master(kdf, master_salt)?, salt)?,
+            None => kdf.derive(algorithm, block_size, &self.passphrase, salt)?
+        };
+        Ok(Cipher {
+            algorithm,
+            block_size,
+            fisher: new_cipher(algorithm, block_size, &key, tweak)?,
+            mac_key: Some(mac_key(&key)),
+        })
+    }
+
+    fn master(&self, kdf: Kdf, master_salt: &[u8; SALT_SIZE]) -> crate::FResult<Zeroizing<Vec<u8>>> {
+        /*
+            * Get the Master Key of a Run, Stretched From the Passphrase the First Time it is Needed
+
+            * The lock is held while stretching, so threads needing the same master key wait for it
+              instead of stretching it again
+
+            @param self: Fisher Instance
+            @param kdf: Kdf
+                * The KDF and parameters the master key is stretched with
+            @param master_salt: &[u8; SALT_SIZE]
+                * The salt of the run
+            @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+                * The master key, see subkey()
+        */
+        let mut masters = self.masters.lock().unwrap();
+        if let Some(master) = masters.get(&(kdf, *master_salt)) {
+            return Ok(master.clone());
+        }
+        let master = kdf.stretch(&self.passphrase, master_salt, MASTER_KEY_SIZE)?;
+        masters.insert((kdf, *master_salt), master.clone());
+        Ok(master)
+    }
+
+    fn run_master_salt(&self) -> Option<[u8; SALT_SIZE]> {
+        /*
+            * Get the Salt of this Run, if Files are Keyed From its Master Key
+
+            @param self: Fisher Instance
+            @return Option<[u8; SALT_SIZE]>
+                * The salt, None when the KDF doesn't stretch (see Kdf::stretching()) or a --salt is given,
+                  and it keys the files itself
+        */
+        match self.kdf.stretching() && self.salt.is_none() {
+            true => Some(self.master_salt),
+            false => None
+        }
+    }
+
+    fn file_params(&self, cipher: &Cipher, mode: Mode, mut plaintext: impl Read)
+        -> crate::FResult<([u8; SALT_SIZE], Vec<u8>, [u8; TWEAK_SIZE])> {
+        /*
+            * Draw the Salt, IV and Tweak of a File About to be Encrypted
+
+            * Random, or with --deterministic derived from the plaintext with HKDF keyed by the password,
+              so the same file encrypts to the same bytes and different files still differ
+
+            @param self: Fisher Instance
+            @param cipher: &Cipher
+                * The cipher the file is encrypted with
+            @param mode: Mode
+                * How the blocks are chained
+            @param plaintext: impl Read
+                * The plaintext, only read with --deterministic
+            @return FResult: Result<([u8; SALT_SIZE], Vec<u8>, [u8; TWEAK_SIZE]), Box<dyn Error>>
+                * The salt (--salt if given), the IV (one block, empty for ECB) and the tweak (zero except for Threefish)
+        */
+        let mut salt = [0u8; SALT_SIZE];
+        let mut iv = match mode {
+            Mode::Ecb => Vec::new(),
+            _ => vec![0u8; cipher.block_size]
+        };
+        let mut tweak = [0u8; TWEAK_SIZE];
+
+        match self.deterministic {
+            true => {
+                let mut extract = hkdf::HkdfExtract::<Sha256>::new(Some(&self.deterministic_key()?));
+                let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+                loop {
+                    match plaintext.read(&mut chunk)? {
+                        0 => break,
+                        len => extract.input_ikm(&chunk[..len])
+                    }
+                }
+                let (_, hkdf) = extract.finalize();
+                hkdf.expand(b"fisher salt", &mut salt).map_err(|_| "Salt too long for HKDF")?;
+                hkdf.expand(b"fisher iv", &mut iv).map_err(|_| "IV too long for HKDF")?;
+                if cipher.algorithm == Algorithm::Threefish {
+                    hkdf.expand(b"fisher tweak", &mut tweak).map_err(|_| "Tweak too long for HKDF")?;
+                }
+            }
+            false => {
+                rand::thread_rng().fill_bytes(&mut salt);
+                rand::thread_rng().fill_bytes(&mut iv);
+                tweak = random_tweak(cipher.algorithm);
+            }
+        }
+
+        Ok((self.salt.unwrap_or(salt), iv, tweak))
+    }
+
+    fn deterministic_key(&self) -> crate::FResult<Zeroizing<Vec<u8>>> {
+        /*
+            * Get the Key the Salt, IV and Tweak of a File are Derived With, see --deterministic
+
+            * Derived from the passphrase stretched with the fixed DETERMINISTIC_SALT, the master key of the run
+              unless --salt is given, or from the passphrase itself for KDFs that don't stretch (--raw-key)
+
+            @param self: Fisher Instance
+            @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+        */
+        let secret = match self.kdf.stretching() {
+            true => self.master(self.kdf, &DETERMINISTIC_SALT)?,
+            false => self.passphrase.clone()
+        };
+        let mut key = Zeroizing::new(vec![0u8; MASTER_KEY_SIZE]);
+        hkdf::Hkdf::<Sha256>::new(None, &secret)
+            .expand(b"fisher deterministic", &mut key)
+            .map_err(|_| "Key too long for HKDF")?;
+        Ok(key)
+    }
+
+    fn cipher_for(&self, path: &Path) -> &Cipher {
+        /*
+            * Get the Cipher to Encrypt (or Decrypt Headerless) the Given File With
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file being modified
+            @return &Cipher
+                * The cipher of the first algorithm map pattern matching the path, otherwise the default
+        */
+        self.algorithm_map.iter()
+            .find(|(glob, _)| glob.is_match(path))
+            .map(|(_, cipher)| cipher)
+            .unwrap_or(&self.cipher)
+    }
+
+    fn modify_device(&self, path: &Path) -> crate::FResult<()> {
+        /*
+            * Modify [Encrypt or Decrypt] the Given Device in Place
+
+            * The device is read and overwritten DEVICE_CHUNK_SIZE bytes at a time, without a header,
+              padding or truncation, so the ciphertext has exactly the size of the device
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The block or character device to encrypt or decrypt
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+        if !self.device {
+            return Err(format!("{:?} is a device, pass --device to modify it in place", path).into());
+        }
+        if self.dry_run {
+            println!("Would {} device {:?} in place", self.operation(), self.log_path(path));
+            self.processed.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
+
+        let mut device = OpenOptions::new().read(true).write(true).open(path)?;
+        let cipher = self.cipher_for(path);
+        let size = device.seek(SeekFrom::End(0))?;
+        if !size.is_multiple_of(cipher.block_size as u64) {
+            return Err(format!("Device {:?} is not a multiple of {} bytes", path, cipher.block_size).into());
+        }
+
+        let bar = self.file_bar(path, size);
+        let mut chunk = vec![0u8; DEVICE_CHUNK_SIZE];
+        let mut offset: u64 = 0;
+        while offset < size {
+            let len = DEVICE_CHUNK_SIZE.min((size - offset) as usize);
+
+            device.seek(SeekFrom::Start(offset))?;
+            device.read_exact(&mut chunk[..len])?;
+
+            /* Whole blocks in, whole blocks out, so the output overwrites exactly what was read */
+            let output = self.transform(cipher, &chunk[..len], self.crypt, Mode::Ecb, &[], Some(len))?;
+            if self.verify && self.crypt && self.transform(cipher, &output, false, Mode::Ecb, &[], Some(len))? != chunk[..len] {
+                return Err(format!("Verification failed for {:?} at byte {}, the device was modified up to there",
+                                   path, offset).into());
+            }
+
+            device.seek(SeekFrom::Start(offset))?;
+            device.write_all(&output)?;
+            offset += len as u64;
+            if let Some(bar) = &bar {
+                bar.set_position(offset);
+            }
+        }
+        if let Some(bar) = bar {
+            bar.finish_and_clear();
+        }
+        device.sync_all()?;
+
+        self.record_file(cipher.algorithm, cipher.block_size, size as usize, size as usize);
+        self.processed.fetch_add(1, Ordering::SeqCst);
+        if let Some(progress) = &self.progress {
+            progress.file_done();
+        }
+
+        Ok(())
+    }
+
+    fn transform(&self, cipher: &Cipher, data: &[u8], crypt: bool, mode: Mode, iv: &[u8], original_size: Option<usize>)
+        -> crate::FResult<Vec<u8>> {
+        /*
+            * Encrypt or Decrypt the Given Bytes
+
+            @param self: Fisher Instance
+            @param cipher: &Cipher
+                * The cipher to modify the bytes with
+            @param data: &[u8]
+                * The bytes to encrypt or decrypt
+            @param crypt: bool
+                * Whether to encrypt or decrypt
+            @param mode: Mode
+                * How the blocks are chained
+            @param iv: &[u8]
+                * The IV (CBC) or nonce (CTR) of the file, one block long, empty for ECB
+            @param original_size: Option<usize>
+                * The exact plaintext length when decrypting, if known
+            @return FResult: Result<Vec<u8>, Box<dyn Error>>
+                * The modified bytes, with the padding of the last block cleared when decrypting
+        */
+        /* CTR is a stream, the output is exactly as long as the input */
+        if mode == Mode::Ctr {
+            return blocks::ctr(&cipher.fisher, cipher.block_size, iv, data);
+        }
+
+        let mut output: Vec<u8> = Vec::with_capacity(data.len() + cipher.block_size);
+
+        let blocks = data.chunks(cipher.block_size).map(<[u8]>::to_vec).blocks(&cipher.fisher, cipher.block_size, crypt);
+        let blocks = match mode {
+            Mode::Cbc => blocks.cbc(iv),
+            _ => blocks
+        };
+        for blocks in blocks {
+            /* Push the modified blocks to the output */
+            output.extend_from_slice(&blocks?);
+        }
+
+        /* Last decrypted block, clear padding (ciphertext always keeps whole blocks) */
+        if let (false, Some(original_size)) = (crypt, original_size) {
+            output.truncate(original_size);
+        } else if !crypt {
+            let last_block = output.len().saturating_sub(cipher.block_size);
+            let padding = output[last_block..].iter().rev().take_while(|byte| **byte == 0).count();
+            output.truncate(output.len() - padding);
+        }
+
+        Ok(output)
+    }
+
+    fn write_temp<T>(&self, path: &Path, temp_path: &Path, mut write: impl FnMut(&mut File) -> crate::FResult<T>)
+        -> crate::FResult<T> {
+        /*
+            * Write the Output for the Given File to a Temporary File
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file being modified, the temporary file gets its permissions and modification time
+            @param temp_path: &Path
+                * The temporary file to write, renamed over the file by the caller
+            @param write: impl FnMut(&mut File) -> FResult<T>
+                * Writes the output to the created temporary file, repeated on transient errors
+            @return FResult: Result<T, Box<dyn Error>>
+                * What write returned
+                * On errors (disk full, device removed) the partial temporary file is removed
+        */
+        /* Write the output, keeping the permissions and modification time of the original */
+        let metadata = fs::metadata(path)?;
+        let written = self.retry("write", temp_path, || {
+            let mut file = File::create(temp_path)?;
+            let result = write(&mut file)?;
+            if let Ok(modified) = metadata.modified() {
+                file.set_modified(modified)?;
+            }
+            file.set_permissions(metadata.permissions())?;
+            Ok((file, result))
+        }).and_then(|(file, result)| {
+            /* Keep the extended attributes of the original if requested */
+            if self.xattrs {
+                copy_xattrs(path, temp_path);
+            }
+
+            /* Flush the written blocks to disk before the rename if requested */
+            if self.sync != SyncMode::Off {
+                self.retry("sync", temp_path, || Ok(file.sync_all()?))?;
+            }
+            Ok(result)
+        });
+
+        if written.is_err() {
+            /* Ignoring errors, the temp file may not have been created */
+            let _ = fs::remove_file(temp_path);
+        }
+        written
+    }
+
+    fn retry<T>(&self, operation: &str, path: &Path, mut op: impl FnMut() -> crate::FResult<T>) -> crate::FResult<T> {
+        /*
+            * Run an IO Operation, Retrying it with Backoff on Transient Errors
+
+            * Network filesystems (NFS, SMB) can fail with errors that go away on their own
+
+            @param self: Fisher Instance
+            @param operation: &str
+                * Name of the operation, for verbose output
+            @param path: &Path
+                * The path the operation works on, for verbose output
+            @param op: impl FnMut() -> FResult<T>
+                * The operation to run, must be safe to repeat
+            @return FResult: Result<T, Box<dyn Error>>
+                * The result of the first successful attempt or the last error
+        */
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(err) if attempt < self.io_retries && err.downcast_ref().is_some_and(is_transient) => {
+                    /* Back off exponentially, starting at 100ms */
+                    let delay = Duration::from_millis(100 << attempt.min(6));
+                    attempt += 1;
+                    if self.verbose {
+                        println!("Retrying {} of {:?} in {:?} ({}/{}): {}", operation, self.log_path(path), delay, attempt, self.io_retries, err);
+                    }
+                    std::thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn output_path(&self, path: &Path) -> PathBuf {
+        /*
+            * Get the Path the Given File is Written to
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file being modified
+            @return PathBuf
+                * The path, or with --output the same path below the output directory (or the output file)
+                * Encrypting: with .fish, or with --algorithm-extension the extension of its algorithm (e.g. .tw),
+                  appended to its full file name
+                * Decrypting: with a trailing .fish or algorithm extension removed, restoring the original name
+                * The name is kept with --keep-name, when decrypting a file with neither extension and for an output file
+        */
+        let path = match &self.destination {
+            Destination::InPlace => path.to_path_buf(),
+            Destination::Dir(output, _) => output.join(self.relative_path(path)),
+            Destination::File(output) => return output.clone()
+        };
+        if self.keep_name {
+            return path;
+        }
+        match self.crypt {
+            true => {
+                let cipher = self.cipher_for(&path);
+                let mut output = path.as_os_str().to_owned();
+                output.push(".");
+                output.push(match self.algorithm_extension {
+                    true => algorithm_info(cipher.algorithm, cipher.block_size).map_or(ENCRYPTED_EXTENSION, |info| info.extension),
+                    false => ENCRYPTED_EXTENSION
+                });
+                PathBuf::from(output)
+            }
+            false => match has_encrypted_extension(&path) {
+                true => path.with_extension(""),
+                false => path
+            }
+        }
+    }
+
+    fn bind(&self, header: Header, path: &Path) -> crate::FResult<Header> {
+        /*
+            * Bind --aad and, unless --no-bind-path, the Name of the Given File to the Header of its Encryption
+
+            * With --relative-to the name is the path of the file below the root, see bound_name()
+
+            * Call before the MAC is computed, see Header::with_associated_data()
+
+            @param self: Fisher Instance
+            @param header: Header
+                * The header of the file being encrypted
+            @param path: &Path
+                * The file being encrypted
+            @return FResult: Result<Header, Box<dyn Error>>
+                * The header, at the version recording what it is bound to
+                * An error if the file is not below --relative-to
+        */
+        let name = match self.bind_path {
+            true => self.bound_name(path, self.relative_to.as_deref())?,
+            false => None
+        };
+        Ok(header.with_associated_data(self.aad.as_deref(), name.as_deref(), self.relative_to.is_some()))
+    }
+
+    fn associate(&self, header: &mut Header, path: &Path) -> crate::FResult<()> {
+        /*
+            * Supply the Associated Data an Encrypted File was Bound to Before its MAC is Checked
+
+            * The name is bound if the header says so (see FLAG_PATH), whatever --no-bind-path says, and
+              its path below --relative-to if it was bound below a root (see FLAG_RELATIVE)
+
+            @param self: Fisher Instance
+            @param header: &mut Header
+                * The header of the file
+            @param path: &Path
+                * The file being decrypted, or checked after encrypting it (see --verify)
+            @return FResult: Result<(), Box<dyn Error>>
+                * An error if --aad or --relative-to is missing for the file, --aad is given for a file
+                  without or the file is not below --relative-to
+        */
+        let root = match header.flags & FLAG_RELATIVE != 0 {
+            true => Some(self.relative_to.as_deref().ok_or(format!(
+                "Could not authenticate {:?}, it is bound to its path below a directory, pass that directory with --relative-to", path))?),
+            false => None
+        };
+        header.associate(self.aad.as_deref(), self.bound_name(path, root)?.as_deref())
+            .map_err(|err| format!("Could not authenticate {:?}, {}", path, err).into())
+    }
+
+    fn bound_name(&self, path: &Path, root: Option<&Path>) -> crate::FResult<Option<Vec<u8>>> {
+        /*
+            * Get the Name the Given File is Bound to, see bound_name()
+
+            * Encrypting, the file name is that of the file written (see output_path()), which only differs
+              from the plaintext when writing to another output file. The directories are those of the file
+              being modified, in both directions
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file being modified
+            @param root: Option<&Path>
+                * The canonical --relative-to root to bind the path below, None to bind the file name only
+            @return FResult: Result<Option<Vec<u8>>, Box<dyn Error>>
+                * The bytes of the name, None for stdin
+        */
+        match self.crypt {
+            true => bound_name(&path.with_file_name(self.output_path(path).file_name().unwrap_or_default()), root),
+            false => bound_name(path, root)
+        }
+    }
+
+    fn check_extension(&self, path: &Path, header: &Header) -> crate::FResult<()> {
+        /*
+            * Check the Algorithm Extension of a File Being Decrypted Matches its Header
+
+            * The extension is only a hint, a renamed file is decrypted with what its header records, but
+              a mismatch means it was named by something else and is reported instead of restored to a wrong name
+            * Not checked with --keep-name, where names are the user's own
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file being decrypted
+            @param header: &Header
+                * Its header
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+        if self.keep_name {
+            return Ok(());
+        }
+        match path.extension().and_then(|extension| extension.to_str()).and_then(extension_info) {
+            Some(info) if (info.algorithm, info.block_size) != (header.algorithm, header.block_size) => {
+                let actual = algorithm_info(header.algorithm, header.block_size)?;
+                Err(format!("{:?} is named .{} ({}) but its header says {}", path, info.extension, info.name, actual.name).into())
+            }
+            _ => Ok(())
+        }
+    }
+
+    fn projected_size(&self, path: &Path, size: u64) -> u64 {
+        /*
+            * Get the Size the Given File Would Have Once Encrypted, see Header::projected_size()
+
+            * Compression is not accounted for, it only ever makes files smaller
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file to encrypt
+            @param size: u64
+                * Its size in bytes
+            @return u64: The size of the encrypted file, in the output format
+        */
+        let cipher = self.cipher_for(path);
+        let encrypted = match self.header {
+            /* The header goes to the sidecar, the ciphertext of CTR is as long as the plaintext */
+            true if self.length_preserving => size,
+            true => {
+                let iv = match self.mode {
+                    Mode::Ecb => Vec::new(),
+                    _ => vec![0u8; cipher.block_size]
+                };
+                let header = Header::new(cipher.algorithm, cipher.block_size, size)
+                    .with_mode(self.mode, iv)
+                    .with_kdf(self.kdf, [0; SALT_SIZE], self.run_master_salt())
+                    .with_ecc(self.ecc)
+                    .with_permissions(fs::metadata(path).map_or(0, |metadata| mode_bits(&metadata)))
+                    .with_tweak(random_tweak(cipher.algorithm))
+                    .with_mac_algorithm(self.mac);
+                /* A file not below --relative-to fails once modified, its size doesn't matter */
+                self.bind(header.clone(), path).unwrap_or(header).projected_size()
+            }
+            /* Headerless files are zero padded ECB */
+            false => size.div_ceil(cipher.block_size as u64) * cipher.block_size as u64
+        };
+        self.output_format.encoded_size(encrypted)
+    }
+
+    fn relative_path(&self, path: &Path) -> PathBuf {
+        /*
+            * Get the Path of the Given File Relative to the Output Directory
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * A given file, or a file found below a given directory
+            @return PathBuf
+                * The name of the given path the file was found below, followed by the path below it
+                  (e.g. docs/notes/a.txt for docs/notes/a.txt found below docs)
+        */
+        for root in &self.paths {
+            if let Ok(relative) = path.strip_prefix(root) {
+                return match (root.file_name(), relative.as_os_str().is_empty()) {
+                    (Some(name), true) => PathBuf::from(name),
+                    (Some(name), false) => Path::new(name).join(relative),
+                    /* Roots such as . or / have no name of their own */
+                    (None, _) => relative.to_path_buf()
+                };
+            }
+        }
+        PathBuf::from(path.file_name().unwrap_or_default())
+    }
+
+    fn verify_written(&self, path: &Path, temp_path: &Path, expected: &[u8]) -> crate::FResult<()> {
+        /*
+            * Check an Encrypted File Decrypts Back to its Original, see --verify
+
+            * The file is decrypted as decrypt would, from its header: the key is derived again and the MAC
+              checked, so a broken header or key derivation is caught and not just the ciphertext
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The original, for errors
+            @param temp_path: &Path
+                * The encrypted file, as written to disk
+            @param expected: &[u8]
+                * SHA-256 of the original
+            @return FResult: Result<(), Box<dyn Error>>
+                * An error if it can't be decrypted or decrypts to something else
+        */
+        let data = self.retry("read", temp_path, || Ok(fs::read(temp_path)?))?;
+        let plaintext = encoding::decode(&data)
+            .and_then(|data| self.decrypt(path, &data, None))
+            .map_err(|err| format!("Verification failed for {:?}, file left untouched: {}", path, err))?
+            .0;
+
+        match Sha256::digest(&plaintext).as_slice() == expected {
+            true => Ok(()),
+            false => Err(format!("Verification failed for {:?}, the encrypted file does not decrypt back to it, file left untouched", path).into())
+        }
+    }
+
+    fn verify_outputs(&self) -> Vec<String> {
+        /*
+            * Check Every File Encrypted by the Run Still Authenticates, see --verify-after
+
+            @param self: Fisher Instance
+            @return Vec<String>
+                * The files failing the check with why, also recorded as failures in the run report
+        */
+        let outputs = std::mem::take(&mut self.report.lock().unwrap().outputs);
+        if self.verbose {
+            println!("Verifying {} encrypted file(s)", outputs.len());
+        }
+
+        outputs.iter()
+            .filter_map(|(path, source)| self.verify_output(path, source).err().map(|err| {
+                let err = self.record_failure(path, err);
+                format!("{:?}: {}", self.log_path(path), err)
+            }))
+            .collect()
+    }
+
+    fn verify_output(&self, path: &Path, source: &Path) -> crate::FResult<()> {
+        /*
+            * Check the Header and MAC of an Encrypted File as Written to Disk
+
+            * Raw files without parity are read a chunk at a time, anything else is decoded in memory
+            * Nothing is decrypted, the MAC covers the header and the whole ciphertext
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The encrypted file
+            @param source: &Path
+                * The file it was encrypted from, whose path it is bound to (see bound_name())
+            @return FResult: Result<(), Box<dyn Error>>
+                * An error if it has no valid header, has the wrong size or fails authentication
+        */
+        let mut start = Vec::with_capacity(header::MAX_SIZE);
+        File::open(path)?.take(header::MAX_SIZE as u64).read_to_end(&mut start)?;
+
+        let (header, mac) = match Header::parse(&start)?.filter(|header| header.ecc == 0) {
+            Some(mut header) => {
+                self.associate(&mut header, source)?;
+                let size = fs::metadata(path)?.len().saturating_sub(header.size() as u64);
+                check_size(path, header.block_size, header.mode, header.flags & FLAG_PKCS7 != 0, size,
+                           Some((header.original_size, "header")))?;
+                let cipher = self.keyed(header.algorithm, header.block_size, header.kdf, &header.salt,
+                                        header.master_salt.as_ref(), &header.tweak)?;
+                let mut mac = header.mac_state(cipher.mac_key.as_ref().ok_or("Missing MAC key")?.as_slice())?;
+
+                let mut input = File::open(path)?;
+                input.seek(SeekFrom::Start(header.size() as u64))?;
+                io::copy(&mut input, &mut MacWriter(&mut *mac))?;
+                (header, mac)
+            }
+            None => {
+                let data = self.retry("read", path, || Ok(fs::read(path)?))?;
+                let data = encoding::decode(&data)?;
+                let mut header = Header::parse(&data)?.ok_or("No fisher header")?;
+                self.associate(&mut header, source)?;
+                let ciphertext = match header.ecc {
+                    0 => Cow::Borrowed(&data[header.size()..]),
+                    parity => Cow::Owned(ecc::decode(&data[header.size()..], parity)?.0)
+                };
+                check_size(path, header.block_size, header.mode, header.flags & FLAG_PKCS7 != 0, ciphertext.len() as u64,
+                           Some((header.original_size, "header")))?;
+                let cipher = self.keyed(header.algorithm, header.block_size, header.kdf, &header.salt,
+                                        header.master_salt.as_ref(), &header.tweak)?;
+                let mut mac = header.mac_state(cipher.mac_key.as_ref().ok_or("Missing MAC key")?.as_slice())?;
+                mac.update(&ciphertext);
+                (header, mac)
+            }
+        };
+
+        match header.mac_matches(mac) {
+            true => Ok(()),
+            false => Err("Authentication failed, the file changed after it was written".into())
+        }
+    }
+
+    fn open_shred(&self, path: &Path) -> crate::FResult<Option<File>> {
+        /*
+            * Open the Original of an Encrypted File for Overwriting, see --shred
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The original
+            @return FResult: Result<Option<File>, Box<dyn Error>>
+                * The original opened for writing, None after a warning if overwriting it can't be trusted
+                  (it is then only removed)
+        */
+        let file = OpenOptions::new().write(true).open(path)
+            .map_err(|err| format!("Could not open {:?} to shred it: {}", path, err))?;
+        match shred::unreliable(path, &file.metadata()?) {
+            Some(reason) => {
+                eprintln!("Warning: not shredding {:?}, {}, it is only removed", self.log_path(path), reason);
+                Ok(None)
+            }
+            None => Ok(Some(file))
+        }
+    }
+
+    fn in_place(&self) -> bool {
+        /*
+            * Check Whether Files Replace their Originals
+
+            @param self: Fisher Instance
+            @return bool: False with --output, which leaves the originals untouched
+        */
+        matches!(self.destination, Destination::InPlace)
+    }
+
+    fn file_bar(&self, path: &Path, size: u64) -> Option<ProgressBar> {
+        /*
+            * Show the Bytes Processed of a Large File, see Progress::file_bar()
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The file
+            @param size: u64
+                * The number of bytes that will be processed
+            @return Option<ProgressBar>
+        */
+        self.progress.as_ref().and_then(|progress| progress.file_bar(path, size))
+    }
+
+    pub(crate) fn operation(&self) -> &'static str {
+        /*
+            * Get What the Run Does to Each File
+
+            @param self: Fisher Instance
+            @return &str: migrate, encrypt or decrypt
+        */
+        operation(self.migrate, self.crypt)
+    }
+
+    fn log_path(&self, path: &Path) -> PathBuf {
+        /*
+            * Get the Given Path as it Should Appear in Logs
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The path being processed
+            @return PathBuf
+                * The canonical path with --canonical-paths, otherwise the path as given
+                * Paths that can't be canonicalized (e.g. removed meanwhile) are only made absolute
+        */
+        if !self.canonical_paths {
+            return path.to_path_buf();
+        }
+
+        fs::canonicalize(path)
+            .or_else(|_| std::path::absolute(path))
+            .unwrap_or(path.to_path_buf())
+    }
+
+    fn sync_dir(&self, path: &Path) -> crate::FResult<()> {
+        /*
+            * Flush the Directory Containing the Given File to Disk
+
+            * Directory entries (such as a rename) are only durable once the directory itself is synced
+
+            @param self: Fisher Instance
+            @param path: &Path
+                * The path of the written file, used to locate its directory
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+        if self.sync != SyncMode::Dir {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            let parent = match parent.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => parent
+            };
+            File::open(parent)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+/* Work items of the worker pool */
+enum Work {
+    /* A path given on the command line */
+    Path(PathBuf),
+    /* A directory to list, with the patterns of the directory above */
+    Dir(PathBuf, Exclude),
+    /* A file found in a directory */
+    File(PathBuf),
+}
+
+/* Feeds what is written to it to a MAC, to authenticate a file with io::copy() */
+struct MacWriter<'a>(&'a mut dyn FileMac);
+
+impl Write for MacWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/*
+    * Seeks Over the All-Zero Blocks Written to it Instead of Writing Them, Leaving Holes in Sparse Files
+
+    * Only filesystems with sparse files (e.g. ext4, XFS, btrfs, APFS, NTFS) leave holes, others (e.g. FAT,
+      exFAT) fill the skipped bytes with zeros, which reads back the same
+    * Only used for plaintext, the ciphertext of zeros is not zero, encrypted files are always dense
+*/
+struct SparseWriter<W: Write + Seek> {
+    inner: W,
+    /* Bytes written or skipped so far */
+    position: u64,
+    /* Zero bytes skipped since the last write */
+    hole: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    fn new(inner: W) -> SparseWriter<W> {
+        SparseWriter { inner, position: 0, hole: 0 }
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        /*
+            * End a Trailing Hole with its Last Zero Byte, so the Output Gets its Full Length
+
+            @param self: SparseWriter Instance
+            @return io::Result<W>: The writer written to
+        */
+        if self.hole > 0 {
+            self.inner.seek(SeekFrom::Current(self.hole as i64 - 1))?;
+            self.inner.write_all(&[0])?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        /* Up to the next block boundary, holes only cover whole blocks */
+        let len = buf.len().min(SPARSE_BLOCK_SIZE - (self.position % SPARSE_BLOCK_SIZE as u64) as usize);
+        let block = &buf[..len];
+        match block.iter().all(|&byte| byte == 0) {
+            true => self.hole += len as u64,
+            false => {
+                if self.hole > 0 {
+                    self.inner.seek(SeekFrom::Current(self.hole as i64))?;
+                    self.hole = 0;
+                }
+                self.inner.write_all(block)?;
+            }
+        }
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/* Bytes of a zero run SparseWriter seeks over at once, the block size of most filesystems */
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/* Bytes of a device read and written at once, a multiple of every block size */
+const DEVICE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/* Bytes of a file encrypted or decrypted at once when streaming, a multiple of every block size */
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/* What modifying a file read and wrote, for its sidecar and the run report */
+struct Modified {
+    algorithm: Algorithm,
+    block_size: usize,
+    /* Plaintext length */
+    original_size: u64,
+    /* Mode bits recorded in the header of a decrypted file, 0 for none */
+    permissions: u32,
+    read: usize,
+    written: usize,
+    /* Header of a --length-preserving file, kept in its sidecar instead of the file */
+    header: Option<Vec<u8>>,
+}
+
+fn check_size(path: &Path, block_size: usize, mode: Mode, pkcs7: bool, size: u64, recorded: Option<(u64, &str)>)
+    -> crate::FResult<()> {
+    /*
+        * Check the Ciphertext of the Given File Fits how it was Encrypted
+
+        @param path: &Path
+            * The file being decrypted
+        @param block_size: usize
+            * The block size in bytes of the cipher decrypting it
+        @param mode: Mode
+            * How the blocks are chained
+        @param pkcs7: bool
+            * Whether the plaintext is PKCS#7 padded (FLAG_PKCS7)
+        @param size: u64
+            * The ciphertext length, without header and parity
+        @param recorded: Option<(u64, &str)>
+            * The plaintext length and where it was recorded (header or sidecar), if known
+        @return FResult: Result<(), Box<dyn Error>>
+            * An error if the ciphertext can't have been encrypted this way
+    */
+    /* Check the ciphertext has the length the recorded plaintext length implies */
+    if let Some((original_size, source)) = recorded {
+        let block_size = block_size as u64;
+        let matches = match (mode, pkcs7) {
+            (Mode::Ctr, _) => size == original_size,
+            (_, true) => size == (original_size / block_size + 1) * block_size,
+            (_, false) => original_size <= size && size < original_size + block_size
+        };
+        if !matches {
+            return Err(format!("{:?} does not match the size recorded in its {}", path, source).into());
+        }
+    }
+
+    /* Ciphertext is always whole blocks except in CTR, anything else was encrypted with a different block size */
+    if mode != Mode::Ctr && !size.is_multiple_of(block_size as u64) {
+        return Err(format!("Block size mismatch: {:?} is not a multiple of {} bytes", path, block_size).into());
+    }
+
+    Ok(())
+}
+
+fn next_iv(iv: &mut [u8], mode: Mode, ciphertext: &[u8]) {
+    /*
+        * Carry the Chaining of a Streamed Chunk Over to the Next Chunk
+
+        @param iv: &mut [u8]
+            * The IV (CBC) or nonce (CTR) the chunk was modified with, empty for ECB
+        @param mode: Mode
+            * How the blocks are chained
+        @param ciphertext: &[u8]
+            * The ciphertext of the chunk, whole blocks
+    */
+    match mode {
+        /* CBC continues from the last ciphertext block */
+        Mode::Cbc => iv.copy_from_slice(&ciphertext[ciphertext.len() - iv.len()..]),
+        /* CTR continues from the counter after the last block */
+        Mode::Ctr => blocks::advance_counter(iv, (ciphertext.len() / iv.len()) as u64),
+        Mode::Ecb => {}
+    }
+}
+
+pub(crate) fn is_device(path: &Path) -> bool {
+    /*
+        * Check Whether the Given Path is a Block or Character Device
+
+        @param path: &Path
+            * The path to check, symlinks are followed
+        @return bool: True for devices
+    */
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        match fs::metadata(path) {
+            Ok(metadata) => metadata.file_type().is_block_device() || metadata.file_type().is_char_device(),
+            Err(_) => false
+        }
+    }
+    /* Devices aren't exposed as paths elsewhere */
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+fn random_tweak(algorithm: Algorithm) -> [u8; TWEAK_SIZE] {
+    /*
+        * Draw the Tweak of a Headered File
+
+        @param algorithm: Algorithm
+            * The algorithm the file is encrypted with
+        @return [u8; TWEAK_SIZE]: Random for Threefish, zero for the algorithms without a tweak
+    */
+    let mut tweak = [0u8; TWEAK_SIZE];
+    if algorithm == Algorithm::Threefish {
+        rand::thread_rng().fill_bytes(&mut tweak);
+    }
+    tweak
+}
+
+fn operation(migrate: bool, crypt: bool) -> &'static str {
+    /*
+        * Get What a Run Does to Each File
+
+        @param migrate: bool
+            * Whether legacy files are migrated
+        @param crypt: bool
+            * Whether files are encrypted (true) or decrypted (false)
+        @return &str: migrate, encrypt or decrypt
+    */
+    match (migrate, crypt) {
+        (true, _) => "migrate",
+        (false, true) => "encrypt",
+        (false, false) => "decrypt"
+    }
+}
+
+fn is_encrypted(path: &Path) -> crate::FResult<bool> {
+    /*
+        * Check Whether the Given File is Already Fisher-Encrypted, see encoding::is_encrypted()
+
+        @param path: &Path
+            * The file about to be encrypted
+        @return FResult: Result<bool, Box<dyn Error>>
+    */
+    let mut start = Vec::with_capacity(header::MAX_SIZE);
+    File::open(path)?.take(header::MAX_SIZE as u64).read_to_end(&mut start)?;
+    Ok(encoding::is_encrypted(&start))
+}
+
+fn mode_bits(metadata: &fs::Metadata) -> u32 {
+    /*
+        * Get the Mode Bits Recorded in the Header of an Encrypted File
+
+        @param metadata: &fs::Metadata
+            * The metadata of the original file
+        @return u32: The permission, setuid, setgid and sticky bits, 0 where files have none
+    */
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        0
+    }
+}
+
+fn set_mode_bits(path: &Path, mode: u32) -> io::Result<()> {
+    /*
+        * Apply Mode Bits Recorded in the Header of an Encrypted File
+
+        @param path: &Path
+            * The decrypted file
+        @param mode: u32
+            * The mode bits, see mode_bits()
+        @return io::Result<()>
+    */
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+        Ok(())
+    }
+}
+
+pub(crate) fn dedupe_paths(paths: Vec<PathBuf>) -> crate::FResult<Vec<PathBuf>> {
+    /*
+        * Drop Paths Already Covered by Other Paths
+
+        * Passing both /dir and /dir/file.txt would otherwise process file.txt twice,
+          e.g. encrypting it twice. Paths are compared canonicalized, so ./dir, dir/ and symlinks match
+
+        @param paths: Vec<PathBuf>
+            * The paths as given by the user
+        @return FResult: Result<Vec<PathBuf>, Box<dyn Error>>
+            * The paths in the same order, without duplicates and paths nested in a given directory
+    */
+    let canonical = paths.iter()
+        .map(|path| path.canonicalize().map_err(|err| format!("Could not resolve {:?}: {}", path, err)))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+
+    let mut kept: Vec<PathBuf> = Vec::with_capacity(paths.len());
+    for (index, path) in paths.into_iter().enumerate() {
+        /* Covered by an earlier copy of the same path, or by a different directory containing it */
+        let covered = canonical.iter().enumerate().find(|(other, other_path)| match canonical[index] == **other_path {
+            true => *other < index,
+            false => other_path.is_dir() && canonical[index].starts_with(other_path)
+        });
+
+        match covered {
+            Some((other, _)) => println!("Skipping {:?}, already covered by {:?}", path, canonical[other]),
+            None => kept.push(path)
+        }
+    }
+
+    Ok(kept)
+}
+
+pub(crate) fn count(paths: &[PathBuf], backup: BackupMode, encrypting: bool, follow_symlinks: bool, exclude: &Exclude)
+    -> crate::FResult<(usize, u64)> {
+    /*
+        * Count the Files and Bytes a Run Over the Given Paths Would Process
+
+        * Walks directories the same way Fisher::iter_dir() does, without modifying anything
+
+        @param paths: &[PathBuf]
+            * The paths to count
+        @param backup: BackupMode
+            * The backup mode of the run, backups are skipped while it is enabled
+        @param encrypting: bool
+            * Whether the run encrypts, outputs of earlier runs are skipped then
+        @param follow_symlinks: bool
+            * Whether symlinks in directories are followed (each directory and file counted once) or skipped
+        @param exclude: &Exclude
+            * The files and directories skipped in directories, on top of those in .fisherignore files
+        @return FResult: Result<(usize, u64), Box<dyn Error>>
+            * The number of files and their total size in bytes
+    */
+    count_visited(paths, backup, encrypting, follow_symlinks, exclude, &mut HashSet::new())
+}
+
+fn count_visited(paths: &[PathBuf], backup: BackupMode, encrypting: bool, follow_symlinks: bool, exclude: &Exclude,
+                 visited: &mut HashSet<PathBuf>) -> crate::FResult<(usize, u64)> {
+    /*
+        * Count the Files and Bytes Below the Given Paths, see count()
+
+        @param visited: &mut HashSet<PathBuf>
+            * The canonical directories and files counted so far, only tracked when following symlinks
+        @return FResult: Result<(usize, u64), Box<dyn Error>>
+    */
+    let mut files = 0;
+    let mut bytes = 0;
+
+    for path in paths {
+        /* Broken links are skipped, as when modifying */
+        if follow_symlinks && !path.canonicalize().is_ok_and(|canonical| visited.insert(canonical)) {
+            continue;
+        }
+
+        match path.is_dir() {
+            true => {
+                /* Walk the directory */
+                let exclude = exclude.enter(path)?;
+                for module in fs::read_dir(path)? {
+                    let module = module?;
+                    let module_path = module.path();
+
+                    if exclude.matching(&module_path).is_some() || (!follow_symlinks && is_symlink(&module_path))
+                        || (!module_path.is_dir() && skip_file(&module_path, backup, encrypting)) {
+                        continue;
+                    }
+
+                    let (module_files, module_bytes) = count_visited(&[module_path], backup, encrypting, follow_symlinks, &exclude,
+                                                                     visited)?;
+                    files += module_files;
+                    bytes += module_bytes;
+                }
+            }
+            false => {
+                files += 1;
+                bytes += fs::metadata(path)?.len();
+            }
+        }
+    }
+
+    Ok((files, bytes))
+}
+
+pub(crate) fn is_symlink(path: &Path) -> bool {
+    /*
+        * Check Whether the Given Path is a Symlink, Without Following it
+
+        @param path: &Path
+            * The path
+        @return bool
+    */
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+pub(crate) fn skip_file(path: &Path, backup: BackupMode, encrypting: bool) -> bool {
+    /*
+        * Check Whether a File Found in a Directory Should be Skipped, see skip_reason()
+
+        @param path: &Path
+            * The file found while walking a directory
+        @param backup: BackupMode
+            * The backup mode of the run
+        @param encrypting: bool
+            * Whether the run encrypts
+        @return bool: True if the file should not be modified
+    */
+    skip_reason(path, backup, encrypting).is_some()
+}
+
+fn skip_reason(path: &Path, backup: BackupMode, encrypting: bool) -> Option<&'static str> {
+    /*
+        * Get Why a File Found in a Directory Should be Skipped
+
+        @param path: &Path
+            * The file found while walking a directory
+        @param backup: BackupMode
+            * The backup mode of the run
+        @param encrypting: bool
+            * Whether the run encrypts, outputs of earlier runs are skipped then
+        @return Option<&str>: The reason, None if the file should be modified
+    */
+
+    /* Never modify temporary outputs of files currently being modified */
+    if path.extension().is_some_and(|extension| extension == TEMP_EXTENSION) {
+        return Some("it is a temporary output");
+    }
+
+    /* Never modify devices found in directories, only devices given explicitly with --device */
+    if is_device(path) {
+        return Some("it is a device");
+    }
+
+    /* Never modify sidecars, they belong to the file they are named after */
+    if path.to_string_lossy().ends_with(META_SUFFIX) {
+        return Some("it is a sidecar");
+    }
+
+    /* Never modify key shares, they may have been written into the tree being encrypted */
+    if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(crate::shares::SHARE_PREFIX)) {
+        return Some("it is a key share");
+    }
+
+    /* Never modify backups made by this (or a previous) run */
+    if backup != BackupMode::Off && path.extension().is_some_and(|extension| extension == BACKUP_EXTENSION) {
+        return Some("it is a backup");
+    }
+
+    /*
+        * Never encrypt the outputs of an earlier run again, they sit next to the originals
+        * Headerless (--no-header) and --length-preserving outputs have no magic to recognize them by,
+          so they are told apart by their .fish or algorithm extension, or by their sidecar
+    */
+    if encrypting {
+        if has_encrypted_extension(path) {
+            return Some("it is named like an encrypted file");
+        }
+        if meta_path(path).exists() {
+            return Some("it has a sidecar, it is an encrypted file");
+        }
+    }
+
+    None
+}
+
+fn has_encrypted_extension(path: &Path) -> bool {
+    /*
+        * Check Whether the Given Path Ends in an Extension Encrypting Appends
+
+        @param path: &Path
+            * The path
+        @return bool: True for .fish and the algorithm extensions of --algorithm-extension (in any case)
+    */
+    path.extension().and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension == ENCRYPTED_EXTENSION || extension_info(extension).is_some())
+}
+
+pub(crate) fn bound_name(path: &Path, root: Option<&Path>) -> crate::FResult<Option<Vec<u8>>> {
+    /*
+        * Get the Name an Encrypted File is Bound to, see FLAG_PATH
+
+        * Only the file name is bound, the file can be moved to another directory but not renamed
+        * Trailing .fish and algorithm extensions are left out, so the plaintext and the encrypted file
+          (with or without --keep-name) give the same name
+        * Below a root (see FLAG_RELATIVE) the directories from the root down are bound as well, joined with /
+          whatever the platform, so the tree can be moved as a whole but files can't be moved within it
+
+        @param path: &Path
+            * The plaintext or the encrypted file
+        @param root: Option<&Path>
+            * The canonical --relative-to root, None to bind the file name only
+        @return FResult: Result<Option<Vec<u8>>, Box<dyn Error>>
+            * The bytes of the name, None for stdin
+            * An error if the directory of the file is not below the root
+    */
+    let mut stem = path;
+    while has_encrypted_extension(stem) {
+        stem = match stem.file_stem() {
+            Some(stem) => Path::new(stem),
+            None => break
+        };
+    }
+    let name = match (stem == Path::new(PIPE_PATH), stem.file_name()) {
+        (false, Some(name)) => name.as_encoded_bytes(),
+        _ => return Ok(None)
+    };
+    let root = match root {
+        Some(root) => root,
+        None => return Ok(Some(name.to_vec()))
+    };
+
+    /* The directory is canonicalized like the root, so ./, .. and symlinked spellings of it bind the same path */
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+    let parent = parent.canonicalize().map_err(|err| format!("Could not resolve {:?}: {}", parent, err))?;
+    let relative = parent.strip_prefix(root).map_err(|_| format!("{:?} is not below --relative-to {:?}", path, root))?;
+
+    let mut bound = Vec::new();
+    for component in relative.components() {
+        bound.extend_from_slice(component.as_os_str().as_encoded_bytes());
+        bound.push(b'/');
+    }
+    bound.extend_from_slice(name);
+    Ok(Some(bound))
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    /*
+        * Check Whether an IO Error is Worth Retrying
+
+        @param err: &io::Error
+            * The error to check
+        @return bool: True if the same operation may succeed when retried
+    */
+    matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        | io::ErrorKind::ResourceBusy)
+}
+
+/* The path standing for stdin and stdout, in --paths and in messages */
+pub(crate) const PIPE_PATH: &str = "-";
+
+/* Extension appended to the name of encrypted files */
+pub(crate) const ENCRYPTED_EXTENSION: &str = "fish";
+
+/* Extension appended to the name of backup copies */
+const BACKUP_EXTENSION: &str = "bak";
+
+fn backup_path(path: &Path) -> PathBuf {
+    /*
+        * Get the Backup Path for the Given File
+
+        @param path: &Path
+            * The file being backed up
+        @return PathBuf
+            * The path with .bak appended to its full file name
+    */
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".");
+    backup.push(BACKUP_EXTENSION);
+    PathBuf::from(backup)
+}
+
+/* Extension of the temporary files outputs are written to before replacing the original */
+const TEMP_EXTENSION: &str = "fisher-tmp";
+
+fn temp_path(path: &Path) -> PathBuf {
+    /*
+        * Get the Temporary Output Path for the Given File
+
+        @param path: &Path
+            * The file being modified
+        @return PathBuf
+            * A hidden sibling of the file, named .<name>.fisher-tmp
+    */
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".");
+    name.push(TEMP_EXTENSION);
+    path.with_file_name(name)
+}
+
+fn copy_xattrs(from: &Path, to: &Path) {
+    /*
+        * Copy the Extended Attributes of One File to Another
+
+        * Best effort, attributes that can't be read or written are reported and skipped
+
+        @param from: &Path
+            * The file to read the attributes from
+        @param to: &Path
+            * The file to write the attributes to
+    */
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        Err(err) => {
+            eprintln!("Warning: could not read extended attributes of {:?}: {}", from, err);
+            return;
+        }
+    };
+
+    for name in names {
+        match xattr::get(from, &name) {
+            Ok(Some(value)) => {
+                if let Err(err) = xattr::set(to, &name, &value) {
+                    eprintln!("Warning: could not preserve extended attribute {:?} of {:?}: {}", name, from, err);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                eprintln!("Warning: could not read extended attribute {:?} of {:?}: {}", name, from, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r#enum::AlgorithmInfo;
+
+    /* Every mode a headered file can be encrypted with */
+    const MODES: [Mode; 3] = [Mode::Ecb, Mode::Cbc, Mode::Ctr];
+
+    fn config(info: &AlgorithmInfo, mode: Mode) -> FisherConfig {
+        /*
+            * Configure Encrypting With the Given Algorithm and Mode, with a Cheap KDF
+
+            @param info: &AlgorithmInfo
+                * The algorithm and block size
+            @param mode: Mode
+                * How the blocks are chained
+            @return FisherConfig
+        */
+        FisherConfig {
+            algorithm: info.algorithm,
+            block_size: info.block_size,
+            mode,
+            passphrase: Zeroizing::new(b"password".to_vec()),
+            kdf: Kdf::Pbkdf2 { iterations: 1 },
+            ..FisherConfig::default()
+        }
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        /*
+            * Create an Empty Directory for a Test, Removing What a Previous Run Left There
+
+            @param name: &str
+                * The name of the test
+            @return PathBuf: The directory, below the system temporary directory
+        */
+        let dir = std::env::temp_dir().join(format!("fisher-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn fisher(info: &AlgorithmInfo, mode: Mode) -> Fisher {
+        /*
+            * Create a Fisher Encrypting With the Given Algorithm and Mode, see config()
+        */
+        Fisher::from_config(config(info, mode)).unwrap()
+    }
+
+    fn tree(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        /*
+            * Read Every File Below the Given Directory
+
+            @param dir: &Path
+                * The directory
+            @return Vec<(PathBuf, Vec<u8>)>: The paths relative to the directory and the contents, sorted
+        */
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => files.extend(tree(&path).into_iter().map(|(name, data)| (Path::new(path.file_name().unwrap()).join(name), data))),
+                false => files.push((PathBuf::from(path.file_name().unwrap()), fs::read(&path).unwrap()))
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn empty_round_trips() {
+        for info in &ALGORITHMS {
+            for mode in MODES {
+                let fisher = fisher(info, mode);
+                let encrypted = fisher.encrypt_bytes(Path::new("empty"), &[]).unwrap();
+
+                let header = Header::parse(&encrypted).unwrap().unwrap();
+                assert_eq!(header.original_size, 0, "{} {:?}", info.name, mode);
+                assert!(fisher.decrypt_bytes(Path::new("empty"), &encrypted, None).unwrap().is_empty(),
+                        "{} {:?}", info.name, mode);
+            }
+        }
+    }
+
+    #[test]
+    fn whole_blocks_round_trip() {
+        for info in &ALGORITHMS {
+            for mode in MODES {
+                let fisher = fisher(info, mode);
+                for blocks in [1, 2, 7] {
+                    let data: Vec<u8> = (0..blocks * info.block_size).map(|byte| byte as u8).collect();
+                    let encrypted = fisher.encrypt_bytes(Path::new("blocks"), &data).unwrap();
+
+                    assert_eq!(Header::parse(&encrypted).unwrap().unwrap().original_size, data.len() as u64);
+                    assert_eq!(fisher.decrypt_bytes(Path::new("blocks"), &encrypted, None).unwrap(), data,
+                               "{} {:?} {} block(s)", info.name, mode, blocks);
+                }
+            }
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn random_data_round_trips(
+            algorithm in 0..ALGORITHMS.len(),
+            mode in proptest::sample::select(&MODES[..]),
+            compress: bool,
+            ecc in proptest::sample::select(&[0u8, 2, 16][..]),
+            data in proptest::collection::vec(proptest::num::u8::ANY, 0..1024),
+        ) {
+            let fisher = Fisher::from_config(FisherConfig { compress, ecc, ..config(&ALGORITHMS[algorithm], mode) }).unwrap();
+            let encrypted = fisher.encrypt_bytes(Path::new("random"), &data).unwrap();
+            proptest::prop_assert_eq!(fisher.decrypt_bytes(Path::new("random"), &encrypted, None).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn files_of_a_run_share_one_master_key() {
+        let fisher = fisher(&ALGORITHMS[1], Mode::Cbc);
+        let first = fisher.encrypt_bytes(Path::new("first"), b"first").unwrap();
+        let second = fisher.encrypt_bytes(Path::new("second"), b"second").unwrap();
+
+        let (first_header, second_header) = (Header::parse(&first).unwrap().unwrap(), Header::parse(&second).unwrap().unwrap());
+        assert_eq!(first_header.master_salt, Some(fisher.master_salt));
+        assert_eq!(first_header.master_salt, second_header.master_salt);
+        assert_ne!(first_header.salt, second_header.salt);
+        assert_eq!(fisher.masters.lock().unwrap().len(), 1);
+
+        /* Another run stretches its own master key from the salt in the header */
+        let other = self::fisher(&ALGORITHMS[1], Mode::Cbc);
+        assert_eq!(other.decrypt_bytes(Path::new("second"), &second, None).unwrap(), b"second");
+    }
+
+    #[test]
+    fn projected_size_matches_encrypted_size() {
+        let dir = scratch("projected");
+        for info in &ALGORITHMS {
+            for mode in MODES {
+                for (ecc, output_format) in [(0, OutputFormat::Raw), (16, OutputFormat::Armor), (2, OutputFormat::Base64Url)] {
+                    let fisher = Fisher::from_config(FisherConfig { ecc, output_format, ..config(info, mode) }).unwrap();
+                    for size in [0, 1, info.block_size, 3 * info.block_size + 5, 1000] {
+                        let path = dir.join(format!("{}-{}", info.extension, size));
+                        fs::write(&path, vec![1u8; size]).unwrap();
+
+                        let encrypted = output_format.encode(fisher.encrypt_bytes(&path, &fs::read(&path).unwrap()).unwrap());
+                        assert_eq!(fisher.projected_size(&path, size as u64), encrypted.len() as u64,
+                                   "{} {:?} {:?} {} bytes", info.name, mode, output_format, size);
+                    }
+                }
+            }
+        }
+
+        let headerless = Fisher::from_config(FisherConfig { header: false, ..config(&ALGORITHMS[0], Mode::Ecb) }).unwrap();
+        assert_eq!(headerless.projected_size(Path::new("headerless"), 9) as usize,
+                   headerless.encrypt_bytes(Path::new("headerless"), &[1; 9]).unwrap().len());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify_output_catches_changed_files() {
+        let dir = scratch("verify-after");
+        for (ecc, output_format) in [(0, OutputFormat::Raw), (4, OutputFormat::Raw), (0, OutputFormat::Armor)] {
+            let fisher = Fisher::from_config(FisherConfig { ecc, output_format, ..config(&ALGORITHMS[2], Mode::Ctr) }).unwrap();
+            let path = dir.join("file.fish");
+            let encrypted = fisher.encrypt_bytes(&path, &[5; 1000]).unwrap();
+            fs::write(&path, output_format.encode(encrypted.clone())).unwrap();
+            assert!(fisher.verify_output(&path, &path).is_ok());
+
+            /* Parity corrects a single flipped byte, so flip more than it can */
+            let mut changed = encrypted;
+            let len = changed.len();
+            for byte in &mut changed[len - 20..] {
+                *byte ^= 1;
+            }
+            fs::write(&path, output_format.encode(changed)).unwrap();
+            assert!(fisher.verify_output(&path, &path).is_err(), "ecc {} {:?}", ecc, output_format);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn given_salt_keys_files_directly() {
+        let salt = *b"0123456789abcdef";
+        let fisher = Fisher::from_config(FisherConfig { salt: Some(salt), ..config(&ALGORITHMS[1], Mode::Cbc) }).unwrap();
+        let encrypted = fisher.encrypt_bytes(Path::new("salted"), b"salted").unwrap();
+
+        let mut header = Header::parse(&encrypted).unwrap().unwrap();
+        assert_eq!(header.salt, salt);
+        assert_eq!(header.master_salt, None);
+
+        /* Anything computing PBKDF2 over the password and salt gets the key of the file */
+        header.associate(None, Some(b"salted")).unwrap();
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(b"password", &salt, 1, &mut key);
+        assert!(header.verify_mac(mac_key(&key).as_slice(), &encrypted[header.size()..]).unwrap());
+        assert_eq!(fisher.decrypt_bytes(Path::new("salted"), &encrypted, None).unwrap(), b"salted");
+    }
+
+    #[test]
+    fn deterministic_files_encrypt_identically() {
+        let dir = scratch("deterministic");
+        for info in &ALGORITHMS {
+            let deterministic = || Fisher::from_config(FisherConfig { deterministic: true, ..config(info, Mode::Cbc) }).unwrap();
+            let (fisher, other) = (deterministic(), deterministic());
+
+            /* Another run, and the streaming path, give the same bytes for the same plaintext */
+            let path = dir.join(info.extension);
+            fs::write(&path, [7u8; 1000]).unwrap();
+            let encrypted = fisher.encrypt_bytes(&path, &[7; 1000]).unwrap();
+            assert_eq!(other.encrypt_bytes(&path, &[7; 1000]).unwrap(), encrypted, "{}", info.name);
+            let streamed = dir.join("streamed");
+            fisher.encrypt_stream(&path, &streamed).unwrap();
+            assert_eq!(fs::read(&streamed).unwrap(), encrypted, "{}", info.name);
+
+            /* A single changed byte changes the salt and IV */
+            let (header, changed) = (Header::parse(&encrypted).unwrap().unwrap(),
+                                     Header::parse(&fisher.encrypt_bytes(&path, &[7; 999]).unwrap()).unwrap().unwrap());
+            assert_ne!(header.salt, changed.salt);
+            assert_ne!(header.iv, changed.iv);
+            assert_eq!(self::fisher(info, Mode::Cbc).decrypt_bytes(&path, &encrypted, None).unwrap(), [7; 1000]);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn algorithm_extension_names_and_checks_files() {
+        for info in &ALGORITHMS {
+            let fisher = Fisher::from_config(FisherConfig { algorithm_extension: true, ..config(info, Mode::Cbc) }).unwrap();
+            let named = PathBuf::from(format!("notes.txt.{}", info.extension));
+            assert_eq!(fisher.output_path(Path::new("notes.txt")), named);
+
+            let decrypt = Fisher::from_config(FisherConfig { crypt: false, ..config(info, Mode::Cbc) }).unwrap();
+            assert_eq!(decrypt.output_path(&named), Path::new("notes.txt"));
+            assert_eq!(decrypt.output_path(Path::new("notes.txt.fish")), Path::new("notes.txt"));
+
+            /* The header decides, a file named after another algorithm is refused */
+            let encrypted = fisher.encrypt_bytes(&named, b"notes").unwrap();
+            assert_eq!(decrypt.decrypt_bytes(&named, &encrypted, None).unwrap(), b"notes");
+            let other = &ALGORITHMS[(info.id as usize) % ALGORITHMS.len()];
+            let renamed = PathBuf::from(format!("notes.txt.{}", other.extension));
+            assert!(decrypt.decrypt_bytes(&renamed, &encrypted, None).unwrap_err().to_string().contains("header says"));
+        }
+
+        /* --algorithm-map picks the extension of each file */
+        let mapped = Fisher::from_config(FisherConfig {
+            algorithm_extension: true,
+            algorithm_map: vec![("*.mp4".to_string(), Algorithm::Blowfish, 8)],
+            ..config(&ALGORITHMS[1], Mode::Cbc)
+        }).unwrap();
+        assert_eq!(mapped.output_path(Path::new("film.mp4")), Path::new("film.mp4.bf"));
+        assert_eq!(mapped.output_path(Path::new("notes.txt")), Path::new("notes.txt.tw"));
+    }
+
+    #[test]
+    fn existing_outputs_follow_their_policy() {
+        let dir = scratch("existing");
+        for existing in [ExistingOutput::Error, ExistingOutput::Overwrite, ExistingOutput::Skip] {
+            let (path, output) = (dir.join("notes.txt"), dir.join("notes.txt.fish"));
+            fs::write(&path, b"notes").unwrap();
+            fs::write(&output, b"previous run").unwrap();
+
+            let fisher = Fisher::from_config(FisherConfig { existing, ..config(&ALGORITHMS[1], Mode::Cbc) }).unwrap();
+            let result = fisher.modify_file(&path);
+            match existing {
+                ExistingOutput::Error => {
+                    assert!(result.unwrap_err().to_string().contains("already exists"));
+                    assert_eq!(fs::read(&path).unwrap(), b"notes");
+                    assert_eq!(fs::read(&output).unwrap(), b"previous run");
+                }
+                ExistingOutput::Overwrite => {
+                    result.unwrap();
+                    assert!(!path.exists());
+                    assert_eq!(fisher.decrypt_bytes(&output, &fs::read(&output).unwrap(), None).unwrap(), b"notes");
+                }
+                ExistingOutput::Skip => {
+                    result.unwrap();
+                    assert_eq!(fs::read(&path).unwrap(), b"notes");
+                    assert_eq!(fs::read(&output).unwrap(), b"previous run");
+                    assert_eq!(fisher.skipped.load(Ordering::SeqCst), 1);
+                }
+            }
+            assert!(!temp_path(&output).exists());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_mac_authenticates_files() {
+        let dir = scratch("mac");
+        let path = dir.join("file");
+        fs::write(&path, [4u8; 1000]).unwrap();
+        for mac in [MacAlgorithm::HmacSha256, MacAlgorithm::Blake3, MacAlgorithm::Poly1305] {
+            for mode in MODES {
+                /* Unbound, so only the MAC decides whether older versions read the file */
+                let fisher = Fisher::from_config(FisherConfig { mac, bind_path: false, ..config(&ALGORITHMS[1], mode) }).unwrap();
+                let encrypted = fisher.encrypt_bytes(&path, &[4; 1000]).unwrap();
+                let header = Header::parse(&encrypted).unwrap().unwrap();
+                assert_eq!(header.mac_algorithm, mac);
+                assert_eq!(header.version >= 7, mac != MacAlgorithm::HmacSha256);
+                assert_eq!(fisher.projected_size(&path, 1000), encrypted.len() as u64);
+
+                /* The streaming path authenticates the same way */
+                let streamed = dir.join("streamed");
+                fisher.encrypt_stream(&path, &streamed).unwrap();
+                let streamed = fs::read(&streamed).unwrap();
+                assert_eq!(fisher.decrypt_bytes(&path, &streamed, None).unwrap(), [4; 1000]);
+
+                let mut changed = encrypted;
+                let last = changed.len() - 1;
+                changed[last] ^= 1;
+                assert!(fisher.decrypt_bytes(&path, &changed, None).unwrap_err().to_string().contains("Authentication failed"),
+                        "{} {:?}", mac.name(), mode);
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sparse_files_decrypt_sparse() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = scratch("sparse");
+        let path = dir.join("disk.img");
+        /* 256 KiB with data only at the start, in the middle and at the very end */
+        let size = 256 * 1024;
+        let mut image = File::create(&path).unwrap();
+        image.set_len(size).unwrap();
+        for offset in [0, size / 2 + 1000, size - 10] {
+            image.seek(SeekFrom::Start(offset)).unwrap();
+            image.write_all(b"some data!").unwrap();
+        }
+        drop(image);
+        let original = fs::read(&path).unwrap();
+
+        for mode in MODES {
+            let fisher = fisher(&ALGORITHMS[1], mode);
+            let (encrypted, decrypted) = (dir.join("disk.img.fish"), dir.join("decrypted.img"));
+            fisher.encrypt_stream(&path, &encrypted).unwrap();
+            let header = Header::parse(&fs::read(&encrypted).unwrap()).unwrap().unwrap();
+            fisher.decrypt_stream(&encrypted, &decrypted, &header, header.size() as u64).unwrap();
+
+            assert_eq!(fs::read(&decrypted).unwrap(), original, "{:?}", mode);
+            /* Only the blocks holding data are allocated, a dense copy takes all of it */
+            assert!(fs::metadata(&decrypted).unwrap().blocks() * 512 < size / 4, "{:?}", mode);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn length_preserving_files_keep_their_size() {
+        let dir = scratch("length-preserving");
+        let (path, encrypted) = (dir.join("record.dat"), dir.join("record.dat.fish"));
+        let original: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        fs::write(&path, &original).unwrap();
+
+        let fisher = Fisher::from_config(FisherConfig { length_preserving: true, ..config(&ALGORITHMS[2], Mode::Cbc) }).unwrap();
+        fisher.modify_file(&path).unwrap();
+        let ciphertext = fs::read(&encrypted).unwrap();
+        assert_eq!(ciphertext.len(), original.len());
+        assert_ne!(ciphertext, original);
+
+        /* The sidecar holds the CTR header, decrypting in memory finds it there as well */
+        let meta = Meta::read(&encrypted).unwrap().unwrap();
+        let header = meta.header().unwrap().unwrap();
+        assert_eq!((header.mode, header.original_size), (Mode::Ctr, 1000));
+        assert_eq!(fisher.decrypt_bytes(&encrypted, &ciphertext, Some(&meta)).unwrap(), original);
+
+        let decrypt = Fisher::from_config(FisherConfig { crypt: false, ..config(&ALGORITHMS[2], Mode::Cbc) }).unwrap();
+        decrypt.modify_file(&encrypted).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert!(!encrypted.exists() && !meta_path(&encrypted).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dedupe_paths_drops_covered_paths() {
+        let dir = scratch("dedupe");
+        let (sub, file, other) = (dir.join("dir"), dir.join("dir").join("file"), dir.join("other"));
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(&file, b"file").unwrap();
+        fs::write(&other, b"other").unwrap();
+
+        /* A directory covers the files below it, whichever comes first */
+        assert_eq!(dedupe_paths(vec![sub.clone(), file.clone(), other.clone()]).unwrap(), [sub.as_path(), other.as_path()]);
+        assert_eq!(dedupe_paths(vec![file.clone(), sub.clone()]).unwrap(), [sub.as_path()]);
+
+        /* The first copy of the same path is kept, however it is spelled */
+        assert_eq!(dedupe_paths(vec![other.clone(), other.clone()]).unwrap(), [other.as_path()]);
+        let dotted = dir.join(".").join("dir");
+        assert_eq!(dedupe_paths(vec![dotted.clone(), sub.clone()]).unwrap(), [dotted.as_path()]);
+        let trailing = dir.join("dir/");
+        assert_eq!(dedupe_paths(vec![trailing.clone(), sub.join("..").join("dir").join("file")]).unwrap(), [trailing.as_path()]);
+
+        /* Files of the same name in different directories are not duplicates */
+        assert_eq!(dedupe_paths(vec![file.clone(), other.clone()]).unwrap(), [file.as_path(), other.as_path()]);
+        assert!(dedupe_paths(vec![dir.join("missing")]).is_err());
+
+        #[cfg(unix)]
+        {
+            let alias = dir.join("alias");
+            std::os::unix::fs::symlink(&sub, &alias).unwrap();
+            assert_eq!(dedupe_paths(vec![sub.clone(), alias.clone()]).unwrap(), [sub.as_path()]);
+            assert_eq!(dedupe_paths(vec![alias.clone(), file.clone()]).unwrap(), [alias.as_path()]);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_leave_the_original_untouched() {
+        let dir = scratch("failed-write");
+        let path = dir.join("file.txt");
+        fs::write(&path, b"original contents").unwrap();
+        let temp = temp_path(&path);
+        assert!(temp.ends_with(".file.txt.fisher-tmp"));
+
+        /* A writer running out of space halfway, the error isn't transient so it isn't retried */
+        let fisher = fisher(&ALGORITHMS[1], Mode::Cbc);
+        let result = fisher.write_temp(&path, &temp, |file| {
+            file.write_all(b"half of the out")?;
+            Err::<(), _>(io::Error::new(io::ErrorKind::StorageFull, "No space left on device").into())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "No space left on device");
+        assert!(!temp.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"original contents");
+
+        /* The temp file can't be created where a directory is in the way, the file isn't replaced either */
+        let encrypted = path.with_file_name("file.txt.fish");
+        fs::create_dir(temp_path(&encrypted)).unwrap();
+        assert!(fisher.modify_file(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"original contents");
+        assert!(!encrypted.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn argon2id_files_round_trip() {
+        let dir = scratch("argon2id");
+        let (path, encrypted) = (dir.join("file.txt"), dir.join("file.txt.fish"));
+        let original: Vec<u8> = (0..1000u32).map(|i| (i * 13) as u8).collect();
+        fs::write(&path, &original).unwrap();
+
+        /* Small parameters keep the test fast, decrypting reads them from the header */
+        let kdf = Kdf::Argon2id { memory: 64, time: 1, parallelism: 1 };
+        Fisher::from_config(FisherConfig { kdf, ..config(&ALGORITHMS[1], Mode::Cbc) }).unwrap().modify_file(&path).unwrap();
+        let header = Header::parse(&fs::read(&encrypted).unwrap()).unwrap().unwrap();
+        assert_eq!(header.kdf, kdf);
+
+        let decrypt = |passphrase: &[u8]| Fisher::from_config(FisherConfig {
+            crypt: false,
+            passphrase: Zeroizing::new(passphrase.to_vec()),
+            ..config(&ALGORITHMS[1], Mode::Cbc)
+        }).unwrap().modify_file(&encrypted);
+        assert!(decrypt(b"wrong").is_err());
+        decrypt(b"password").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), original);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn encrypting_twice_skips_earlier_outputs() {
+        let dir = scratch("encrypt-twice");
+        /* Without a header or with it in the sidecar, only the names tell the outputs apart */
+        for name in ["headerless", "length-preserving", "algorithm-extension"] {
+            let root = dir.join(name);
+            fs::create_dir_all(root.join("sub")).unwrap();
+            fs::write(root.join("a.txt"), b"first file").unwrap();
+            fs::write(root.join("sub").join("b.txt"), [7; 100]).unwrap();
+
+            let run = || {
+                let config = FisherConfig { paths: vec![root.clone()], ..config(&ALGORITHMS[1], Mode::Ecb) };
+                let config = match name {
+                    "headerless" => FisherConfig { header: false, ..config },
+                    "length-preserving" => FisherConfig { length_preserving: true, ..config },
+                    _ => FisherConfig { header: false, algorithm_extension: true, ..config }
+                };
+                Fisher::from_config(config).unwrap().run().unwrap();
+            };
+            run();
+            let encrypted = tree(&root);
+            assert!(encrypted.iter().all(|(path, _)| path.to_string_lossy().contains(".txt.")), "{}: {:?}", name, encrypted);
+
+            run();
+            assert_eq!(tree(&root), encrypted, "{}", name);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn output_directories_leave_the_sources_untouched() {
+        let dir = scratch("output-dirs");
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), b"first file").unwrap();
+        fs::write(source.join("sub").join("b.txt"), [7; 100]).unwrap();
+        let plaintext = tree(&source);
+        let run = |crypt: bool, path: &Path, output: &Path| Fisher::from_config(FisherConfig {
+            crypt,
+            paths: vec![path.to_path_buf()],
+            output: Some(output.to_path_buf()),
+            ..config(&ALGORITHMS[1], Mode::Cbc)
+        }).unwrap().run().unwrap();
+
+        /* Encrypting to a directory keeps the plaintext where it is, the tree is written below the output */
+        let (encrypted, decrypted) = (dir.join("encrypted"), dir.join("decrypted"));
+        run(true, &source, &encrypted);
+        assert_eq!(tree(&source), plaintext);
+        let ciphertext = tree(&encrypted.join("source"));
+        assert_eq!(ciphertext.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>(),
+                   [Path::new("a.txt.fish"), &Path::new("sub").join("b.txt.fish")]);
+
+        /* Decrypting to a directory keeps the ciphertext where it is, and strips .fish */
+        run(false, &encrypted.join("source"), &decrypted);
+        assert_eq!(tree(&encrypted.join("source")), ciphertext);
+        assert_eq!(tree(&decrypted.join("source")), plaintext);
+
+        /* A single file to an output file, both ways */
+        let (file, single) = (source.join("a.txt"), dir.join("single.fish"));
+        run(true, &file, &single);
+        run(false, &single, &dir.join("single.txt"));
+        assert!(single.exists());
+        assert_eq!(fs::read(dir.join("single.txt")).unwrap(), b"first file");
+        assert_eq!(tree(&source), plaintext);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_bound_to_their_name_and_aad() {
+        let dir = scratch("bound");
+        let (moved, renamed) = (dir.join("moved"), dir.join("renamed"));
+        fs::create_dir_all(&moved).unwrap();
+        fs::create_dir_all(&renamed).unwrap();
+        let aad = || Some(b"backup host".to_vec());
+        let run = |config: FisherConfig| {
+            /* --verify and --verify-after can't read the header from the sidecar */
+            let verify = !config.length_preserving;
+            Fisher::from_config(FisherConfig { verify_written: verify, verify_after: verify, ..config }).unwrap()
+        };
+
+        /* Modified in memory (for the parity), streamed and with the header in the sidecar */
+        for (name, encrypting) in [
+            ("buffered", FisherConfig { aad: aad(), ecc: 4, ..config(&ALGORITHMS[1], Mode::Cbc) }),
+            ("streamed", FisherConfig { aad: aad(), ..config(&ALGORITHMS[2], Mode::Ctr) }),
+            ("length-preserving", FisherConfig { aad: aad(), meta: true, length_preserving: true, ..config(&ALGORITHMS[1], Mode::Ctr) }),
+        ] {
+            let path = dir.join(format!("{}.txt", name));
+            fs::write(&path, name).unwrap();
+            let encrypted = run(encrypting);
+            encrypted.modify_file(&path).unwrap();
+            assert!(encrypted.verify_outputs().is_empty(), "{}", name);
+
+            /* Another directory is fine, another name isn't */
+            let (output, sidecar) = (dir.join(format!("{}.txt.fish", name)), meta_path(&dir.join(format!("{}.txt.fish", name))));
+            let copy = |to: &Path, file_name: &str| {
+                fs::copy(&output, to.join(file_name)).unwrap();
+                if sidecar.exists() {
+                    fs::copy(&sidecar, meta_path(&to.join(file_name))).unwrap();
+                }
+                to.join(file_name)
+            };
+            let decrypt = |path: &Path, aad: Option<Vec<u8>>| {
+                Fisher::from_config(FisherConfig { crypt: false, aad, ..config(&ALGORITHMS[0], Mode::Cbc) }).unwrap().modify_file(path)
+            };
+            let error = |path: &Path, aad: Option<Vec<u8>>| decrypt(path, aad).unwrap_err().to_string();
+
+            let renamed = copy(&renamed, "other.txt.fish");
+            assert!(error(&renamed, aad()).contains("Authentication failed"), "{}", name);
+            assert!(error(&output, Some(b"other host".to_vec())).contains("Authentication failed"), "{}", name);
+            assert!(error(&output, None).contains("with --aad"), "{}", name);
+
+            decrypt(&copy(&moved, &format!("{}.txt.fish", name)), aad()).unwrap();
+            assert_eq!(fs::read(moved.join(format!("{}.txt", name))).unwrap(), name.as_bytes());
+        }
+
+        /* Unbound files can be renamed, and refuse an --aad they weren't encrypted with */
+        let path = dir.join("unbound.txt");
+        fs::write(&path, b"unbound").unwrap();
+        run(FisherConfig { bind_path: false, ..config(&ALGORITHMS[1], Mode::Cbc) }).modify_file(&path).unwrap();
+        let unbound = renamed.join("renamed.txt.fish");
+        fs::rename(dir.join("unbound.txt.fish"), &unbound).unwrap();
+        let decrypt = |aad| Fisher::from_config(FisherConfig { crypt: false, aad, ..config(&ALGORITHMS[1], Mode::Cbc) }).unwrap()
+            .modify_file(&unbound);
+        assert!(decrypt(aad()).unwrap_err().to_string().contains("without --aad"));
+        decrypt(None).unwrap();
+        assert_eq!(fs::read(renamed.join("renamed.txt")).unwrap(), b"unbound");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trees_bound_below_a_root_move_as_a_whole() {
+        let dir = scratch("relative-to");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("notes")).unwrap();
+        fs::write(root.join("a.txt"), b"top").unwrap();
+        fs::write(root.join("notes").join("a.txt"), b"nested").unwrap();
+        let run = |crypt: bool, path: &Path, relative_to: Option<&Path>| Fisher::from_config(FisherConfig {
+            crypt,
+            paths: vec![path.to_path_buf()],
+            relative_to: relative_to.map(Path::to_path_buf),
+            verify_written: crypt,
+            verify_after: crypt,
+            ..config(&ALGORITHMS[1], Mode::Cbc)
+        }).unwrap();
+
+        /* The root may be given in any spelling, the bound paths are the same */
+        run(true, &root, Some(&root.join("notes").join(".."))).run().unwrap();
+        let header = Header::parse(&fs::read(root.join("notes").join("a.txt.fish")).unwrap()).unwrap().unwrap();
+        assert_ne!(header.flags & FLAG_RELATIVE, 0);
+
+        /* Swapping two files of the same name in different directories is caught */
+        let (top, nested) = (root.join("a.txt.fish"), root.join("notes").join("a.txt.fish"));
+        let swapped = dir.join("swapped");
+        fs::rename(&top, &swapped).unwrap();
+        fs::rename(&nested, &top).unwrap();
+        fs::rename(&swapped, &nested).unwrap();
+        let decrypt = |path: &Path, relative_to: Option<&Path>| run(false, path, relative_to).modify_file(path);
+        assert!(decrypt(&top, Some(&root)).unwrap_err().to_string().contains("Authentication failed"));
+        fs::rename(&top, &swapped).unwrap();
+        fs::rename(&nested, &top).unwrap();
+        fs::rename(&swapped, &nested).unwrap();
+
+        /* Without the root, or with another one, the path can't be found */
+        assert!(decrypt(&top, None).unwrap_err().to_string().contains("--relative-to"));
+        assert!(decrypt(&top, Some(&root.join("notes"))).unwrap_err().to_string().contains("not below"));
+        assert!(decrypt(&top, Some(&dir)).unwrap_err().to_string().contains("Authentication failed"));
+
+        /* The whole tree moved elsewhere decrypts below its new root */
+        let moved = dir.join("moved");
+        fs::rename(&root, &moved).unwrap();
+        run(false, &moved, Some(&moved)).run().unwrap();
+        assert_eq!(tree(&moved), [(PathBuf::from("a.txt"), b"top".to_vec()),
+                                  (Path::new("notes").join("a.txt"), b"nested".to_vec())]);
+
+        /* Files bound to their name only decrypt whatever root is given */
+        run(true, &moved.join("a.txt"), None).run().unwrap();
+        run(false, &moved.join("a.txt.fish"), Some(&dir)).run().unwrap();
+        assert_eq!(fs::read(moved.join("a.txt")).unwrap(), b"top");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,927 @@
+use crate::FResult;
+use crate::ecc;
+use crate::mac::{FileMac, MacAlgorithm, tags_match};
+use zeroize::Zeroizing;
+
+use crate::r#enum::{Algorithm, ALGORITHMS, Kdf, MASTER_KEY_SIZE, TWEAK_SIZE, subkey};
+
+/* Bytes every encrypted file starts with */
+pub(crate) const MAGIC: [u8; 4] = *b"FSHR";
+
+/* Newest header version this build reads and writes, headers with a newer version are rejected */
+pub(crate) const VERSION: u8 = 8;
+
+/*
+    * Oldest Version Able to Describe a File
+
+    * Files are written with the oldest version their fields need, so older builds keep reading them:
+        * 1: the fields below without ecc
+        * 2: adds ecc, older readers must not decrypt the parity as ciphertext
+        * 3: adds permissions
+        * 4: adds FLAG_COMPRESSED, older readers must not take the compressed plaintext as the original
+        * 5: adds tweak, older readers would decrypt Threefish with the zero tweak
+        * 6: adds the master salt, older readers would take the key stretched from it as the key of the file
+        * 7: adds the MAC id, older readers would check a BLAKE3 or Poly1305 tag as HMAC-SHA256
+        * 8: adds FLAG_AAD, FLAG_PATH and FLAG_RELATIVE, older readers would check the MAC without the associated data
+*/
+const VERSION_BASE: u8 = 1;
+const VERSION_ECC: u8 = 2;
+const VERSION_PERMISSIONS: u8 = 3;
+const VERSION_COMPRESSED: u8 = 4;
+const VERSION_TWEAK: u8 = 5;
+const VERSION_MASTER: u8 = 6;
+const VERSION_MAC: u8 = 7;
+const VERSION_AAD: u8 = 8;
+
+/* Block chaining modes as stored in the header */
+const MODE_ECB: u8 = 0;
+const MODE_CBC: u8 = 1;
+const MODE_CTR: u8 = 2;
+
+/* How the blocks of a file are chained */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Mode {
+    /* Every block encrypted on its own, identical plaintext blocks give identical ciphertext blocks */
+    Ecb,
+    /* Each plaintext block XORed with the previous ciphertext block, starting from a one block IV */
+    Cbc,
+    /* The data XORed with encrypted counter blocks starting from a one block nonce, no padding */
+    Ctr,
+}
+
+impl Mode {
+    pub(crate) fn parse(name: &str) -> Option<Mode> {
+        /*
+            * Get the Mode Named on the Command Line
+
+            @param name: &str
+                * ecb, cbc or ctr
+            @return Option<Mode>
+                * The mode, None for unknown names
+        */
+        match name.to_lowercase().as_str() {
+            "ecb" => Some(Mode::Ecb),
+            "cbc" => Some(Mode::Cbc),
+            "ctr" => Some(Mode::Ctr),
+            _ => None
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        /*
+            * Get the Command Line Name of the Mode
+
+            @param self: Mode Instance
+            @return &str: ecb, cbc or ctr
+        */
+        match self {
+            Mode::Ecb => "ecb",
+            Mode::Cbc => "cbc",
+            Mode::Ctr => "ctr"
+        }
+    }
+}
+
+/* Key derivation functions as stored in the header, see Kdf in enum.rs */
+const KDF_SHA2: u8 = 0;
+const KDF_PBKDF2: u8 = 1;
+const KDF_ARGON2ID: u8 = 2;
+const KDF_RAW: u8 = 3;
+/* Version 6 and up, the key is HKDF'd from a master key stretched with PBKDF2 or Argon2id, see subkey() */
+const KDF_PBKDF2_HKDF: u8 = 4;
+const KDF_ARGON2ID_HKDF: u8 = 5;
+
+/*
+    * Flags Bitfield
+
+    * Bits not defined below are reserved for future use
+    * Writers set reserved bits to zero, readers ignore them. A change older readers must not
+      ignore bumps VERSION instead
+*/
+/*
+    * The Plaintext is PKCS#7 Padded
+
+    * Older readers truncate to the original size and get the plaintext right, except for files with
+      a whole block of padding (empty or whole-block plaintext), which they reject as mismatched
+*/
+pub(crate) const FLAG_PKCS7: u16 = 0x0001;
+/*
+    * The mac Field Authenticates the File
+
+    * The MAC named by the mac id (HMAC-SHA256 before version 7) over the header (mac zeroed) and the
+      ciphertext before any parity, see with_mac()
+    * Older readers decrypt without checking it
+*/
+pub(crate) const FLAG_MAC: u16 = 0x0002;
+/*
+    * The Plaintext was DEFLATE Compressed Before Encryption, see compression.rs
+
+    * The original size is the size of the compressed plaintext
+    * Only set from version 4 on
+*/
+pub(crate) const FLAG_COMPRESSED: u16 = 0x0004;
+/*
+    * The MAC Also Covers Associated Data Given With --aad, see associate()
+
+    * The data itself is not stored, decrypting needs the same --aad
+    * Only set from version 8 on
+*/
+pub(crate) const FLAG_AAD: u16 = 0x0008;
+/*
+    * The MAC Also Covers the Name of the Plaintext File, see associate()
+
+    * The file only decrypts under the name it was encrypted from (with .fish or its algorithm extension)
+    * Only set from version 8 on
+*/
+pub(crate) const FLAG_PATH: u16 = 0x0010;
+/*
+    * The Bound Name is the Path of the File Below a --relative-to Root, With FLAG_PATH
+
+    * The directories below the root are bound too, decrypting needs the root the file is below now
+    * Only set from version 8 on
+*/
+pub(crate) const FLAG_RELATIVE: u16 = 0x0020;
+pub(crate) const FLAGS_RESERVED: u16 = !(FLAG_PKCS7 | FLAG_MAC | FLAG_COMPRESSED | FLAG_AAD | FLAG_PATH | FLAG_RELATIVE);
+
+/* Size in bytes of the salt and MAC fields */
+pub(crate) const SALT_SIZE: usize = 16;
+pub(crate) const MAC_SIZE: usize = 32;
+
+/* Largest possible header, Argon2id parameters, a master salt and a 255 byte IV, see the layout below */
+pub(crate) const MAX_SIZE: usize = MAGIC.len() + 4 + 2 + 1 + 4 + TWEAK_SIZE + 1 + SALT_SIZE + 12 + SALT_SIZE + 1 + u8::MAX as usize + 8
+    + MAC_SIZE;
+
+/*
+    * Header Written in Front of the Ciphertext of Every Encrypted File
+
+    * Layout, integers little-endian:
+        * magic          4 bytes   "FSHR"
+        * version        1 byte
+        * algorithm      1 byte    AlgorithmInfo::id, identifies the algorithm and block size
+        * mode           1 byte    MODE_*, see Mode
+        * kdf            1 byte    KDF_*
+        * flags          2 bytes   see FLAGS_RESERVED
+        * ecc            1 byte    version 2 and up, Reed-Solomon parity bytes per codeword, 0 without (see ecc.rs)
+        * permissions    4 bytes   version 3 and up, Unix mode bits of the original file, restored on decryption
+        * tweak         16 bytes   version 5 and up, random Threefish tweak of the file, zero for other algorithms
+        * mac id         1 byte    version 7 and up, MAC_* (see mac.rs), HMAC-SHA256 before
+        * salt          16 bytes   zero when the KDF is unsalted
+        * kdf parameters           older readers reject an unknown KDF before reaching them
+                         0 bytes   KDF_SHA2, KDF_RAW
+                         4 bytes   KDF_PBKDF2: iterations
+                        12 bytes   KDF_ARGON2ID: memory in KiB, passes, lanes
+                        20 bytes   KDF_PBKDF2_HKDF: iterations, master salt
+                        28 bytes   KDF_ARGON2ID_HKDF: memory in KiB, passes, lanes, master salt
+        * iv length      1 byte
+        * iv             iv length bytes, empty when the mode takes no IV
+        * original size  8 bytes   plaintext length before padding (after compression with FLAG_COMPRESSED)
+        * mac           32 bytes   zero when the file is not authenticated (no FLAG_MAC), Poly1305 tags followed by their key commitment
+
+    * With FLAG_AAD or FLAG_PATH the MAC goes on over the associated data after the header, each part
+      (the --aad string, then the file name) as its length (8 bytes) followed by its bytes
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Header {
+    pub(crate) version: u8,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) block_size: usize,
+    pub(crate) mode: Mode,
+    pub(crate) kdf: Kdf,
+    pub(crate) flags: u16,
+    pub(crate) ecc: u8,
+    /* 0 when not recorded, e.g. for stdin or on platforms without mode bits */
+    pub(crate) permissions: u32,
+    pub(crate) tweak: [u8; TWEAK_SIZE],
+    pub(crate) mac_algorithm: MacAlgorithm,
+    pub(crate) salt: [u8; SALT_SIZE],
+    /* The salt of the run the master key was stretched with, None when the KDF keyed the file directly */
+    pub(crate) master_salt: Option<[u8; SALT_SIZE]>,
+    pub(crate) iv: Vec<u8>,
+    pub(crate) original_size: u64,
+    pub(crate) mac: [u8; MAC_SIZE],
+    /* Associated data the MAC covers after the header, never written, see associate() */
+    pub(crate) associated: Vec<u8>,
+}
+
+impl Header {
+    pub(crate) fn new(algorithm: Algorithm, block_size: usize, original_size: u64) -> Header {
+        /*
+            * Create a Header for an ECB Encrypted, PKCS#7 Padded File with an Unsalted Key
+
+            @param algorithm: Algorithm
+                * The algorithm the file is encrypted with
+            @param block_size: usize
+                * The block size in bytes the file is encrypted with
+            @param original_size: u64
+                * The plaintext length in bytes
+            @return Header
+        */
+        Header {
+            version: VERSION_BASE,
+            algorithm,
+            block_size,
+            mode: Mode::Ecb,
+            kdf: Kdf::Sha2,
+            flags: FLAG_PKCS7,
+            ecc: 0,
+            permissions: 0,
+            tweak: [0; TWEAK_SIZE],
+            mac_algorithm: MacAlgorithm::HmacSha256,
+            salt: [0; SALT_SIZE],
+            master_salt: None,
+            iv: Vec::new(),
+            original_size,
+            mac: [0; MAC_SIZE],
+            associated: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_mode(mut self, mode: Mode, iv: Vec<u8>) -> Header {
+        /*
+            * Record How the Blocks are Chained
+
+            * CTR needs no padding, so FLAG_PKCS7 is cleared for it
+
+            @param self: Header Instance
+            @param mode: Mode
+                * The mode the file is encrypted in
+            @param iv: Vec<u8>
+                * The random IV (CBC) or nonce (CTR) the file was encrypted with, one block long, empty for ECB
+            @return Header: The header in the given mode
+        */
+        self.mode = mode;
+        self.iv = iv;
+        if mode == Mode::Ctr {
+            self.flags &= !FLAG_PKCS7;
+        }
+        self
+    }
+
+    pub(crate) fn with_kdf(mut self, kdf: Kdf, salt: [u8; SALT_SIZE], master_salt: Option<[u8; SALT_SIZE]>) -> Header {
+        /*
+            * Record How the Key of the File was Derived
+
+            @param self: Header Instance
+            @param kdf: Kdf
+                * The key derivation function and its parameters
+            @param salt: [u8; SALT_SIZE]
+                * The random salt of the file
+            @param master_salt: Option<[u8; SALT_SIZE]>
+                * The salt of the run when the KDF stretched a master key the file key was derived from
+                  (see subkey()), None when it derived the file key itself
+            @return Header: The header with the given KDF, at the version recording the master salt when given
+        */
+        self.kdf = kdf;
+        self.salt = salt;
+        self.master_salt = master_salt.filter(|_| kdf.stretching());
+        if self.master_salt.is_some() {
+            self.version = self.version.max(VERSION_MASTER);
+        }
+        self
+    }
+
+    pub(crate) fn with_ecc(mut self, parity: u8) -> Header {
+        /*
+            * Record Reed-Solomon Parity Following the Ciphertext
+
+            @param self: Header Instance
+            @param parity: u8
+                * Parity bytes per codeword, 0 for none
+            @return Header: The header, at the version recording ecc when parity is added
+        */
+        self.ecc = parity;
+        if parity > 0 {
+            self.version = self.version.max(VERSION_ECC);
+        }
+        self
+    }
+
+    pub(crate) fn with_permissions(mut self, permissions: u32) -> Header {
+        /*
+            * Record the Mode Bits of the Original File
+
+            @param self: Header Instance
+            @param permissions: u32
+                * The Unix mode bits (e.g. 0o755), 0 for none
+            @return Header: The header, at the version recording permissions when given
+        */
+        self.permissions = permissions;
+        if permissions > 0 {
+            self.version = self.version.max(VERSION_PERMISSIONS);
+        }
+        self
+    }
+
+    pub(crate) fn with_tweak(mut self, tweak: [u8; TWEAK_SIZE]) -> Header {
+        /*
+            * Record the Threefish Tweak the File was Encrypted With
+
+            @param self: Header Instance
+            @param tweak: [u8; TWEAK_SIZE]
+                * The tweak, zero for none
+            @return Header: The header, at the version recording the tweak when given
+        */
+        self.tweak = tweak;
+        if tweak != [0; TWEAK_SIZE] {
+            self.version = self.version.max(VERSION_TWEAK);
+        }
+        self
+    }
+
+    pub(crate) fn with_mac_algorithm(mut self, mac_algorithm: MacAlgorithm) -> Header {
+        /*
+            * Record the MAC the File is Authenticated With, see with_mac()
+
+            @param self: Header Instance
+            @param mac_algorithm: MacAlgorithm
+                * The MAC
+            @return Header: The header, at the version recording the MAC when it isn't HMAC-SHA256
+        */
+        self.mac_algorithm = mac_algorithm;
+        if mac_algorithm != MacAlgorithm::HmacSha256 {
+            self.version = self.version.max(VERSION_MAC);
+        }
+        self
+    }
+
+    pub(crate) fn with_compression(mut self, compressed: bool) -> Header {
+        /*
+            * Record Whether the Plaintext was Compressed Before Encryption
+
+            @param self: Header Instance
+            @param compressed: bool
+                * Whether the encrypted plaintext is a DEFLATE stream of the original
+            @return Header: The header, with FLAG_COMPRESSED and at the version recording it when compressed
+        */
+        if compressed {
+            self.flags |= FLAG_COMPRESSED;
+            self.version = self.version.max(VERSION_COMPRESSED);
+        }
+        self
+    }
+
+    pub(crate) fn with_associated_data(mut self, aad: Option<&[u8]>, path: Option<&[u8]>, relative: bool) -> Header {
+        /*
+            * Bind the Given Associated Data to the File, see FLAG_AAD, FLAG_PATH and FLAG_RELATIVE
+
+            * Call before with_mac() or begin_mac(), the data is only authenticated
+
+            @param self: Header Instance
+            @param aad: Option<&[u8]>
+                * The --aad string, None for none
+            @param path: Option<&[u8]>
+                * The name of the plaintext file, None to leave it unbound
+            @param relative: bool
+                * Whether the name is the path of the file below a --relative-to root
+            @return Header: The header with the flags of the given parts, at the version recording them when given
+        */
+        if aad.is_some() {
+            self.flags |= FLAG_AAD;
+        }
+        if path.is_some() {
+            self.flags |= FLAG_PATH;
+            if relative {
+                self.flags |= FLAG_RELATIVE;
+            }
+        }
+        if aad.is_some() || path.is_some() {
+            self.version = self.version.max(VERSION_AAD);
+        }
+        self.associated = associated_data(aad, path);
+        self
+    }
+
+    pub(crate) fn associate(&mut self, aad: Option<&[u8]>, path: Option<&[u8]>) -> FResult<()> {
+        /*
+            * Supply the Associated Data of a File Being Decrypted or Checked
+
+            * Must match the flags: a file bound to an --aad string can't be checked without one and
+              the other way around, which would otherwise only fail as a wrong password
+
+            @param self: Header Instance
+            @param aad: Option<&[u8]>
+                * The --aad string, None for none
+            @param path: Option<&[u8]>
+                * The name of the plaintext file, only used with FLAG_PATH, None when there is none (stdin)
+            @return FResult: Result<(), Box<dyn Error>>
+                * An error when the given data doesn't match the flags
+        */
+        match (self.flags & FLAG_AAD != 0, aad.is_some()) {
+            (true, false) => return Err("it was encrypted with --aad, pass the same --aad to decrypt it".into()),
+            (false, true) => return Err("it was encrypted without --aad, leave --aad out to decrypt it".into()),
+            _ => {}
+        }
+        let path = match (self.flags & FLAG_PATH != 0, path) {
+            (true, None) => return Err("it is bound to the name of its file, it can't be decrypted from stdin".into()),
+            (true, path) => path,
+            (false, _) => None
+        };
+
+        self.associated = associated_data(aad, path);
+        Ok(())
+    }
+
+    pub(crate) fn with_mac(mut self, key: &[u8], ciphertext: &[u8]) -> FResult<Header> {
+        /*
+            * Authenticate the Header and the Given Ciphertext
+
+            * Call last, the MAC covers every other field
+
+            @param self: Header Instance
+            @param key: &[u8]
+                * The MAC key of the file, see mac_key()
+            @param ciphertext: &[u8]
+                * The ciphertext following the header, before any parity
+            @return FResult: Result<Header, Box<dyn Error>>
+                * The header with FLAG_MAC set and its mac filled in
+        */
+        let mut mac = self.begin_mac(key)?;
+        mac.update(ciphertext);
+        self.finish_mac(mac);
+        Ok(self)
+    }
+
+    pub(crate) fn begin_mac(&mut self, key: &[u8]) -> FResult<Box<dyn FileMac>> {
+        /*
+            * Start Authenticating a Ciphertext Written Piece by Piece, see with_mac()
+
+            * Every other field must be final, feed the ciphertext to the returned state and pass it
+              to finish_mac()
+
+            @param self: Header Instance
+            @param key: &[u8]
+                * The MAC key of the file, see mac_key()
+            @return FResult: Result<Box<dyn FileMac>, Box<dyn Error>>
+                * The MAC state over the header, FLAG_MAC is set
+        */
+        self.flags |= FLAG_MAC;
+        self.mac_state(key)
+    }
+
+    pub(crate) fn finish_mac(&mut self, mac: Box<dyn FileMac>) {
+        /*
+            * Fill in the mac Field from the State Returned by begin_mac()
+
+            @param self: Header Instance
+            @param mac: Box<dyn FileMac>
+                * The MAC state, fed with the whole ciphertext
+        */
+        self.mac = mac.finalize();
+    }
+
+    pub(crate) fn key(&self, passphrase: &[u8]) -> FResult<Zeroizing<Vec<u8>>> {
+        /*
+            * Derive the Raw Cipher Key of the File from the Given Passphrase
+
+            * From the master key of its run if it has a master salt (see subkey()), otherwise with its
+              KDF over its salt (see Kdf::derive())
+
+            @param self: Header Instance
+            @param passphrase: &[u8]
+                * The passphrase (or key file contents) the file was encrypted with
+            @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+                * AlgorithmInfo::key_size bytes, wiped from memory when dropped
+        */
+        match &self.master_salt {
+            Some(master_salt) => subkey(self.algorithm, self.block_size,
+                                        &self.kdf.stretch(passphrase, master_salt, MASTER_KEY_SIZE)?, &self.salt),
+            None => self.kdf.derive(self.algorithm, self.block_size, passphrase, &self.salt)
+        }
+    }
+
+    pub(crate) fn verify_mac(&self, key: &[u8], ciphertext: &[u8]) -> FResult<bool> {
+        /*
+            * Check the MAC of the Header and the Given Ciphertext in Constant Time
+
+            @param self: Header Instance
+            @param key: &[u8]
+                * The MAC key of the file, see mac_key()
+            @param ciphertext: &[u8]
+                * The ciphertext following the header, after any parity was corrected and stripped
+            @return FResult: Result<bool, Box<dyn Error>>
+                * Whether the MAC matches, true for files without FLAG_MAC
+        */
+        let mut mac = self.mac_state(key)?;
+        mac.update(ciphertext);
+        Ok(self.mac_matches(mac))
+    }
+
+    pub(crate) fn mac_matches(&self, mac: Box<dyn FileMac>) -> bool {
+        /*
+            * Check the mac Field Against a State from mac_state() in Constant Time
+
+            @param self: Header Instance
+            @param mac: Box<dyn FileMac>
+                * The MAC state, fed with the whole ciphertext
+            @return bool: Whether the MAC matches, true for files without FLAG_MAC
+        */
+        self.flags & FLAG_MAC == 0 || tags_match(&mac.finalize(), &self.mac)
+    }
+
+    pub(crate) fn mac_state(&self, key: &[u8]) -> FResult<Box<dyn FileMac>> {
+        /*
+            * Feed the Header with its mac Zeroed, and its Associated Data, to the MAC of the File
+
+            @param self: Header Instance
+            @param key: &[u8]
+                * The MAC key of the file, see mac_key()
+            @return FResult: Result<Box<dyn FileMac>, Box<dyn Error>>
+                * The MAC state, to be fed the ciphertext following the header
+        */
+        let mut mac = self.mac_algorithm.start(key, &self.salt, &self.iv, &self.tweak)?;
+        let mut bytes = self.to_bytes()?;
+        let len = bytes.len();
+        bytes[len - MAC_SIZE..].fill(0);
+        mac.update(&bytes);
+        mac.update(&self.associated);
+        Ok(mac)
+    }
+
+    pub(crate) fn to_bytes(&self) -> FResult<Vec<u8>> {
+        /*
+            * Serialize the Header
+
+            @param self: Header Instance
+            @return FResult: Result<Vec<u8>, Box<dyn Error>>
+                * The header bytes, to be written in front of the ciphertext
+        */
+        let id = ALGORITHMS.iter()
+            .find(|info| info.algorithm == self.algorithm && info.block_size == self.block_size)
+            .ok_or(format!("No header id for {:?} with {} byte blocks", self.algorithm, self.block_size))?
+            .id;
+        let iv_len = u8::try_from(self.iv.len()).map_err(|_| "IV too long for the header")?;
+
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(self.version);
+        bytes.push(id);
+        bytes.push(match self.mode {
+            Mode::Ecb => MODE_ECB,
+            Mode::Cbc => MODE_CBC,
+            Mode::Ctr => MODE_CTR
+        });
+        bytes.push(match (self.kdf, self.master_salt.is_some()) {
+            (Kdf::Sha2, _) => KDF_SHA2,
+            (Kdf::Pbkdf2 { .. }, false) => KDF_PBKDF2,
+            (Kdf::Pbkdf2 { .. }, true) => KDF_PBKDF2_HKDF,
+            (Kdf::Argon2id { .. }, false) => KDF_ARGON2ID,
+            (Kdf::Argon2id { .. }, true) => KDF_ARGON2ID_HKDF,
+            (Kdf::Raw, _) => KDF_RAW
+        });
+        bytes.extend_from_slice(&(self.flags & !FLAGS_RESERVED).to_le_bytes());
+        if self.version >= VERSION_ECC {
+            bytes.push(self.ecc);
+        }
+        if self.version >= VERSION_PERMISSIONS {
+            bytes.extend_from_slice(&self.permissions.to_le_bytes());
+        }
+        if self.version >= VERSION_TWEAK {
+            bytes.extend_from_slice(&self.tweak);
+        }
+        if self.version >= VERSION_MAC {
+            bytes.push(self.mac_algorithm.id());
+        }
+        bytes.extend_from_slice(&self.salt);
+        match self.kdf {
+            Kdf::Sha2 | Kdf::Raw => {}
+            Kdf::Pbkdf2 { iterations } => bytes.extend_from_slice(&iterations.to_le_bytes()),
+            Kdf::Argon2id { memory, time, parallelism } => {
+                for param in [memory, time, parallelism] {
+                    bytes.extend_from_slice(&param.to_le_bytes());
+                }
+            }
+        }
+        if let Some(master_salt) = &self.master_salt {
+            bytes.extend_from_slice(master_salt);
+        }
+        bytes.push(iv_len);
+        bytes.extend_from_slice(&self.iv);
+        bytes.extend_from_slice(&self.original_size.to_le_bytes());
+        bytes.extend_from_slice(&self.mac);
+
+        Ok(bytes)
+    }
+
+    pub(crate) fn parse(data: &[u8]) -> FResult<Option<Header>> {
+        /*
+            * Deserialize the Header at the Start of the Given Bytes
+
+            @param data: &[u8]
+                * The contents of an encrypted file
+            @return FResult: Result<Option<Header>, Box<dyn Error>>
+                * The header, None if the data does not start with MAGIC (headerless file)
+                * The ciphertext starts at Header::size() bytes
+        */
+        if !data.starts_with(&MAGIC) {
+            return Ok(None);
+        }
+
+        let mut reader = Reader { data, offset: MAGIC.len() };
+
+        let version = reader.take(1)?[0];
+        if version > VERSION {
+            return Err(format!("Header version {} was written by a newer fisher (this one reads up to {}), please upgrade",
+                               version, VERSION).into());
+        }
+
+        let id = reader.take(1)?[0];
+        let info = ALGORITHMS.iter().find(|info| info.id == id)
+            .ok_or(format!("Unknown algorithm id {} in header", id))?;
+
+        let mode = match reader.take(1)?[0] {
+            MODE_ECB => Mode::Ecb,
+            MODE_CBC => Mode::Cbc,
+            MODE_CTR => Mode::Ctr,
+            mode => return Err(format!("Unknown mode {} in header", mode).into())
+        };
+        let kdf = reader.take(1)?[0];
+        let newest_kdf = match version >= VERSION_MASTER {
+            true => KDF_ARGON2ID_HKDF,
+            false => KDF_RAW
+        };
+        if kdf > newest_kdf {
+            return Err(format!("Unknown key derivation {} in header", kdf).into());
+        }
+
+        let mut flags = u16::from_le_bytes(reader.take(2)?.try_into()?) & !FLAGS_RESERVED;
+        /* Reserved before version 4 and 8, so ignored as such */
+        if version < VERSION_COMPRESSED {
+            flags &= !FLAG_COMPRESSED;
+        }
+        if version < VERSION_AAD {
+            flags &= !(FLAG_AAD | FLAG_PATH | FLAG_RELATIVE);
+        }
+        let ecc = match version >= VERSION_ECC {
+            true => reader.take(1)?[0],
+            false => 0
+        };
+        /* ecc::parity_for_ratio() never writes more parity than data */
+        if ecc > 128 {
+            return Err(format!("Invalid error correction parity {} in header", ecc).into());
+        }
+        let permissions = match version >= VERSION_PERMISSIONS {
+            true => reader.u32()?,
+            false => 0
+        };
+        let tweak = match version >= VERSION_TWEAK {
+            true => reader.take(TWEAK_SIZE)?.try_into()?,
+            false => [0; TWEAK_SIZE]
+        };
+        let mac_algorithm = match version >= VERSION_MAC {
+            true => {
+                let id = reader.take(1)?[0];
+                MacAlgorithm::from_id(id).ok_or(format!("Unknown MAC {} in header", id))?
+            }
+            false => MacAlgorithm::HmacSha256
+        };
+        let salt = reader.take(SALT_SIZE)?.try_into()?;
+        let (kdf, master) = match kdf {
+            KDF_PBKDF2 | KDF_PBKDF2_HKDF => (Kdf::Pbkdf2 { iterations: reader.u32()? }, kdf == KDF_PBKDF2_HKDF),
+            KDF_ARGON2ID | KDF_ARGON2ID_HKDF => (Kdf::Argon2id { memory: reader.u32()?, time: reader.u32()?, parallelism: reader.u32()? },
+                                                 kdf == KDF_ARGON2ID_HKDF),
+            KDF_RAW => (Kdf::Raw, false),
+            _ => (Kdf::Sha2, false)
+        };
+        let master_salt = match master {
+            true => Some(reader.take(SALT_SIZE)?.try_into()?),
+            false => None
+        };
+        if kdf == (Kdf::Pbkdf2 { iterations: 0 }) {
+            return Err("PBKDF2 header has no iterations".into());
+        }
+        let iv_len = reader.take(1)?[0] as usize;
+        let iv = reader.take(iv_len)?.to_vec();
+        if mode != Mode::Ecb && iv.len() != info.block_size {
+            return Err(format!("{} header has a {} byte IV, expected {}", mode.name().to_uppercase(), iv.len(), info.block_size).into());
+        }
+        let original_size = u64::from_le_bytes(reader.take(8)?.try_into()?);
+        let mac = reader.take(MAC_SIZE)?.try_into()?;
+
+        Ok(Some(Header {
+            version,
+            algorithm: info.algorithm,
+            block_size: info.block_size,
+            mode,
+            kdf,
+            flags,
+            ecc,
+            permissions,
+            tweak,
+            mac_algorithm,
+            salt,
+            master_salt,
+            iv,
+            original_size,
+            mac,
+            associated: Vec::new(),
+        }))
+    }
+
+    pub(crate) fn projected_size(&self) -> u64 {
+        /*
+            * Get the Size of the File this Header Describes, Without Encrypting it
+
+            @param self: Header Instance
+            @return u64
+                * The header, the ciphertext of original_size bytes (padded unless CTR) and its parity
+        */
+        let block_size = self.block_size as u64;
+        let ciphertext = match (self.mode, self.flags & FLAG_PKCS7 != 0) {
+            (Mode::Ctr, _) => self.original_size,
+            (_, true) => (self.original_size / block_size + 1) * block_size,
+            (_, false) => self.original_size.div_ceil(block_size) * block_size
+        };
+        self.size() as u64 + ecc::encoded_size(ciphertext, self.ecc)
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        /*
+            * Get the Serialized Size of the Header
+
+            @param self: Header Instance
+            @return usize: The number of bytes to_bytes() writes and parse() consumes
+        */
+        let ecc = match self.version >= VERSION_ECC {
+            true => 1,
+            false => 0
+        };
+        let permissions = match self.version >= VERSION_PERMISSIONS {
+            true => 4,
+            false => 0
+        };
+        let tweak = match self.version >= VERSION_TWEAK {
+            true => TWEAK_SIZE,
+            false => 0
+        };
+        let mac = match self.version >= VERSION_MAC {
+            true => 1,
+            false => 0
+        };
+        let kdf = match self.kdf {
+            Kdf::Sha2 | Kdf::Raw => 0,
+            Kdf::Pbkdf2 { .. } => 4,
+            Kdf::Argon2id { .. } => 12
+        } + self.master_salt.map_or(0, |master_salt| master_salt.len());
+        MAGIC.len() + 4 + 2 + ecc + permissions + tweak + mac + SALT_SIZE + kdf + 1 + self.iv.len() + 8 + MAC_SIZE
+    }
+}
+
+fn associated_data(aad: Option<&[u8]>, path: Option<&[u8]>) -> Vec<u8> {
+    /*
+        * Encode the Associated Data the MAC Covers After the Header
+
+        @param aad: Option<&[u8]>
+            * The --aad string, None for none
+        @param path: Option<&[u8]>
+            * The bound file name, None for none
+        @return Vec<u8>: The length and bytes of each given part, in this order
+    */
+    let mut associated = Vec::new();
+    for part in [aad, path].into_iter().flatten() {
+        associated.extend_from_slice(&(part.len() as u64).to_le_bytes());
+        associated.extend_from_slice(part);
+    }
+    associated
+}
+
+/* Cursor over the header bytes, failing cleanly on truncated headers */
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> FResult<&'a [u8]> {
+        /*
+            * Take the Next len Bytes
+
+            @param self: Reader Instance
+            @param len: usize
+                * The number of bytes to take
+            @return FResult: Result<&[u8], Box<dyn Error>>
+                * The bytes, or an error if the header is truncated
+        */
+        let bytes = self.data.get(self.offset..self.offset + len).ok_or("Truncated header")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> FResult<u32> {
+        /*
+            * Take the Next Little-Endian u32
+
+            @param self: Reader Instance
+            @return FResult: Result<u32, Box<dyn Error>>
+        */
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> Vec<Header> {
+        /*
+            * Build a Header of Every Version, Each Adding the Field its Version Introduced
+
+            @return Vec<Header>: Versions 1 to VERSION, in order
+        */
+        let v1 = Header::new(Algorithm::Twofish, 16, 1000).with_mode(Mode::Cbc, vec![7; 16])
+            .with_kdf(Kdf::Pbkdf2 { iterations: 10 }, [1; SALT_SIZE], None);
+        let v2 = v1.clone().with_ecc(16);
+        let v3 = v2.clone().with_permissions(0o640);
+        let v4 = v3.clone().with_compression(true);
+        let v5 = Header::new(Algorithm::Threefish, 64, 0).with_mode(Mode::Ctr, vec![3; 64])
+            .with_kdf(Kdf::ARGON2ID_DEFAULT, [2; SALT_SIZE], None).with_tweak([5; TWEAK_SIZE]);
+        let v6 = v5.clone().with_kdf(Kdf::ARGON2ID_DEFAULT, [2; SALT_SIZE], Some([4; SALT_SIZE]));
+        let mut v7 = Header::new(Algorithm::Blowfish, 8, 9).with_kdf(Kdf::Raw, [0; SALT_SIZE], None)
+            .with_mac_algorithm(MacAlgorithm::Poly1305);
+        v7.flags |= FLAG_MAC;
+        v7.mac = [6; MAC_SIZE];
+        let mut v8 = v7.clone();
+        v8.version = VERSION_AAD;
+        v8.flags |= FLAG_AAD | FLAG_PATH | FLAG_RELATIVE;
+        vec![v1, v2, v3, v4, v5, v6, v7, v8]
+    }
+
+    #[test]
+    fn every_version_round_trips() {
+        let headers = headers();
+        assert_eq!(headers.len(), VERSION as usize);
+        for (version, header) in (1..).zip(headers) {
+            assert_eq!(header.version, version);
+            let mut bytes = header.to_bytes().unwrap();
+            assert_eq!(bytes.len(), header.size());
+            assert!(bytes.len() <= MAX_SIZE);
+
+            /* The ciphertext following the header is left alone */
+            bytes.extend_from_slice(b"ciphertext");
+            assert_eq!(Header::parse(&bytes).unwrap(), Some(header), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let headers = headers();
+        let bytes = |version: usize| headers[version - 1].to_bytes().unwrap();
+        let error = |bytes: &[u8]| Header::parse(bytes).unwrap_err().to_string();
+        let changed = |version: usize, offset: usize, byte: u8| {
+            let mut bytes = bytes(version);
+            bytes[offset] = byte;
+            bytes
+        };
+
+        /* Offsets of the version, algorithm, mode, KDF and ecc bytes, and the MAC id of version 7 behind the tweak */
+        assert!(error(&changed(1, 4, VERSION + 1)).contains("newer fisher"));
+        assert!(error(&changed(1, 5, 0)).contains("Unknown algorithm id 0"));
+        assert!(error(&changed(1, 6, 3)).contains("Unknown mode 3"));
+        assert!(error(&changed(7, 7, KDF_ARGON2ID_HKDF + 1)).contains("Unknown key derivation"));
+        assert!(error(&changed(5, 7, KDF_PBKDF2_HKDF)).contains("Unknown key derivation"));
+        assert!(error(&changed(2, 10, 129)).contains("Invalid error correction parity 129"));
+        assert!(error(&changed(7, 10 + 1 + 4 + TWEAK_SIZE, 9)).contains("Unknown MAC 9"));
+
+        let unstretched = Header::new(Algorithm::Twofish, 16, 0).with_kdf(Kdf::Pbkdf2 { iterations: 0 }, [1; SALT_SIZE], None);
+        assert!(error(&unstretched.to_bytes().unwrap()).contains("no iterations"));
+
+        /* Data without the magic is a headerless file, data cut short anywhere after it a truncated header */
+        assert_eq!(Header::parse(b"FISH and more").unwrap(), None);
+        assert_eq!(Header::parse(&[]).unwrap(), None);
+        for version in 1..=VERSION as usize {
+            let bytes = bytes(version);
+            for len in MAGIC.len()..bytes.len() {
+                assert_eq!(error(&bytes[..len]), "Truncated header", "version {} cut at {}", version, len);
+            }
+        }
+    }
+
+    #[test]
+    fn associated_data_is_authenticated() {
+        let key = [9; MAC_SIZE];
+        let header = Header::new(Algorithm::Twofish, 16, 5).with_kdf(Kdf::Raw, [0; SALT_SIZE], None)
+            .with_associated_data(Some(b"aad"), Some(b"a.txt"), false)
+            .with_mac(&key, b"ciphertext").unwrap();
+        assert_eq!((header.version, header.flags & (FLAG_AAD | FLAG_PATH)), (VERSION_AAD, FLAG_AAD | FLAG_PATH));
+
+        /* The data is not written, only what is supplied when reading the header again is checked */
+        let parsed = Header::parse(&header.to_bytes().unwrap()).unwrap().unwrap();
+        let verify = |aad: Option<&[u8]>, path: Option<&[u8]>| {
+            let mut header = parsed.clone();
+            header.associate(aad, path).map(|_| header.verify_mac(&key, b"ciphertext").unwrap())
+        };
+        assert!(verify(Some(b"aad"), Some(b"a.txt")).unwrap());
+        assert!(!verify(Some(b"other"), Some(b"a.txt")).unwrap());
+        assert!(!verify(Some(b"aad"), Some(b"b.txt")).unwrap());
+        /* Moving bytes between the parts changes the lengths in front of them */
+        assert!(!verify(Some(b"aada"), Some(b".txt")).unwrap());
+        assert!(verify(None, Some(b"a.txt")).unwrap_err().to_string().contains("with --aad"));
+        assert!(verify(Some(b"aad"), None).unwrap_err().to_string().contains("stdin"));
+
+        /* Without the flags nothing is bound, and an --aad given anyway is reported */
+        let mut unbound = Header::new(Algorithm::Twofish, 16, 5).with_associated_data(None, None, true);
+        assert_eq!((unbound.version, unbound.flags, unbound.associated.len()), (VERSION_BASE, FLAG_PKCS7, 0));
+        unbound.associate(None, Some(b"a.txt")).unwrap();
+        assert!(unbound.associated.is_empty());
+        assert!(unbound.associate(Some(b"aad"), None).unwrap_err().to_string().contains("without --aad"));
+    }
+}
@@ -0,0 +1,423 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use zeroize::Zeroizing;
+
+use crate::fish::{Fisher, FisherConfig};
+use crate::header::Header;
+use crate::meta::Meta;
+
+/*
+    * Fisher - Encrypt or Decrypt Files and Directories Using Blowfish, Twofish or Threefish
+
+    * The fisher command line is cli::run(), other programs encrypt and decrypt with encrypt_file(),
+      decrypt_file() or a FisherBuilder for whole trees, new_cipher() gives the keyed ciphers themselves
+    * Files are written in the same format as the command line writes them, so either can decrypt
+      what the other encrypted
+*/
+
+pub mod cli;
+
+mod blocks;
+mod compression;
+mod concurrency;
+mod container;
+mod doctor;
+mod ecc;
+mod encoding;
+mod exclude;
+mod r#enum;
+mod fish;
+mod header;
+mod mac;
+mod meta;
+mod pool;
+mod progress;
+mod report;
+mod shares;
+mod shred;
+
+pub use crate::r#enum::{Algorithm, Fishers};
+
+pub type FResult<T> = Result<T, Box<dyn Error>>;
+
+pub fn encrypt_file(path: &Path, algorithm: Algorithm, block_size: usize, passphrase: &str) -> FResult<()> {
+    /*
+        * Encrypt the Given File, Renaming it to <name>.fish
+
+        @param path: &Path
+            * The file to encrypt
+        @param algorithm: Algorithm
+            * The algorithm to encrypt with
+        @param block_size: usize
+            * The block size in bytes, 8 for Blowfish, 16 for Twofish, 32, 64 or 128 for Threefish
+        @param passphrase: &str
+            * The passphrase, used as it is
+        @return FResult: Result<(), Box<dyn Error>>
+    */
+    FisherBuilder::new(passphrase)
+        .encrypt()
+        .algorithm(algorithm, block_size)
+        .path(path)
+        .run()
+        .map(|_| ())
+}
+
+pub fn decrypt_file(path: &Path, passphrase: &str) -> FResult<()> {
+    /*
+        * Decrypt the Given File, Renaming <name>.fish Back to <name>
+
+        * The algorithm and block size are read from the header of the file
+
+        @param path: &Path
+            * The file to decrypt, named as encrypt_file() left it
+        @param passphrase: &str
+            * The passphrase it was encrypted with, used as it is
+        @return FResult: Result<(), Box<dyn Error>>
+    */
+    FisherBuilder::new(passphrase)
+        .decrypt()
+        .path(path)
+        .run()
+        .map(|_| ())
+}
+
+pub fn derive_key(algorithm: Algorithm, block_size: usize, passphrase: &[u8], salt: &[u8]) -> FResult<Zeroizing<Vec<u8>>> {
+    /*
+        * Derive the Raw Cipher Key fisher Encrypts Files With, Given their Salt
+
+        * PBKDF2-HMAC-SHA256 over the passphrase and salt with 100000 iterations, the key being the first
+          56 bytes for Blowfish, 32 for Twofish and as many as the block for Threefish
+        * This is the key of files encrypted with --salt (and the default KDF). Other headered files are
+          keyed from the master key of their run, see file_key()
+        * Feed the key to new_cipher() to get the cipher
+
+        @param algorithm: Algorithm
+            * The algorithm to derive the key for
+        @param block_size: usize
+            * The block size in bytes, 8 for Blowfish, 16 for Twofish, 32, 64 or 128 for Threefish
+        @param passphrase: &[u8]
+            * The passphrase as UTF-8 bytes, or the contents of the key file
+        @param salt: &[u8]
+            * The salt, e.g. the 16 bytes given with --salt
+        @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+            * The key, wiped from memory when dropped
+    */
+    FisherConfig::default().kdf.derive(algorithm, block_size, passphrase, salt)
+}
+
+pub fn file_key(path: &Path, passphrase: &[u8]) -> FResult<Zeroizing<Vec<u8>>> {
+    /*
+        * Derive the Raw Cipher Key of the Given Encrypted File
+
+        * The KDF, its parameters and the salts are read from the header of the file (or of its sidecar),
+          so this is the key decrypting it uses, whichever way it was derived
+
+        @param path: &Path
+            * The encrypted file, in any output format
+        @param passphrase: &[u8]
+            * The passphrase as UTF-8 bytes, or the contents of the key file
+        @return FResult: Result<Zeroizing<Vec<u8>>, Box<dyn Error>>
+            * The key, wiped from memory when dropped, or an error for files without a header
+    */
+    let header = match Meta::read(path)?.map(|meta| meta.header()).transpose()?.flatten() {
+        Some(header) => header,
+        None => Header::parse(&encoding::decode(&fs::read(path)?)?)?.ok_or_else(|| format!("{:?} has no header", path))?
+    };
+    header.key(passphrase)
+}
+
+pub fn new_cipher(algorithm: Algorithm, block_size: usize, key: &[u8], tweak: &[u8; 16]) -> FResult<Fishers> {
+    /*
+        * Create the Cipher for the Given Raw Key, to Use with the RustCrypto Traits Directly
+
+        * The RustCrypto cipher inside implements BlockCipher, BlockEncrypt and BlockDecrypt, so it can be
+          plugged into other block modes, e.g. <&Twofish>::try_from(&fishers)
+
+        @param algorithm: Algorithm
+            * The algorithm of the cipher
+        @param block_size: usize
+            * The block size in bytes, 8 for Blowfish, 16 for Twofish, 32, 64 or 128 for Threefish
+        @param key: &[u8]
+            * The key, 56 bytes for Blowfish, 32 for Twofish and as long as the block for Threefish
+        @param tweak: &[u8; 16]
+            * The Threefish tweak, ignored for Blowfish and Twofish
+        @return FResult: Result<Fishers, Box<dyn Error>>
+            * The keyed cipher, or an error for block sizes and key lengths the algorithm doesn't take
+    */
+    let info = r#enum::algorithm_info(algorithm, block_size)?;
+    if key.len() != info.key_size {
+        return Err(format!("{} takes a {} byte key, got {} bytes", info.name, info.key_size, key.len()).into());
+    }
+    r#enum::new_cipher(algorithm, block_size, key, tweak)
+}
+
+/*
+    * Builder for a Run Over Files and Directories
+
+    * Decrypts unless encrypt() is called, otherwise defaults as on the command line: Threefish-1024,
+      CBC with a PBKDF2 key per file, one worker per CPU
+*/
+pub struct FisherBuilder {
+    config: FisherConfig,
+}
+
+impl FisherBuilder {
+    pub fn new(passphrase: &str) -> FisherBuilder {
+        /*
+            * Create a Builder with the Given Passphrase
+
+            @param passphrase: &str
+                * The passphrase, used as it is
+            @return FisherBuilder
+        */
+        /* FisherConfig encrypts by default, a builder only encrypts when asked to */
+        FisherBuilder {
+            config: FisherConfig {
+                crypt: false,
+                passphrase: Zeroizing::new(passphrase.as_bytes().to_vec()),
+                ..FisherConfig::default()
+            },
+        }
+    }
+
+    pub fn encrypt(mut self) -> FisherBuilder {
+        /*
+            * Encrypt the Paths
+
+            @param self: FisherBuilder Instance
+            @return FisherBuilder
+        */
+        self.config.crypt = true;
+        self
+    }
+
+    pub fn decrypt(mut self) -> FisherBuilder {
+        /*
+            * Decrypt the Paths, the Default
+
+            @param self: FisherBuilder Instance
+            @return FisherBuilder
+        */
+        self.config.crypt = false;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: Algorithm, block_size: usize) -> FisherBuilder {
+        /*
+            * Encrypt with the Given Algorithm, Decrypting Reads it from the Header of Each File
+
+            @param self: FisherBuilder Instance
+            @param algorithm: Algorithm
+                * The algorithm
+            @param block_size: usize
+                * The block size in bytes, 8 for Blowfish, 16 for Twofish, 32, 64 or 128 for Threefish
+            @return FisherBuilder
+        */
+        self.config.algorithm = algorithm;
+        self.config.block_size = block_size;
+        self
+    }
+
+    pub fn path(mut self, path: impl Into<PathBuf>) -> FisherBuilder {
+        /*
+            * Add a File or Directory to Modify
+
+            @param self: FisherBuilder Instance
+            @param path: impl Into<PathBuf>
+                * The file, or directory to modify every file below
+            @return FisherBuilder
+        */
+        self.config.paths.push(path.into());
+        self
+    }
+
+    pub fn keep_name(mut self) -> FisherBuilder {
+        /*
+            * Modify Files in Place Under Their Own Name, Instead of Adding or Removing .fish
+
+            @param self: FisherBuilder Instance
+            @return FisherBuilder
+        */
+        self.config.keep_name = true;
+        self
+    }
+
+    pub fn output(mut self, output: impl Into<PathBuf>) -> FisherBuilder {
+        /*
+            * Write the Modified Files Below the Given Directory, Leaving the Originals Untouched
+
+            @param self: FisherBuilder Instance
+            @param output: impl Into<PathBuf>
+                * The directory, created if needed, or for a single file the file to write
+            @return FisherBuilder
+        */
+        self.config.output = Some(output.into());
+        self
+    }
+
+    pub fn aad(mut self, aad: impl Into<Vec<u8>>) -> FisherBuilder {
+        /*
+            * Bind Encrypted Files to the Given Data, as --aad Does
+
+            * Files encrypted with it only decrypt with the same data given again
+
+            @param self: FisherBuilder Instance
+            @param aad: impl Into<Vec<u8>>
+                * The associated data, not stored in the files
+            @return FisherBuilder
+        */
+        self.config.aad = Some(aad.into());
+        self
+    }
+
+    pub fn relative_to(mut self, root: impl Into<PathBuf>) -> FisherBuilder {
+        /*
+            * Bind Encrypted Files to Their Path Below the Given Directory, as --relative-to Does
+
+            @param self: FisherBuilder Instance
+            @param root: impl Into<PathBuf>
+                * The directory, decrypting needs the directory the files are below then
+            @return FisherBuilder
+        */
+        self.config.relative_to = Some(root.into());
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> FisherBuilder {
+        /*
+            * Modify up to the Given Number of Files at Once
+
+            @param self: FisherBuilder Instance
+            @param jobs: usize
+                * The number of worker threads, at least 1
+            @return FisherBuilder
+        */
+        self.config.jobs = Some(jobs.max(1));
+        self
+    }
+
+    pub fn run(self) -> FResult<usize> {
+        /*
+            * Modify the Paths
+
+            @param self: FisherBuilder Instance
+            @return FResult: Result<usize, Box<dyn Error>>
+                * The number of files modified, or the first error
+        */
+        let fisher = Fisher::from_config(self.config)?;
+        fisher.run()?;
+        Ok(fisher.processed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, InnerIvInit};
+    use cbc::cipher::block_padding::NoPadding;
+    use threefish::Threefish512;
+    use twofish::Twofish;
+
+    use crate::blocks::BlocksExt;
+
+    #[test]
+    fn ciphers_drive_rustcrypto_block_modes() {
+        let data: Vec<u8> = (0..128u8).collect();
+        let iv = [9u8; 16];
+
+        /* The cbc crate chains the Twofish inside like fisher's own CBC does */
+        let fishers = new_cipher(Algorithm::Twofish, 16, &[5; 32], &[0; 16]).unwrap();
+        let expected = vec![data.clone()].into_iter().blocks(&fishers, 16, true).cbc(&iv)
+            .collect::<FResult<Vec<_>>>().unwrap().concat();
+        let twofish = <&Twofish>::try_from(&fishers).unwrap();
+        let mut buffer = data.clone();
+        cbc::Encryptor::<Twofish>::inner_iv_init(twofish.clone(), &iv.into())
+            .encrypt_padded_mut::<NoPadding>(&mut buffer, data.len()).unwrap();
+        assert_eq!(buffer, expected);
+        cbc::Decryptor::<Twofish>::inner_iv_init(twofish.clone(), &iv.into())
+            .decrypt_padded_mut::<NoPadding>(&mut buffer).unwrap();
+        assert_eq!(buffer, data);
+
+        /* Threefish keeps its tweak */
+        let fishers = new_cipher(Algorithm::Threefish, 64, &[5; 64], &[3; 16]).unwrap();
+        let mut block = data[..64].to_vec();
+        fishers.encrypt_block(&mut block).unwrap();
+        let threefish = <&Threefish512>::try_from(&fishers).unwrap();
+        let mut buffer = data[..64].to_vec();
+        cbc::Encryptor::<Threefish512>::inner_iv_init(threefish.clone(), &[0; 64].into())
+            .encrypt_padded_mut::<NoPadding>(&mut buffer, 64).unwrap();
+        assert_eq!(buffer, block);
+
+        /* Asking for another cipher, or passing a key of the wrong length, fails instead of panicking */
+        assert!(<&Twofish>::try_from(&fishers).is_err());
+        assert!(new_cipher(Algorithm::Twofish, 16, &[5; 31], &[0; 16]).is_err());
+        assert!(new_cipher(Algorithm::Blowfish, 16, &[5; 56], &[0; 16]).is_err());
+    }
+
+    #[test]
+    fn derived_keys_match_encrypted_files() {
+        let dir = std::env::temp_dir().join(format!("fisher-test-{}-derive-key", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let authenticates = |path: &Path, key: &[u8]| {
+            let data = fs::read(path).unwrap();
+            let mut header = Header::parse(&data).unwrap().unwrap();
+            header.associate(None, fish::bound_name(path, None).unwrap().as_deref()).unwrap();
+            header.verify_mac(r#enum::mac_key(key).as_slice(), &data[header.size()..]).unwrap()
+        };
+
+        /* A file encrypted with --salt and the default KDF is keyed with derive_key() */
+        let salt = *b"0123456789abcdef";
+        let salted = dir.join("salted.txt");
+        fs::write(&salted, b"salted").unwrap();
+        Fisher::from_config(FisherConfig {
+            algorithm: Algorithm::Twofish,
+            block_size: 16,
+            passphrase: Zeroizing::new(b"password".to_vec()),
+            salt: Some(salt),
+            paths: vec![salted.clone()],
+            ..FisherConfig::default()
+        }).unwrap().run().unwrap();
+        let key = derive_key(Algorithm::Twofish, 16, b"password", &salt).unwrap();
+        assert!(authenticates(&dir.join("salted.txt.fish"), &key));
+        assert_eq!(*file_key(&dir.join("salted.txt.fish"), b"password").unwrap(), *key);
+        assert!(!authenticates(&dir.join("salted.txt.fish"), &derive_key(Algorithm::Twofish, 16, b"wrong", &salt).unwrap()));
+
+        /* Other files are keyed from the master key of their run, file_key() reads both salts from the header */
+        let file = dir.join("file.txt");
+        fs::write(&file, b"file").unwrap();
+        Fisher::from_config(FisherConfig {
+            algorithm: Algorithm::Threefish,
+            block_size: 64,
+            passphrase: Zeroizing::new(b"password".to_vec()),
+            kdf: r#enum::Kdf::Pbkdf2 { iterations: 1 },
+            paths: vec![file.clone()],
+            ..FisherConfig::default()
+        }).unwrap().run().unwrap();
+        let key = file_key(&dir.join("file.txt.fish"), b"password").unwrap();
+        assert_eq!(key.len(), 64);
+        assert!(authenticates(&dir.join("file.txt.fish"), &key));
+        assert!(file_key(&file.with_file_name("missing"), b"password").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bare_builders_decrypt() {
+        let dir = std::env::temp_dir().join(format!("fisher-test-{}-builder", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (path, encrypted) = (dir.join("notes.txt"), dir.join("notes.txt.fish"));
+        fs::write(&path, b"notes").unwrap();
+
+        assert_eq!(FisherBuilder::new("password").encrypt().algorithm(Algorithm::Twofish, 16).path(&path).run().unwrap(), 1);
+        assert!(encrypted.exists() && !path.exists());
+
+        /* Without encrypt() or decrypt() the paths are decrypted */
+        assert_eq!(FisherBuilder::new("password").path(&encrypted).run().unwrap(), 1);
+        assert_eq!(fs::read(&path).unwrap(), b"notes");
+        assert!(!encrypted.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+use hmac::{Hmac, Mac};
+use poly1305::Poly1305;
+use poly1305::universal_hash::{KeyInit, UniversalHash};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::FResult;
+use crate::header::{MAC_SIZE, SALT_SIZE};
+use crate::r#enum::TWEAK_SIZE;
+
+/*
+    * Message Authentication Codes Encrypted Files are Authenticated With
+
+    * Every MAC is keyed with the 32 byte MAC key of the file (see mac_key()) and fills the 32 byte
+      mac field of the header, see the key commitment below
+    * Encrypt-then-MAC: the header (mac zeroed) and the ciphertext before any parity are authenticated,
+      see Header::mac_state()
+    * Every tag commits to the key, so no file can be crafted to authenticate under two passwords:
+      HMAC-SHA256 and keyed BLAKE3 do by themselves (two keys giving one tag take a collision), Poly1305
+      does not, so its 16 byte tag is followed by HMAC-SHA256(MAC key, "fisher key commitment")
+      cut to 16 bytes instead of zeros (see Poly1305Mac)
+*/
+
+/* MACs as stored in the header, version 7 and up, older files are HMAC-SHA256 */
+pub(crate) const MAC_HMAC_SHA256: u8 = 0;
+pub(crate) const MAC_BLAKE3: u8 = 1;
+pub(crate) const MAC_POLY1305: u8 = 2;
+
+/* The MAC of a file */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MacAlgorithm {
+    /* HMAC-SHA256, the default and the only MAC before header version 7 */
+    HmacSha256,
+    /* Keyed BLAKE3, several times faster than HMAC-SHA256 on large files */
+    Blake3,
+    /* Poly1305 with a one-time key derived for the file from its MAC key, salt, IV and tweak, and a key commitment */
+    Poly1305,
+}
+
+impl MacAlgorithm {
+    pub(crate) fn parse(name: &str) -> Option<MacAlgorithm> {
+        /*
+            * Get the MAC Named on the Command Line
+
+            @param name: &str
+                * hmac-sha256, blake3 or poly1305
+            @return Option<MacAlgorithm>
+                * The MAC, None for unknown names
+        */
+        match name.to_lowercase().as_str() {
+            "hmac-sha256" => Some(MacAlgorithm::HmacSha256),
+            "blake3" => Some(MacAlgorithm::Blake3),
+            "poly1305" => Some(MacAlgorithm::Poly1305),
+            _ => None
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        /*
+            * Get the Command Line Name of the MAC
+
+            @param self: MacAlgorithm Instance
+            @return &str: hmac-sha256, blake3 or poly1305
+        */
+        match self {
+            MacAlgorithm::HmacSha256 => "hmac-sha256",
+            MacAlgorithm::Blake3 => "blake3",
+            MacAlgorithm::Poly1305 => "poly1305"
+        }
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        /*
+            * Get the Id of the MAC in the Header
+
+            @param self: MacAlgorithm Instance
+            @return u8: MAC_*
+        */
+        match self {
+            MacAlgorithm::HmacSha256 => MAC_HMAC_SHA256,
+            MacAlgorithm::Blake3 => MAC_BLAKE3,
+            MacAlgorithm::Poly1305 => MAC_POLY1305
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<MacAlgorithm> {
+        /*
+            * Get the MAC with the Given Header Id
+
+            @param id: u8
+                * MAC_*
+            @return Option<MacAlgorithm>: None for unknown ids
+        */
+        match id {
+            MAC_HMAC_SHA256 => Some(MacAlgorithm::HmacSha256),
+            MAC_BLAKE3 => Some(MacAlgorithm::Blake3),
+            MAC_POLY1305 => Some(MacAlgorithm::Poly1305),
+            _ => None
+        }
+    }
+
+    pub(crate) fn start(&self, key: &[u8], salt: &[u8; SALT_SIZE], iv: &[u8], tweak: &[u8; TWEAK_SIZE])
+        -> FResult<Box<dyn FileMac>> {
+        /*
+            * Start Authenticating a File
+
+            @param self: MacAlgorithm Instance
+            @param key: &[u8]
+                * The MAC key of the file, see mac_key()
+            @param salt: &[u8; SALT_SIZE]
+                * The salt of the file
+            @param iv: &[u8]
+                * The IV of the file, empty for ECB
+            @param tweak: &[u8; TWEAK_SIZE]
+                * The tweak of the file
+            @return FResult: Result<Box<dyn FileMac>, Box<dyn Error>>
+                * The state to feed the header and ciphertext to
+                * Salt, IV and tweak only key Poly1305, whose key must never authenticate two messages
+        */
+        Ok(match self {
+            MacAlgorithm::HmacSha256 => Box::new(<Hmac<Sha256> as Mac>::new_from_slice(key)?),
+            MacAlgorithm::Blake3 => {
+                let key: [u8; 32] = key.try_into().map_err(|_| "BLAKE3 takes a 32 byte key")?;
+                Box::new(blake3::Hasher::new_keyed(&key))
+            }
+            MacAlgorithm::Poly1305 => {
+                let mut info = b"fisher poly1305".to_vec();
+                info.extend_from_slice(salt);
+                info.extend_from_slice(iv);
+                info.extend_from_slice(tweak);
+                let mut one_time = Zeroizing::new([0u8; poly1305::KEY_SIZE]);
+                hkdf::Hkdf::<Sha256>::new(None, key)
+                    .expand(&info, one_time.as_mut_slice())
+                    .map_err(|_| "Key too long for HKDF")?;
+                let mut commitment = <Hmac<Sha256> as Mac>::new_from_slice(key)?;
+                Mac::update(&mut commitment, b"fisher key commitment");
+                Box::new(Poly1305Mac {
+                    poly1305: Poly1305::new(&(*one_time).into()),
+                    partial: Vec::with_capacity(poly1305::BLOCK_SIZE),
+                    commitment: Mac::finalize(commitment).into_bytes()[..MAC_SIZE - poly1305::BLOCK_SIZE].try_into()?,
+                })
+            }
+        })
+    }
+}
+
+/* A MAC being computed over a file, fed a piece at a time */
+pub(crate) trait FileMac: Send {
+    /* Feed the next bytes */
+    fn update(&mut self, data: &[u8]);
+
+    /* Get the tag, MAC_SIZE bytes with the key commitment of shorter tags */
+    fn finalize(self: Box<Self>) -> [u8; MAC_SIZE];
+}
+
+impl FileMac for Hmac<Sha256> {
+    fn update(&mut self, data: &[u8]) {
+        Mac::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; MAC_SIZE] {
+        Mac::finalize(*self).into_bytes().into()
+    }
+}
+
+impl FileMac for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; MAC_SIZE] {
+        blake3::Hasher::finalize(&self).into()
+    }
+}
+
+/*
+    * Poly1305 only takes whole blocks until the last one, the bytes short of a block are held back
+
+    * A Poly1305 tag doesn't commit to its key: a file can be crafted to authenticate under many keys at
+      once, so anyone learning whether it decrypts (a partitioning oracle) tests many passwords per try
+    * The commitment, fixed for the MAC key, fills the other half of the mac field. Checking the whole
+      field rejects any other key unless it collides with this one on 16 bytes of HMAC-SHA256
+*/
+struct Poly1305Mac {
+    poly1305: Poly1305,
+    partial: Vec<u8>,
+    commitment: [u8; MAC_SIZE - poly1305::BLOCK_SIZE],
+}
+
+impl FileMac for Poly1305Mac {
+    fn update(&mut self, mut data: &[u8]) {
+        if !self.partial.is_empty() {
+            let len = data.len().min(poly1305::BLOCK_SIZE - self.partial.len());
+            self.partial.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.partial.len() < poly1305::BLOCK_SIZE {
+                return;
+            }
+            let block = poly1305::Block::try_from(self.partial.as_slice()).expect("partial holds one block");
+            self.poly1305.update(&[block]);
+            self.partial.clear();
+        }
+
+        let whole = data.len() - data.len() % poly1305::BLOCK_SIZE;
+        for block in data[..whole].chunks_exact(poly1305::BLOCK_SIZE) {
+            self.poly1305.update(&[poly1305::Block::try_from(block).expect("chunks are one block")]);
+        }
+        self.partial.extend_from_slice(&data[whole..]);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; MAC_SIZE] {
+        let Poly1305Mac { poly1305, partial, commitment } = *self;
+        let mut tag = [0u8; MAC_SIZE];
+        tag[..poly1305::BLOCK_SIZE].copy_from_slice(&poly1305.compute_unpadded(&partial));
+        tag[poly1305::BLOCK_SIZE..].copy_from_slice(&commitment);
+        tag
+    }
+}
+
+pub(crate) fn tags_match(tag: &[u8; MAC_SIZE], expected: &[u8; MAC_SIZE]) -> bool {
+    /*
+        * Compare Two Tags in Constant Time
+
+        @param tag: &[u8; MAC_SIZE]
+            * The computed tag
+        @param expected: &[u8; MAC_SIZE]
+            * The tag from the header
+        @return bool: Whether they are equal, looking at every byte either way
+    */
+    tag.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macs_match_one_shot_whatever_the_chunks() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let key = [9u8; 32];
+        for mac in [MacAlgorithm::HmacSha256, MacAlgorithm::Blake3, MacAlgorithm::Poly1305] {
+            let start = || mac.start(&key, &[1; SALT_SIZE], &[2; 16], &[3; TWEAK_SIZE]).unwrap();
+            let mut one_shot = start();
+            one_shot.update(&data);
+            let one_shot = one_shot.finalize();
+
+            for chunk in [1, 15, 16, 17, 999] {
+                let mut chunked = start();
+                for piece in data.chunks(chunk) {
+                    chunked.update(piece);
+                }
+                assert!(tags_match(&chunked.finalize(), &one_shot), "{} in {} byte chunks", mac.name(), chunk);
+            }
+            assert_eq!(MacAlgorithm::from_id(mac.id()), Some(mac));
+        }
+
+        /* Every file gets its own Poly1305 key */
+        let tag = |salt| {
+            let mut mac = MacAlgorithm::Poly1305.start(&key, &[salt; SALT_SIZE], &[], &[0; TWEAK_SIZE]).unwrap();
+            mac.update(&data);
+            mac.finalize()
+        };
+        assert_ne!(tag(1), tag(2));
+    }
+
+    #[test]
+    fn poly1305_tags_commit_to_the_key() {
+        let tag = |key: [u8; 32], data: &[u8]| {
+            let mut mac = MacAlgorithm::Poly1305.start(&key, &[1; SALT_SIZE], &[], &[0; TWEAK_SIZE]).unwrap();
+            mac.update(data);
+            mac.finalize()
+        };
+        let commitment = |tag: [u8; MAC_SIZE]| tag[poly1305::BLOCK_SIZE..].to_vec();
+
+        /* The second half only depends on the key, whatever the data */
+        assert_eq!(commitment(tag([9; 32], b"one file")), commitment(tag([9; 32], b"another file")));
+        assert_ne!(commitment(tag([9; 32], b"one file")), commitment(tag([8; 32], b"one file")));
+
+        let mut expected = <Hmac<Sha256> as Mac>::new_from_slice(&[9; 32]).unwrap();
+        Mac::update(&mut expected, b"fisher key commitment");
+        assert_eq!(commitment(tag([9; 32], b"")), Mac::finalize(expected).into_bytes()[..16].to_vec());
+
+        /* A tag with the commitment zeroed, as an uncommitted Poly1305 tag would be, doesn't match */
+        let mut uncommitted = tag([9; 32], b"one file");
+        uncommitted[poly1305::BLOCK_SIZE..].fill(0);
+        assert!(!tags_match(&uncommitted, &tag([9; 32], b"one file")));
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+
+use crate::FResult;
+use crate::header::Header;
+use crate::r#enum::Algorithm;
+
+/* Suffix appended to the full file name of an encrypted file to name its sidecar */
+pub(crate) const META_SUFFIX: &str = ".fisher.meta";
+
+/*
+    * Sidecar Metadata of an Encrypted File
+
+    * Written as <name>.fisher.meta JSON next to the encrypted file
+    * With --length-preserving the header of the file is kept here instead of in front of the ciphertext,
+      so the encrypted file holds nothing but its CTR ciphertext, exactly as long as the plaintext
+*/
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Meta {
+    /* The algorithm the file was encrypted with */
+    pub(crate) algorithm: Algorithm,
+    /* The block size in bytes the file was encrypted with */
+    pub(crate) block_size: usize,
+    /* How blocks were chained, "ecb", or "cbc" or "ctr" with the IV in the header of the file */
+    pub(crate) mode: String,
+    /* The plaintext length in bytes, before padding */
+    pub(crate) original_size: u64,
+    /* The header of a --length-preserving file in base64, None when the file carries its own */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) header: Option<String>,
+}
+
+impl Meta {
+    pub(crate) fn read(path: &Path) -> FResult<Option<Meta>> {
+        /*
+            * Read the Sidecar of the Given Encrypted File
+
+            @param path: &Path
+                * The encrypted file (not the sidecar itself)
+            @return FResult: Result<Option<Meta>, Box<dyn Error>>
+                * The metadata, None if the file has no sidecar
+        */
+        let meta_path = meta_path(path);
+        if !meta_path.is_file() {
+            return Ok(None);
+        }
+
+        let meta = serde_json::from_str(&fs::read_to_string(&meta_path)?)
+            .map_err(|err| format!("Invalid sidecar {:?}: {}", meta_path, err))?;
+        Ok(Some(meta))
+    }
+
+    pub(crate) fn header(&self) -> FResult<Option<Header>> {
+        /*
+            * Get the Header Kept in the Sidecar of a --length-preserving File
+
+            @param self: Meta Instance
+            @return FResult: Result<Option<Header>, Box<dyn Error>>
+                * The header, None when the file carries its own
+                * An error if the sidecar holds something else than a header
+        */
+        let Some(header) = &self.header else {
+            return Ok(None);
+        };
+        let bytes = STANDARD.decode(header).map_err(|err| format!("Invalid header in sidecar: {}", err))?;
+        match Header::parse(&bytes)? {
+            Some(header) if header.size() == bytes.len() => Ok(Some(header)),
+            _ => Err("Invalid header in sidecar".into())
+        }
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> FResult<()> {
+        /*
+            * Write the Sidecar of the Given Encrypted File
+
+            @param self: Meta Instance
+            @param path: &Path
+                * The encrypted file (not the sidecar itself)
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+        fs::write(meta_path(path), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn meta_path(path: &Path) -> PathBuf {
+    /*
+        * Get the Sidecar Path of the Given Encrypted File
+
+        @param path: &Path
+            * The encrypted file
+        @return PathBuf
+            * The path with .fisher.meta appended to its full file name
+    */
+    let mut meta = path.as_os_str().to_owned();
+    meta.push(META_SUFFIX);
+    PathBuf::from(meta)
+}
@@ -0,0 +1,153 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Condvar, Mutex};
+
+use crate::FResult;
+
+/*
+    * Bounded Pool of Worker Threads Over a Work Queue
+
+    * A fixed number of workers take items from a shared queue, work on an item may push further
+      items (e.g. the entries of a directory), the pool is done once the queue is empty and no
+      worker is busy
+    * A failing or panicking item doesn't take its worker or the other items down, the errors are
+      handed back once every item is done
+*/
+pub(crate) struct Pool<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /* Items queued or being worked on */
+    pending: usize,
+}
+
+impl<T: Send> Pool<T> {
+    pub(crate) fn run(jobs: usize, items: impl IntoIterator<Item = T>, work: impl Fn(T, &Pool<T>) -> FResult<()> + Sync)
+        -> Vec<String> {
+        /*
+            * Work on the Given Items and Everything They Push With the Given Number of Workers
+
+            @param jobs: usize
+                * The number of worker threads, at least one is started
+            @param items: impl IntoIterator<Item = T>
+                * The initial work items, worked on in order as workers become free
+            @param work: impl Fn(T, &Pool<T>) -> FResult<()> + Sync
+                * Works on one item, may push() more
+            @return Vec<String>
+                * The errors of the items that failed or panicked, in the order they happened, once every item is done
+        */
+        let pool = Pool {
+            state: Mutex::new(State { queue: VecDeque::new(), pending: 0 }),
+            ready: Condvar::new(),
+        };
+        for item in items {
+            pool.push(item);
+        }
+
+        let errors = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..jobs.max(1) {
+                scope.spawn(|| {
+                    while let Some(item) = pool.next() {
+                        /* Errors aren't Send, so only their messages leave the worker */
+                        match std::panic::catch_unwind(AssertUnwindSafe(|| work(item, &pool))) {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => errors.lock().unwrap().push(err.to_string()),
+                            Err(panic) => errors.lock().unwrap().push(panic_message(panic))
+                        }
+                        pool.done();
+                    }
+                });
+            }
+        });
+
+        errors.into_inner().unwrap()
+    }
+
+    pub(crate) fn push(&self, item: T) {
+        /*
+            * Queue an Item for the Next Free Worker
+
+            @param self: Pool Instance
+            @param item: T
+                * The work item
+        */
+        let mut state = self.state.lock().unwrap();
+        state.queue.push_back(item);
+        state.pending += 1;
+        self.ready.notify_one();
+    }
+
+    fn next(&self) -> Option<T> {
+        /*
+            * Wait for the Next Item
+
+            @param self: Pool Instance
+            @return Option<T>
+                * The item, None once every item is done
+        */
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.queue.pop_front() {
+                return Some(item);
+            }
+            /* Nothing queued, but a busy worker may still push items */
+            if state.pending == 0 {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    fn done(&self) {
+        /*
+            * Mark an Item Taken With next() as Done
+
+            @param self: Pool Instance
+        */
+        let mut state = self.state.lock().unwrap();
+        state.pending -= 1;
+        /* Wake the idle workers to exit once the last item is done */
+        if state.pending == 0 {
+            self.ready.notify_all();
+        }
+    }
+}
+
+pub(crate) fn default_jobs() -> usize {
+    /*
+        * Get the Number of Workers Used Unless Given
+
+        @return usize
+            * One per CPU, or 1 (with a warning) where the CPU count can't be detected, e.g. in some sandboxes
+    */
+    match std::thread::available_parallelism() {
+        Ok(cpus) => cpus.get(),
+        Err(err) => {
+            eprintln!("Warning: could not detect the number of CPUs ({}), using a single worker", err);
+            1
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    /*
+        * Get the Message of a Caught Panic
+
+        @param panic: Box<dyn Any + Send>
+            * The payload returned by catch_unwind()
+        @return String
+            * The message the panic was raised with
+    */
+    match panic.downcast::<String>() {
+        Ok(message) => format!("Panicked: {}", message),
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => format!("Panicked: {}", message),
+            Err(_) => "Panicked".to_string()
+        }
+    }
+}
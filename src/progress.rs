@@ -0,0 +1,138 @@
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/* How often a progress line is printed when stdout is not a terminal */
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/* Files at least this large get a bar of their own showing the bytes processed */
+const LARGE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/* How progress is reported, see Progress */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ProgressMode {
+    Off,
+    /* "processed X/Y files" lines, for logs and CI */
+    Text,
+    /* Progress bars on the terminal */
+    Bar,
+}
+
+/*
+    * Progress Reporter
+
+    * Text progress never prints control codes, so the output stays readable in redirected logs and CI:
+        * On a terminal the line is rewritten in place with a carriage return
+        * Otherwise a new "processed X/Y files" line is printed at most once per LOG_INTERVAL
+    * Bar progress draws a bar of the files processed on the terminal, with a bar of the bytes
+      processed below it for each large file being streamed
+*/
+pub(crate) struct Progress {
+    done: AtomicUsize,
+    last_print: Mutex<Instant>,
+    /* The files bar and the bars of large files below it, None for text progress */
+    bars: Option<(MultiProgress, ProgressBar)>,
+    terminal: bool,
+    total: usize,
+}
+
+impl Progress {
+    pub(crate) fn new(mode: ProgressMode, total: usize) -> Option<Progress> {
+        /*
+            * Create a new Progress Reporter
+
+            @param mode: ProgressMode
+                * How to report progress
+            @param total: usize
+                * The number of files expected to be processed
+            @return Option<Progress>
+                * The reporter, None for ProgressMode::Off
+        */
+        let bars = match mode {
+            ProgressMode::Off => return None,
+            ProgressMode::Text => None,
+            ProgressMode::Bar => {
+                let bars = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+                let files = bars.add(ProgressBar::new(total as u64).with_style(
+                    ProgressStyle::with_template("{bar:40} {pos}/{len} files [{elapsed_precise}, {eta} left]")
+                        .expect("valid progress template")));
+                Some((bars, files))
+            }
+        };
+
+        Some(Progress {
+            done: AtomicUsize::new(0),
+            last_print: Mutex::new(Instant::now()),
+            bars,
+            terminal: std::io::stdout().is_terminal(),
+            total,
+        })
+    }
+
+    pub(crate) fn file_bar(&self, path: &Path, size: u64) -> Option<ProgressBar> {
+        /*
+            * Add a Bar for the Bytes of a Large File Being Streamed
+
+            @param self: Progress Instance
+            @param path: &Path
+                * The file, named next to the bar
+            @param size: u64
+                * The number of bytes that will be processed
+            @return Option<ProgressBar>
+                * The bar to advance by the bytes processed and finish_and_clear() once done, None for
+                  text progress and files below LARGE_FILE_SIZE
+        */
+        let (bars, _) = self.bars.as_ref().filter(|_| size >= LARGE_FILE_SIZE)?;
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+        Some(bars.add(ProgressBar::new(size).with_message(name).with_style(
+            ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {msg}")
+                .expect("valid progress template"))))
+    }
+
+    pub(crate) fn file_done(&self) {
+        /*
+            * Record a Processed File and Print the Progress if Due
+
+            @param self: Progress Instance
+        */
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if let Some((_, files)) = &self.bars {
+            files.inc(1);
+            return;
+        }
+
+        if self.terminal {
+            print!("\rprocessed {}/{} files", done, self.total);
+            let _ = std::io::stdout().flush();
+            return;
+        }
+
+        /* Limit the number of lines written to logs */
+        let mut last_print = self.last_print.lock().unwrap();
+        if done == self.total || last_print.elapsed() >= LOG_INTERVAL {
+            println!("processed {}/{} files", done, self.total);
+            *last_print = Instant::now();
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        /*
+            * End the Progress Line, or Remove the Bars
+
+            @param self: Progress Instance
+        */
+        if let Some((bars, _)) = &self.bars {
+            let _ = bars.clear();
+            return;
+        }
+
+        if self.terminal && self.done.load(Ordering::SeqCst) > 0 {
+            println!();
+        }
+    }
+}
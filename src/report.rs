@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::FResult;
+
+/*
+    * Machine Readable Summary of a Run, Written by --stats-json
+
+    * Filled in while the run progresses, so it also describes runs that failed or were interrupted
+*/
+#[derive(Default, Serialize)]
+pub(crate) struct RunReport {
+    /* encrypt, decrypt or migrate */
+    pub(crate) operation: String,
+    /* Files modified successfully */
+    pub(crate) files: usize,
+    /* Bytes read from and written to the modified files */
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+    /* Wall clock time of the run */
+    pub(crate) duration_secs: f64,
+    /* Files encrypted or decrypted per algorithm name, e.g. "Threefish-1024" */
+    pub(crate) algorithms: BTreeMap<String, usize>,
+    /* Files that could not be modified */
+    pub(crate) failures: Vec<Failure>,
+    /* Whether the run was stopped with Ctrl-C or by --abort-on-first-error */
+    pub(crate) interrupted: bool,
+    /* The files written and the files they were encrypted from, only collected for --verify-after */
+    #[serde(skip)]
+    pub(crate) outputs: Vec<(PathBuf, PathBuf)>,
+}
+
+/* One file that could not be modified */
+#[derive(Serialize)]
+pub(crate) struct Failure {
+    pub(crate) path: PathBuf,
+    pub(crate) error: String,
+}
+
+impl RunReport {
+    pub(crate) fn write(&self, path: &Path) -> FResult<()> {
+        /*
+            * Write the Report as JSON
+
+            @param self: RunReport Instance
+            @param path: &Path
+                * The file to write, replaced if it exists
+            @return FResult: Result<(), Box<dyn Error>>
+        */
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use rand::RngCore;
+use sharks::{Share, Sharks};
+use zeroize::Zeroizing;
+
+use crate::FResult;
+
+/* File name prefix of share files, followed by the share number */
+pub(crate) const SHARE_PREFIX: &str = "fisher.share.";
+
+/* Size in bytes of the random secret split into shares */
+const SECRET_SIZE: usize = 32;
+
+pub(crate) fn deal(shares: u8, threshold: u8) -> FResult<String> {
+    /*
+        * Generate a Random Secret and Split it into Share Files
+
+        * Any `threshold` of the `shares` files written to the current directory (fisher.share.<n>)
+          reconstruct the secret, fewer reveal nothing about it
+
+        @param shares: u8
+            * The number of shares to write
+        @param threshold: u8
+            * The number of shares needed to reconstruct the secret
+        @return FResult: Result<String, Box<dyn Error>>
+            * The secret, hex encoded, to be used as the passphrase
+    */
+    if threshold == 0 || threshold > shares {
+        return Err("Threshold must be between 1 and the number of shares".into());
+    }
+
+    let mut secret = Zeroizing::new([0u8; SECRET_SIZE]);
+    rand::thread_rng().fill_bytes(&mut *secret);
+
+    /* Write each share as "<threshold>:<hex share>", never overwriting existing shares */
+    for (index, share) in Sharks(threshold).dealer(&*secret).take(shares as usize).enumerate() {
+        let path = PathBuf::from(format!("{}{}", SHARE_PREFIX, index + 1));
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path)
+            .map_err(|err| format!("Could not create share file {:?}: {}", path, err))?;
+        writeln!(file, "{}:{}", threshold, to_hex(&Vec::from(&share)))?;
+        println!("Wrote share {:?}", path);
+    }
+
+    Ok(to_hex(&*secret))
+}
+
+pub(crate) fn recover(paths: &[PathBuf]) -> FResult<String> {
+    /*
+        * Reconstruct the Secret from Share Files
+
+        @param paths: &[PathBuf]
+            * The share files written by deal()
+        @return FResult: Result<String, Box<dyn Error>>
+            * The secret, hex encoded, to be used as the passphrase
+    */
+    let mut threshold = 0;
+    let mut shares: Vec<Share> = Vec::new();
+
+    for path in paths {
+        let contents = fs::read_to_string(path)?;
+        let (share_threshold, share) = contents.trim().split_once(':')
+            .ok_or(format!("{:?} is not a share file", path))?;
+
+        threshold = share_threshold.parse::<u8>()
+            .map_err(|_| format!("{:?} is not a share file", path))?;
+        shares.push(Share::try_from(from_hex(share)?.as_slice())?);
+    }
+
+    let secret = Zeroizing::new(Sharks(threshold).recover(&shares)
+        .map_err(|err| format!("Could not recover the key from the given shares: {}", err))?);
+
+    Ok(to_hex(&secret))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    /*
+        * Hex Encode the Given Bytes
+    */
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> FResult<Vec<u8>> {
+    /*
+        * Decode the Given Hex String
+    */
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err("Invalid hex in share file".into());
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).map_err(|_| "Invalid hex in share file".into()))
+        .collect()
+}
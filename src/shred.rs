@@ -0,0 +1,121 @@
+use std::fs::{File, Metadata};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rand::RngCore;
+
+use crate::FResult;
+
+/*
+    * Overwriting the Plaintext Left Behind by Encryption, see --shred
+
+    * The original is overwritten with random bytes in place, so its old blocks are replaced on disk
+      instead of just being unlinked
+    * That only holds where a write lands on the blocks it overwrites, see unreliable()
+*/
+
+/* Bytes overwritten at once */
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/*
+    * Filesystems Overwriting Nothing in Place
+
+    * Copy-on-write and log-structured filesystems write new data to new blocks (and may keep the old
+      ones in snapshots), overlays copy files up to another layer, and network filesystems leave it to
+      a server that may do either
+*/
+#[cfg(target_os = "linux")]
+const UNRELIABLE_FILESYSTEMS: &[&str] = &[
+    "btrfs", "zfs", "bcachefs", "f2fs", "nilfs2", "overlay",
+    "nfs", "nfs4", "cifs", "smb3", "9p", "fuse.sshfs",
+];
+
+pub(crate) fn unreliable(path: &Path, metadata: &Metadata) -> Option<String> {
+    /*
+        * Get Why Overwriting the Given File Wouldn't Destroy its Contents
+
+        @param path: &Path
+            * The file to overwrite
+        @param metadata: &Metadata
+            * Its metadata
+        @return Option<String>
+            * Why not, None if overwriting it should work (or there is no way to tell)
+    */
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        /* The other names would be overwritten too */
+        if metadata.nlink() > 1 {
+            return Some(format!("it has {} hard links", metadata.nlink()));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+
+    #[cfg(target_os = "linux")]
+    if let Some(filesystem) = filesystem(path).filter(|filesystem| UNRELIABLE_FILESYSTEMS.contains(&filesystem.as_str())) {
+        return Some(format!("{} does not overwrite files in place", filesystem));
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = path;
+
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn filesystem(path: &Path) -> Option<String> {
+    /*
+        * Get the Type of the Filesystem Holding the Given File
+
+        @param path: &Path
+            * The file
+        @return Option<String>
+            * The type as in /proc/self/mounts (e.g. ext4), None if it can't be found
+    */
+    let path = path.canonicalize().ok()?;
+    let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+
+    /* The deepest mount point holding the file, the last one mounted there if several were */
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ').skip(1);
+            let mount_point = fields.next()?
+                .replace("\\040", " ")
+                .replace("\\011", "\t")
+                .replace("\\012", "\n")
+                .replace("\\134", "\\");
+            Some((mount_point, fields.next()?))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.len())
+        .map(|(_, filesystem)| filesystem.to_string())
+}
+
+pub(crate) fn overwrite(file: &mut File, passes: u32) -> FResult<()> {
+    /*
+        * Overwrite the Whole File with Random Bytes
+
+        @param file: &mut File
+            * The file, opened for writing
+        @param passes: u32
+            * How many times to overwrite it, each pass is synced to disk before the next
+        @return FResult: Result<(), Box<dyn Error>>
+    */
+    let len = file.metadata()?.len();
+    let mut buffer = vec![0u8; CHUNK_SIZE.min(len as usize)];
+
+    for _ in 0..passes {
+        file.seek(SeekFrom::Start(0))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = &mut buffer[..CHUNK_SIZE.min(remaining as usize)];
+            rand::thread_rng().fill_bytes(chunk);
+            file.write_all(chunk)?;
+            remaining -= chunk.len() as u64;
+        }
+        file.sync_all()?;
+    }
+
+    Ok(())
+}